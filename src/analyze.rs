@@ -0,0 +1,655 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter},
+    path::PathBuf,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}, mpsc::{self, Receiver, SyncSender, TrySendError}},
+    thread::{self, JoinHandle},
+};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+use gstreamer_app as gst_app;
+use gstreamer_audio as gst_audio;
+use gstreamer_video as gst_video;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{error::Result, probe::StreamKind};
+
+/// analyzer のスレッドに渡すまで溜めておけるフレームや音声の数のデフォルト
+pub const DEFAULT_QUEUE_SIZE: usize = 8;
+
+/// `Analysis::add_builtin` で名前で足せる analyzer
+pub const BUILTIN_ANALYZERS: &[&str] = &["brightness", "level"];
+
+/// tee から appsink までの queue に溜められる buffer の数
+///
+/// 溢れたら古いものから捨てるので、 convert が遅くても tee (transcode の branch) は止まらない
+const TAP_QUEUE_BUFFERS: u32 = 4;
+
+/// decode したフレーム。 buffer は map したままコピーせずに渡す
+pub struct DecodedFrame {
+    /// 出力の timeline での PTS (running time)。 trim した時は開始位置が 0 で、 concat の入力の切れ目でも戻らない
+    pub pts: gst::ClockTime,
+    pub duration: Option<gst::ClockTime>,
+    pub frame: gst_video::VideoFrame<gst_video::video_frame::Readable>,
+}
+
+/// decode した音声の一つの buffer
+pub struct AudioChunk {
+    /// 出力の timeline での PTS (running time)
+    pub pts: gst::ClockTime,
+    pub duration: Option<gst::ClockTime>,
+    pub sample_rate: u32,
+    pub channels: u32,
+    /// channel ごとに交互に並んだ float32 の sample
+    pub samples: Vec<f32>,
+}
+
+/// transcode 中に decode したフレームを受け取って、何かを計算する
+///
+/// `analyze` は analyzer ごとのスレッドで呼ばれる。遅くても transcode は待たずに、間に合わないフレームは捨てる
+pub trait FrameAnalyzer: Send + 'static {
+    /// sidecar に書く名前
+    fn name(&self) -> &str;
+
+    /// 受け取るフレームの pixel format
+    fn format(&self) -> gst_video::VideoFormat {
+        gst_video::VideoFormat::Rgb
+    }
+
+    /// 受け取るフレームの (幅, 高さ) 。 None なら decode したまま
+    fn size(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    /// 返した値はフレームの PTS と一緒に sidecar に書く。 None なら何も書かない
+    fn analyze(&mut self, frame: &DecodedFrame) -> Option<Value>;
+
+    /// 最後のフレームの後に一度だけ呼ばれる。返した値は sidecar の summary に書く
+    fn finish(&mut self) -> Option<Value> {
+        None
+    }
+}
+
+/// transcode 中に decode した音声を受け取って、何かを計算する
+///
+/// `analyze` は analyzer ごとのスレッドで呼ばれる。遅くても transcode は待たずに、間に合わない buffer は捨てる
+pub trait AudioAnalyzer: Send + 'static {
+    /// sidecar に書く名前
+    fn name(&self) -> &str;
+
+    /// 受け取る音声の sample rate 。 None なら decode したまま
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// 受け取る音声の channel 数。 None なら decode したまま
+    fn channels(&self) -> Option<u32> {
+        None
+    }
+
+    /// 返した値は buffer の PTS と一緒に sidecar に書く。 None なら何も書かない
+    fn analyze(&mut self, chunk: &AudioChunk) -> Option<Value>;
+
+    /// 最後の buffer の後に一度だけ呼ばれる。返した値は sidecar の summary に書く
+    fn finish(&mut self) -> Option<Value> {
+        None
+    }
+}
+
+/// transcode しながら動かす analyzer と、結果を書く sidecar の JSON ファイル
+///
+/// 出力に書く最初の映像と音声の stream を analyzer に渡す
+pub struct Analysis {
+    pub frame_analyzers: Vec<Box<dyn FrameAnalyzer>>,
+    pub audio_analyzers: Vec<Box<dyn AudioAnalyzer>>,
+    pub sidecar_path: PathBuf,
+    /// analyzer ごとの、まだ処理していないフレームや音声を溜めておける数
+    pub queue_size: usize,
+}
+
+impl Analysis {
+    pub fn new(sidecar_path: impl Into<PathBuf>) -> Self {
+        Self { frame_analyzers: Vec::new(), audio_analyzers: Vec::new(), sidecar_path: sidecar_path.into(), queue_size: DEFAULT_QUEUE_SIZE }
+    }
+
+    /// `BUILTIN_ANALYZERS` にある analyzer を名前で足す
+    pub fn add_builtin(&mut self, name: &str) -> std::result::Result<(), glib::BoolError> {
+        match name {
+            "brightness" => self.frame_analyzers.push(Box::<Brightness>::default()),
+            "level" => self.audio_analyzers.push(Box::<Level>::default()),
+            _ => return Err(glib::bool_error!("Unknown analyzer: {} (available: {})", name, BUILTIN_ANALYZERS.join(", "))),
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frame_analyzers.is_empty() && self.audio_analyzers.is_empty()
+    }
+
+    /// analyzer ごとにスレッドを立てて、 pipeline に繋ぐ tap を返す
+    pub(crate) fn start(self) -> Result<AnalysisRun> {
+        let mut taps = Taps::default();
+        let mut workers = Vec::new();
+
+        for analyzer in self.frame_analyzers {
+            let mut caps_builder = gst_video::VideoCapsBuilder::new().format(analyzer.format());
+            if let Some((width, height)) = analyzer.size() {
+                caps_builder = caps_builder.width(width as i32).height(height as i32);
+            }
+            let (tap, receiver) = Tap::<DecodedFrame>::new(caps_builder.build(), self.queue_size);
+            workers.push(spawn_worker(analyzer, receiver, tap.dropped.clone())?);
+            taps.frame_taps.push(tap);
+        }
+        for analyzer in self.audio_analyzers {
+            let mut caps_builder = gst_audio::AudioCapsBuilder::new_interleaved().format(gst_audio::AudioFormat::F32le);
+            if let Some(sample_rate) = analyzer.sample_rate() {
+                caps_builder = caps_builder.rate(sample_rate as i32);
+            }
+            if let Some(channels) = analyzer.channels() {
+                caps_builder = caps_builder.channels(channels as i32);
+            }
+            let (tap, receiver) = Tap::<AudioChunk>::new(caps_builder.build(), self.queue_size);
+            workers.push(spawn_worker(analyzer, receiver, tap.dropped.clone())?);
+            taps.audio_taps.push(tap);
+        }
+
+        Ok(AnalysisRun { taps: Arc::new(taps), workers, sidecar_path: self.sidecar_path })
+    }
+}
+
+/// 一つの analyzer の結果。 sidecar にはこれを analyzer の数だけ並べる
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalyzerReport {
+    pub name: String,
+    /// "video" か "audio"
+    pub kind: &'static str,
+    /// analyzer に渡したフレームや buffer の数
+    pub analyzed: u64,
+    /// queue が一杯で analyzer に渡せずに捨てた数
+    pub dropped: u64,
+    pub results: Vec<AnalysisResult>,
+    pub summary: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalysisResult {
+    pub pts_ns: u64,
+    pub duration_ns: Option<u64>,
+    pub value: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Sidecar<'a> {
+    analyzers: &'a [AnalyzerReport],
+}
+
+/// 動いている analyzer のスレッドと、 pipeline に繋ぐ tap
+pub(crate) struct AnalysisRun {
+    pub(crate) taps: Arc<Taps>,
+    workers: Vec<JoinHandle<AnalyzerReport>>,
+    sidecar_path: PathBuf,
+}
+
+impl AnalysisRun {
+    /// tap を閉じて、 analyzer が溜まっている分を処理し終えるのを待ってから sidecar を書く
+    ///
+    /// pipeline を止めた後に呼ぶ。中断やエラーで途中までしか流れていなくても、そこまでの結果を書く
+    pub(crate) fn finish(self) -> Result<Vec<AnalyzerReport>> {
+        self.taps.close();
+
+        let mut reports = Vec::new();
+        for worker in self.workers {
+            match worker.join() {
+                Ok(report) => {
+                    if report.dropped > 0 {
+                        log::warn!("Analyzer {} dropped {} of {} {} buffers", report.name, report.dropped, report.analyzed + report.dropped, report.kind);
+                    }
+                    reports.push(report);
+                },
+                Err(_) => log::warn!("Analyzer thread panicked, skip its results"),
+            }
+        }
+
+        let file = BufWriter::new(File::create(&self.sidecar_path)?);
+        serde_json::to_writer_pretty(file, &Sidecar { analyzers: &reports }).map_err(io::Error::from)?;
+        log::info!("Wrote analysis to {}", self.sidecar_path.display());
+
+        Ok(reports)
+    }
+}
+
+/// transcode の decode した後ろに繋ぐ、 analyzer ごとの appsink の設定と送り先
+///
+/// 種類ごとに最初に繋いだ stream だけを analyzer に渡す
+#[derive(Default)]
+pub(crate) struct Taps {
+    frame_taps: Vec<Tap<DecodedFrame>>,
+    audio_taps: Vec<Tap<AudioChunk>>,
+    linked_kinds: Mutex<HashSet<StreamKind>>,
+}
+
+impl Taps {
+    /// head_el の前に tee を入れて analyzer の branch を足し、 tee を返す
+    ///
+    /// この kind の analyzer がないか、もう他の stream に繋いでいれば head_el をそのまま返す
+    /// head_el から下流は先に state を合わせておくこと
+    pub(crate) fn link(&self, pipeline: &gst::Pipeline, kind: StreamKind, head_el: &gst::Element) -> std::result::Result<gst::Element, glib::BoolError> {
+        if !self.claim(kind) {
+            return Ok(head_el.clone());
+        }
+
+        let tee_el = gst::ElementFactory::make("tee").build()?;
+        pipeline.add(&tee_el)?;
+        tee_el.link(head_el)?;
+        self.link_branches(pipeline, kind, &tee_el)?;
+        tee_el.sync_state_with_parent()?;
+
+        Ok(tee_el)
+    }
+
+    /// bitrate ladder のように decode の後ろにもう tee があれば、そこに analyzer の branch を足す
+    pub(crate) fn link_to_tee(&self, pipeline: &gst::Pipeline, kind: StreamKind, tee_el: &gst::Element) -> std::result::Result<(), glib::BoolError> {
+        if self.claim(kind) {
+            self.link_branches(pipeline, kind, tee_el)?;
+        }
+        Ok(())
+    }
+
+    fn claim(&self, kind: StreamKind) -> bool {
+        let has_taps = match kind {
+            StreamKind::Video => !self.frame_taps.is_empty(),
+            StreamKind::Audio => !self.audio_taps.is_empty(),
+            StreamKind::Subtitle => false,
+        };
+        has_taps && self.linked_kinds.lock().unwrap().insert(kind)
+    }
+
+    fn link_branches(&self, pipeline: &gst::Pipeline, kind: StreamKind, tee_el: &gst::Element) -> std::result::Result<(), glib::BoolError> {
+        match kind {
+            StreamKind::Video => self.frame_taps.iter().try_for_each(|tap| tap.link(pipeline, tee_el)),
+            StreamKind::Audio => self.audio_taps.iter().try_for_each(|tap| tap.link(pipeline, tee_el)),
+            StreamKind::Subtitle => Ok(()),
+        }
+    }
+
+    /// これ以上 analyzer に送らない。全部の送り先が閉じると analyzer のスレッドが終わる
+    fn close(&self) {
+        for tap in &self.frame_taps {
+            tap.sender.lock().unwrap().take();
+        }
+        for tap in &self.audio_taps {
+            tap.sender.lock().unwrap().take();
+        }
+    }
+}
+
+/// 一つの analyzer の appsink に求める caps と、 analyzer のスレッドへの送り先
+struct Tap<T> {
+    caps: gst::Caps,
+    /// appsink の callback と共有して、 `Taps::close` で None にする
+    sender: Arc<Mutex<Option<SyncSender<T>>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<T: TapItem> Tap<T> {
+    fn new(caps: gst::Caps, queue_size: usize) -> (Self, Receiver<T>) {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        (Self { caps, sender: Arc::new(Mutex::new(Some(sender))), dropped: Default::default() }, receiver)
+    }
+
+    /// tee ! queue (leaky) ! convert ! appsink
+    fn link(&self, pipeline: &gst::Pipeline, tee_el: &gst::Element) -> std::result::Result<(), glib::BoolError> {
+        let queue_el = gst::ElementFactory::make("queue")
+            .property("max-size-buffers", TAP_QUEUE_BUFFERS)
+            .property("max-size-bytes", 0u32)
+            .property("max-size-time", 0u64)
+            .property_from_str("leaky", "downstream")
+            .build()?;
+        // leaky な queue は一杯になると overrun を出してから古い buffer を一つ捨てる
+        let dropped = self.dropped.clone();
+        queue_el.connect("overrun", false, move |_| {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            None
+        });
+        let mut chain = vec![queue_el];
+        for converter_name in T::CONVERTER_NAMES {
+            chain.push(gst::ElementFactory::make(converter_name).build()?);
+        }
+        let appsink = gst_app::AppSink::builder().caps(&self.caps).sync(false).build();
+        chain.push(appsink.clone().upcast());
+        pipeline.add_many(&chain)?;
+        gst::Element::link_many(&chain)?;
+
+        let sender = self.sender.clone();
+        let dropped = self.dropped.clone();
+        appsink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    // analyzer に渡せなくても transcode は止めない
+                    let item = match T::from_sample(&sample) {
+                        Ok(item) => item,
+                        Err(err) => {
+                            log::warn!("Skip sample for analyzer: {}", err);
+                            return Ok(gst::FlowSuccess::Ok);
+                        },
+                    };
+                    if let Some(sender) = sender.lock().unwrap().as_ref() {
+                        if let Err(TrySendError::Full(_)) = sender.try_send(item) {
+                            dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        tee_el.link(&chain[0])?;
+        for el in chain.iter().rev() {
+            el.sync_state_with_parent()?;
+        }
+        log::debug!("Linked analyzer tap: {}", self.caps);
+
+        Ok(())
+    }
+}
+
+/// appsink から analyzer のスレッドに送るもの
+trait TapItem: Sized + Send + 'static {
+    /// sidecar に書く stream の種類
+    const KIND: &'static str;
+    const CONVERTER_NAMES: &'static [&'static str];
+
+    fn from_sample(sample: &gst::Sample) -> std::result::Result<Self, glib::BoolError>;
+    fn pts(&self) -> gst::ClockTime;
+    fn duration(&self) -> Option<gst::ClockTime>;
+}
+
+/// buffer の PTS を sample の segment で running time にする
+///
+/// buffer の PTS は入力ごとの timeline なので、 concat すると次の入力で 0 に戻る。
+/// concat は segment の base をずらすので、 running time にすると続けて増える
+fn running_time(sample: &gst::Sample, pts: gst::ClockTime) -> gst::ClockTime {
+    sample.segment()
+        .and_then(|segment| segment.downcast_ref::<gst::ClockTime>())
+        .and_then(|segment| segment.to_running_time(pts))
+        .unwrap_or(pts)
+}
+
+impl TapItem for DecodedFrame {
+    const KIND: &'static str = "video";
+    const CONVERTER_NAMES: &'static [&'static str] = &["videoconvert", "videoscale"];
+
+    fn from_sample(sample: &gst::Sample) -> std::result::Result<Self, glib::BoolError> {
+        let caps = sample.caps().ok_or_else(|| glib::bool_error!("Decoded frame has no caps"))?;
+        let info = gst_video::VideoInfo::from_caps(caps)?;
+        let buffer = sample.buffer_owned().ok_or_else(|| glib::bool_error!("Decoded frame has no buffer"))?;
+        let pts = running_time(sample, buffer.pts().ok_or_else(|| glib::bool_error!("Decoded frame has no PTS"))?);
+        let duration = buffer.duration();
+        let frame = gst_video::VideoFrame::from_buffer_readable(buffer, &info)
+            .map_err(|_| glib::bool_error!("Failed to map decoded frame at {}", pts))?;
+        Ok(Self { pts, duration, frame })
+    }
+
+    fn pts(&self) -> gst::ClockTime {
+        self.pts
+    }
+
+    fn duration(&self) -> Option<gst::ClockTime> {
+        self.duration
+    }
+}
+
+impl TapItem for AudioChunk {
+    const KIND: &'static str = "audio";
+    const CONVERTER_NAMES: &'static [&'static str] = &["audioconvert", "audioresample"];
+
+    fn from_sample(sample: &gst::Sample) -> std::result::Result<Self, glib::BoolError> {
+        let caps = sample.caps().ok_or_else(|| glib::bool_error!("Decoded audio has no caps"))?;
+        let info = gst_audio::AudioInfo::from_caps(caps)?;
+        let buffer = sample.buffer().ok_or_else(|| glib::bool_error!("Decoded audio has no buffer"))?;
+        let pts = running_time(sample, buffer.pts().ok_or_else(|| glib::bool_error!("Decoded audio has no PTS"))?);
+        let map = buffer.map_readable().map_err(|err| glib::bool_error!("Failed to map decoded audio at {}: {}", pts, err))?;
+        // f32 の alignment が揃っているとは限らないので byte から読む
+        let samples = map.chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        Ok(Self { pts, duration: buffer.duration(), sample_rate: info.rate(), channels: info.channels(), samples })
+    }
+
+    fn pts(&self) -> gst::ClockTime {
+        self.pts
+    }
+
+    fn duration(&self) -> Option<gst::ClockTime> {
+        self.duration
+    }
+}
+
+/// worker のスレッドで `FrameAnalyzer` と `AudioAnalyzer` を同じように扱う
+trait Analyzer<T>: Send + 'static {
+    fn name(&self) -> &str;
+    fn analyze(&mut self, item: &T) -> Option<Value>;
+    fn finish(&mut self) -> Option<Value>;
+}
+
+impl Analyzer<DecodedFrame> for Box<dyn FrameAnalyzer> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn analyze(&mut self, frame: &DecodedFrame) -> Option<Value> {
+        (**self).analyze(frame)
+    }
+
+    fn finish(&mut self) -> Option<Value> {
+        (**self).finish()
+    }
+}
+
+impl Analyzer<AudioChunk> for Box<dyn AudioAnalyzer> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn analyze(&mut self, chunk: &AudioChunk) -> Option<Value> {
+        (**self).analyze(chunk)
+    }
+
+    fn finish(&mut self) -> Option<Value> {
+        (**self).finish()
+    }
+}
+
+/// 送り先が全部閉じるまで受け取って analyzer に渡す
+fn spawn_worker<T: TapItem, A: Analyzer<T>>(mut analyzer: A, receiver: Receiver<T>, dropped: Arc<AtomicU64>) -> io::Result<JoinHandle<AnalyzerReport>> {
+    let name = analyzer.name().to_string();
+    thread::Builder::new().name(format!("analyze-{}", name)).spawn(move || {
+        let mut analyzed = 0;
+        let mut results = Vec::new();
+        for item in receiver {
+            analyzed += 1;
+            if let Some(value) = analyzer.analyze(&item) {
+                results.push(AnalysisResult { pts_ns: item.pts().nseconds(), duration_ns: item.duration().map(gst::ClockTime::nseconds), value });
+            }
+        }
+
+        AnalyzerReport { name, kind: T::KIND, analyzed, dropped: dropped.load(Ordering::Relaxed), results, summary: analyzer.finish() }
+    })
+}
+
+/// 無音を -inf にしないための下限
+const MIN_DBFS: f64 = -120.0;
+
+fn to_dbfs(amplitude: f64) -> f64 {
+    (20.0 * amplitude.log10()).max(MIN_DBFS)
+}
+
+/// フレームごとの平均の明るさ (0.0 - 1.0)
+#[derive(Debug, Default)]
+pub struct Brightness {
+    total: f64,
+    frames: u64,
+}
+
+impl FrameAnalyzer for Brightness {
+    fn name(&self) -> &str {
+        "brightness"
+    }
+
+    fn format(&self) -> gst_video::VideoFormat {
+        gst_video::VideoFormat::Gray8
+    }
+
+    fn analyze(&mut self, frame: &DecodedFrame) -> Option<Value> {
+        let width = frame.frame.width() as usize;
+        let height = frame.frame.height() as usize;
+        let stride = frame.frame.plane_stride()[0] as usize;
+        let plane = frame.frame.plane_data(0).ok()?;
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        // 行の後ろの stride の padding は数えない
+        let sum = plane.chunks(stride).take(height)
+            .map(|row| row[..width].iter().map(|&value| value as u64).sum::<u64>())
+            .sum::<u64>();
+        let mean = sum as f64 / (width * height) as f64 / 255.0;
+
+        self.total += mean;
+        self.frames += 1;
+        Some(json!({ "mean": mean }))
+    }
+
+    fn finish(&mut self) -> Option<Value> {
+        (self.frames > 0).then(|| json!({ "mean": self.total / self.frames as f64 }))
+    }
+}
+
+/// buffer ごとの RMS と peak (dBFS)
+#[derive(Debug, Default)]
+pub struct Level {
+    sum_squares: f64,
+    samples: u64,
+    peak: f64,
+}
+
+impl AudioAnalyzer for Level {
+    fn name(&self) -> &str {
+        "level"
+    }
+
+    fn analyze(&mut self, chunk: &AudioChunk) -> Option<Value> {
+        if chunk.samples.is_empty() {
+            return None;
+        }
+
+        let sum_squares = chunk.samples.iter().map(|&sample| (sample as f64).powi(2)).sum::<f64>();
+        let peak = chunk.samples.iter().map(|&sample| (sample as f64).abs()).fold(0.0, f64::max);
+
+        self.sum_squares += sum_squares;
+        self.samples += chunk.samples.len() as u64;
+        self.peak = self.peak.max(peak);
+        Some(json!({
+            "rms_dbfs": to_dbfs((sum_squares / chunk.samples.len() as f64).sqrt()),
+            "peak_dbfs": to_dbfs(peak),
+        }))
+    }
+
+    fn finish(&mut self) -> Option<Value> {
+        (self.samples > 0).then(|| json!({
+            "rms_dbfs": to_dbfs((self.sum_squares / self.samples as f64).sqrt()),
+            "peak_dbfs": to_dbfs(self.peak),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> gst::ClockTime {
+        gst::ClockTime::from_mseconds(ms)
+    }
+
+    fn chunk(samples: Vec<f32>) -> AudioChunk {
+        AudioChunk { pts: ms(0), duration: None, sample_rate: 48000, channels: 1, samples }
+    }
+
+    fn dbfs(value: &Value, key: &str) -> f64 {
+        value[key].as_f64().unwrap()
+    }
+
+    #[test]
+    fn level_of_silence_is_floor() {
+        let mut level = Level::default();
+        let value = level.analyze(&chunk(vec![0.0; 480])).unwrap();
+        assert_eq!(dbfs(&value, "rms_dbfs"), MIN_DBFS);
+        assert_eq!(dbfs(&value, "peak_dbfs"), MIN_DBFS);
+        assert!(level.analyze(&chunk(Vec::new())).is_none());
+    }
+
+    #[test]
+    fn level_of_full_scale_and_sine() {
+        let mut level = Level::default();
+        let value = level.analyze(&chunk(vec![1.0, -1.0, 1.0, -1.0])).unwrap();
+        assert!(dbfs(&value, "rms_dbfs").abs() < 1e-9);
+        assert!(dbfs(&value, "peak_dbfs").abs() < 1e-9);
+
+        // 周期ちょうどの sine の RMS は peak の 1/√2 (-3.01 dBFS)
+        let sine = (0..480).map(|index| (2.0 * std::f64::consts::PI * index as f64 / 48.0).sin() as f32 * 0.5).collect();
+        let value = level.analyze(&chunk(sine)).unwrap();
+        assert!((dbfs(&value, "rms_dbfs") - (20.0 * 0.5f64.log10() - 3.0103)).abs() < 0.01);
+        assert!((dbfs(&value, "peak_dbfs") - 20.0 * 0.5f64.log10()).abs() < 0.01);
+
+        // summary は全部の buffer を通した値
+        let summary = level.finish().unwrap();
+        assert!(dbfs(&summary, "peak_dbfs").abs() < 1e-9);
+        let rms = ((4.0 + 480.0 * 0.125) / 484.0f64).sqrt();
+        assert!((dbfs(&summary, "rms_dbfs") - 20.0 * rms.log10()).abs() < 0.01);
+    }
+
+    #[test]
+    fn level_without_buffers_has_no_summary() {
+        assert!(Level::default().finish().is_none());
+    }
+
+    #[test]
+    fn brightness_ignores_stride_padding() {
+        gst::init().unwrap();
+        // Gray8 の行は 4 byte に揃うので、幅 3 なら 1 byte の padding がある
+        let info = gst_video::VideoInfo::builder(gst_video::VideoFormat::Gray8, 3, 2).build().unwrap();
+        assert_eq!(info.stride()[0], 4);
+        let buffer = gst::Buffer::from_mut_slice(vec![
+            0, 0, 255, 255,
+            255, 255, 255, 255,
+        ]);
+        let frame = gst_video::VideoFrame::from_buffer_readable(buffer, &info).unwrap();
+        let frame = DecodedFrame { pts: ms(0), duration: None, frame };
+
+        let mut brightness = Brightness::default();
+        let value = brightness.analyze(&frame).unwrap();
+        assert!((value["mean"].as_f64().unwrap() - 4.0 / 6.0).abs() < 1e-9);
+        let summary = brightness.finish().unwrap();
+        assert!((summary["mean"].as_f64().unwrap() - 4.0 / 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn running_time_adds_segment_base() {
+        gst::init().unwrap();
+        // concat の 2 つ目の入力。 PTS は 5 秒から始まり、前の入力の 10 秒の後に続く
+        let mut segment = gst::FormattedSegment::<gst::ClockTime>::new();
+        segment.set_start(ms(5000));
+        segment.set_base(ms(10000));
+        let sample = gst::Sample::builder().segment(&segment).build();
+        assert_eq!(running_time(&sample, ms(5000)), ms(10000));
+        assert_eq!(running_time(&sample, ms(6000)), ms(11000));
+
+        // segment が無ければ PTS のまま
+        let sample = gst::Sample::builder().build();
+        assert_eq!(running_time(&sample, ms(6000)), ms(6000));
+    }
+}
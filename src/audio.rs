@@ -0,0 +1,509 @@
+use std::{fs::{self, File}, io::{self, BufWriter, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use gstreamer as gst;
+use gst::glib;
+use gstreamer_audio as gst_audio;
+use serde::Serialize;
+
+use crate::{error::{Error, Result}, extract::{self, SampleWriter}, npy, probe::StreamKind};
+
+/// window に分けたときに、各ファイルの時刻を書く sidecar のファイル名
+pub const INDEX_FILE_NAME: &str = "index.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFileFormat {
+    /// 32bit float の WAV (WAVE_FORMAT_IEEE_FLOAT)
+    Wav,
+    /// header のない little endian の float32 。 channel は interleave する
+    F32,
+    /// shape が (sample 数, channel 数) の float32 の NumPy 配列
+    Npy,
+}
+
+impl AudioFileFormat {
+    pub fn find(name: &str) -> std::result::Result<Self, glib::BoolError> {
+        match name.to_ascii_lowercase().as_str() {
+            "wav" => Ok(Self::Wav),
+            "f32" => Ok(Self::F32),
+            "npy" => Ok(Self::Npy),
+            _ => Err(glib::bool_error!("Unsupported audio format: {} (supported: wav, f32, npy)", name)),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> std::result::Result<Self, glib::BoolError> {
+        let ext = path.extension()
+            .ok_or_else(|| glib::bool_error!("Output path {} has no extension, use --format", path.display()))?;
+        Self::find(&ext.to_string_lossy())
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::F32 => "f32",
+            Self::Npy => "npy",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioExportConfig {
+    pub path: PathBuf,
+    /// window に分けるときはディレクトリ
+    pub output_path: PathBuf,
+    pub format: AudioFileFormat,
+    /// None なら入力のまま
+    pub sample_rate: Option<u32>,
+    /// None なら入力のまま
+    pub channels: Option<u32>,
+    /// 何番目の音声の stream を書き出すか
+    pub stream_index: usize,
+    /// 指定があれば、この長さごとに別のファイルに分けて、それぞれの時刻を `INDEX_FILE_NAME` に書く
+    /// 最後のファイルはこれより短くなる
+    pub window: Option<gst::ClockTime>,
+    pub diagnose_caps: bool,
+}
+
+impl AudioExportConfig {
+    pub fn new(path: impl Into<PathBuf>, output_path: impl Into<PathBuf>, format: AudioFileFormat) -> Self {
+        Self {
+            path: path.into(),
+            output_path: output_path.into(),
+            format,
+            sample_rate: None,
+            channels: None,
+            stream_index: 0,
+            window: None,
+            diagnose_caps: false,
+        }
+    }
+}
+
+/// 書き出した一つのファイル
+#[derive(Debug, Clone)]
+pub struct AudioWindow {
+    pub path: PathBuf,
+    /// 最初の sample の PTS
+    pub pts: gst::ClockTime,
+    /// channel をまとめて一つと数えた sample 数
+    pub frames: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioExport {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub frames: u64,
+    /// window に分けなければ一つだけ
+    pub windows: Vec<AudioWindow>,
+}
+
+/// `INDEX_FILE_NAME` の中身
+#[derive(Debug, Serialize)]
+struct Index<'a> {
+    sample_rate: u32,
+    channels: u32,
+    windows: Vec<IndexEntry<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct IndexEntry<'a> {
+    /// index と同じディレクトリからの相対パス
+    file: &'a str,
+    pts_ns: u64,
+    duration_ns: u64,
+    frames: u64,
+}
+
+/// 入力の音声を decode して、 float32 の sample を書き出す
+///
+/// sample_rate と channels の指定があれば audioresample と audioconvert で合わせる
+pub fn export_audio(config: &AudioExportConfig) -> Result<AudioExport> {
+    let mut caps_builder = gst_audio::AudioCapsBuilder::new_interleaved().format(gst_audio::AudioFormat::F32le);
+    if let Some(sample_rate) = config.sample_rate {
+        caps_builder = caps_builder.rate(sample_rate as i32);
+    }
+    if let Some(channels) = config.channels {
+        caps_builder = caps_builder.channels(channels as i32);
+    }
+    let raw_caps = caps_builder.build();
+
+    if config.window == Some(gst::ClockTime::ZERO) {
+        return Err(Error::Gst(glib::bool_error!("Audio window must be greater than zero")));
+    }
+    if config.window.is_some() {
+        fs::create_dir_all(&config.output_path)?;
+    }
+
+    let writer = extract::decode_to_writer(&config.path, StreamKind::Audio, config.stream_index, &raw_caps, config.diagnose_caps, AudioWriter::new(config))?;
+    writer.finish()
+}
+
+/// appsink から来た sample を window ごとのファイルに振り分けて書く
+struct AudioWriter {
+    output_path: PathBuf,
+    format: AudioFileFormat,
+    window: Option<gst::ClockTime>,
+    /// 最初の sample の caps から決める (sample rate, channel 数, WAV の channel mask)
+    layout: Option<(u32, u32, u32)>,
+    current: Option<(SampleFile, AudioWindow)>,
+    windows: Vec<AudioWindow>,
+    frames: u64,
+}
+
+impl AudioWriter {
+    fn new(config: &AudioExportConfig) -> Self {
+        Self {
+            output_path: config.output_path.clone(),
+            format: config.format,
+            window: config.window,
+            layout: None,
+            current: None,
+            windows: Vec::new(),
+            frames: 0,
+        }
+    }
+
+    fn close_window(&mut self) -> Result<()> {
+        if let Some((file, window)) = self.current.take() {
+            file.finish()?;
+            log::debug!("Wrote {} audio frames at {} to {}", window.frames, window.pts, window.path.display());
+            self.windows.push(window);
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<AudioExport> {
+        self.close_window()?;
+        let Some((sample_rate, channels, _)) = self.layout else {
+            return Err(Error::Gst(glib::bool_error!("No audio was decoded")));
+        };
+
+        if self.window.is_some() {
+            self.write_index(sample_rate, channels)?;
+        }
+
+        Ok(AudioExport { sample_rate, channels, frames: self.frames, windows: self.windows })
+    }
+
+    fn write_index(&self, sample_rate: u32, channels: u32) -> Result<()> {
+        let windows = self.windows.iter()
+            .map(|window| IndexEntry {
+                file: window.path.file_name().and_then(|name| name.to_str()).unwrap_or_default(),
+                pts_ns: window.pts.nseconds(),
+                duration_ns: duration_of(window.frames, sample_rate).nseconds(),
+                frames: window.frames,
+            })
+            .collect();
+        let index = Index { sample_rate, channels, windows };
+
+        let file = BufWriter::new(File::create(self.output_path.join(INDEX_FILE_NAME))?);
+        serde_json::to_writer_pretty(file, &index).map_err(io::Error::from)?;
+
+        Ok(())
+    }
+}
+
+impl SampleWriter for AudioWriter {
+    fn push(&mut self, sample: &gst::Sample) -> Result<()> {
+        let caps = sample.caps().ok_or_else(|| glib::bool_error!("Decoded audio has no caps"))?;
+        let info = gst_audio::AudioInfo::from_caps(caps).map_err(|err| glib::bool_error!("Invalid audio caps {}: {}", caps, err))?;
+        let (sample_rate, channels) = (info.rate(), info.channels());
+        let channel_mask = match self.layout {
+            None => {
+                let channel_mask = wav_channel_mask(&info);
+                self.layout = Some((sample_rate, channels, channel_mask));
+                channel_mask
+            },
+            // 一つのファイルの中で sample rate や channel 数は変えられない
+            Some(layout) if (layout.0, layout.1) != (sample_rate, channels) => {
+                return Err(Error::Gst(glib::bool_error!(
+                    "Audio changed from {}Hz {}ch to {}Hz {}ch, specify the sample rate and channels", layout.0, layout.1, sample_rate, channels,
+                )));
+            },
+            Some((_, _, channel_mask)) => channel_mask,
+        };
+
+        let Some(buffer) = sample.buffer() else {
+            return Ok(());
+        };
+        let map = buffer.map_readable().map_err(|err| glib::bool_error!("Failed to map audio buffer: {}", err))?;
+        let bpf = info.bpf() as usize;
+        let buffer_frames = map.len() / bpf;
+        // window が 1 sample より短くても進むようにする
+        let window_frames = self.window.map(|window| frames_in(window, sample_rate).max(1));
+
+        let mut offset = 0;
+        while offset < buffer_frames {
+            if self.current.is_none() {
+                // buffer の PTS がなければ、最初からの sample 数で数える
+                let pts = match buffer.pts() {
+                    Some(pts) => pts + duration_of(offset as u64, sample_rate),
+                    None => duration_of(self.frames, sample_rate),
+                };
+                let path = match self.window {
+                    Some(_) => self.output_path.join(format!("window_{:06}.{}", self.windows.len(), self.format.extension())),
+                    None => self.output_path.clone(),
+                };
+                let file = SampleFile::create(&path, self.format, sample_rate, channels, channel_mask)?;
+                self.current = Some((file, AudioWindow { path, pts, frames: 0 }));
+            }
+
+            let (file, window) = self.current.as_mut().expect("current window must be opened");
+            let frames = window_frames.map_or(buffer_frames - offset, |window_frames| (window_frames - window.frames) as usize)
+                .min(buffer_frames - offset);
+            file.write(&map[offset * bpf..(offset + frames) * bpf], frames as u64)?;
+            window.frames += frames as u64;
+            self.frames += frames as u64;
+            offset += frames;
+
+            if window_frames == Some(window.frames) {
+                self.close_window()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// 書いている途中のファイル
+///
+/// WAV と npy は header に長さが入るので、最初は 0 で書いておいて閉じるときに書き直す
+struct SampleFile {
+    writer: BufWriter<File>,
+    format: AudioFileFormat,
+    sample_rate: u32,
+    channels: u32,
+    channel_mask: u32,
+    frames: u64,
+}
+
+impl SampleFile {
+    fn create(path: &Path, format: AudioFileFormat, sample_rate: u32, channels: u32, channel_mask: u32) -> io::Result<Self> {
+        let mut file = Self { writer: BufWriter::new(File::create(path)?), format, sample_rate, channels, channel_mask, frames: 0 };
+        file.write_header()?;
+        Ok(file)
+    }
+
+    /// appsink の caps が F32LE なので、 buffer の中身をそのまま書ける
+    fn write(&mut self, data: &[u8], frames: u64) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.frames += frames;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if self.format != AudioFileFormat::F32 {
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.write_header()?;
+        }
+        self.writer.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        match self.format {
+            AudioFileFormat::Wav => write_wav_header(&mut self.writer, self.sample_rate, self.channels, self.channel_mask, self.frames),
+            AudioFileFormat::F32 => Ok(()),
+            AudioFileFormat::Npy => npy::write_header(&mut self.writer, "<f4", &[self.frames, self.channels as u64]),
+        }
+    }
+}
+
+/// WAVE_FORMAT_EXTENSIBLE の SubFormat に入れる KSDATAFORMAT_SUBTYPE_IEEE_FLOAT
+const IEEE_FLOAT_SUBFORMAT: [u8; 16] = [0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+/// RIFF header と fmt, fact chunk
+///
+/// float の WAV は PCM ではないので、 fmt に cbSize を入れて fact chunk に sample 数を書く。
+/// 3 channel 以上は channel の並びを書けるように WAVE_FORMAT_EXTENSIBLE にする
+fn write_wav_header<W: Write>(writer: &mut W, sample_rate: u32, channels: u32, channel_mask: u32, frames: u64) -> io::Result<()> {
+    let extensible = channels > 2;
+    let fmt_size: u32 = if extensible { 40 } else { 18 };
+    // data chunk の前までの RIFF の中身 ("WAVE", fmt, fact, data の chunk header)
+    let header_size = 4 + 8 + fmt_size + 12 + 8;
+
+    let block_align = channels * 4;
+    let data_size = u32::try_from(frames * block_align as u64).ok()
+        .filter(|data_size| *data_size <= u32::MAX - header_size)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "WAV output must be smaller than 4 GiB, use --window or another format"))?;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_u32::<LittleEndian>(header_size + data_size)?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_u32::<LittleEndian>(fmt_size)?;
+    writer.write_u16::<LittleEndian>(if extensible { 0xfffe } else { 3 })?;
+    writer.write_u16::<LittleEndian>(channels as u16)?;
+    writer.write_u32::<LittleEndian>(sample_rate)?;
+    writer.write_u32::<LittleEndian>(sample_rate * block_align)?;
+    writer.write_u16::<LittleEndian>(block_align as u16)?;
+    writer.write_u16::<LittleEndian>(32)?;
+    if extensible {
+        writer.write_u16::<LittleEndian>(22)?;
+        writer.write_u16::<LittleEndian>(32)?;
+        writer.write_u32::<LittleEndian>(channel_mask)?;
+        writer.write_all(&IEEE_FLOAT_SUBFORMAT)?;
+    } else {
+        writer.write_u16::<LittleEndian>(0)?;
+    }
+
+    // data_size が 4 GiB に収まるので、 sample 数も u32 に収まる
+    writer.write_all(b"fact")?;
+    writer.write_u32::<LittleEndian>(4)?;
+    writer.write_u32::<LittleEndian>(frames as u32)?;
+
+    writer.write_all(b"data")?;
+    writer.write_u32::<LittleEndian>(data_size)?;
+
+    Ok(())
+}
+
+/// caps の channel の並びを WAVE_FORMAT_EXTENSIBLE の dwChannelMask にする
+///
+/// mask は bit の順に channel が並んでいる前提なので、表せない位置があったり順番が違ったりしたら 0 (指定なし) にする
+fn wav_channel_mask(info: &gst_audio::AudioInfo) -> u32 {
+    use gst_audio::AudioChannelPosition as Position;
+
+    let Some(positions) = info.positions() else {
+        return 0;
+    };
+    let mut channel_mask = 0u32;
+    for position in positions {
+        let bit = match position {
+            Position::FrontLeft => 0x1,
+            Position::FrontRight => 0x2,
+            Position::FrontCenter => 0x4,
+            Position::Lfe1 => 0x8,
+            Position::RearLeft => 0x10,
+            Position::RearRight => 0x20,
+            Position::FrontLeftOfCenter => 0x40,
+            Position::FrontRightOfCenter => 0x80,
+            Position::RearCenter => 0x100,
+            Position::SideLeft => 0x200,
+            Position::SideRight => 0x400,
+            Position::TopCenter => 0x800,
+            Position::TopFrontLeft => 0x1000,
+            Position::TopFrontCenter => 0x2000,
+            Position::TopFrontRight => 0x4000,
+            Position::TopRearLeft => 0x8000,
+            Position::TopRearCenter => 0x10000,
+            Position::TopRearRight => 0x20000,
+            _ => return 0,
+        };
+        if bit <= channel_mask {
+            return 0;
+        }
+        channel_mask |= bit;
+    }
+    channel_mask
+}
+
+/// duration の間の sample 数
+fn frames_in(duration: gst::ClockTime, sample_rate: u32) -> u64 {
+    (duration.nseconds() as u128 * sample_rate as u128 / gst::ClockTime::SECOND.nseconds() as u128) as u64
+}
+
+fn duration_of(frames: u64, sample_rate: u32) -> gst::ClockTime {
+    gst::ClockTime::from_nseconds((frames as u128 * gst::ClockTime::SECOND.nseconds() as u128 / sample_rate as u128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    #[test]
+    fn writes_float_wav_header() {
+        let mut header = Vec::new();
+        write_wav_header(&mut header, 48000, 2, 0, 10).unwrap();
+
+        assert_eq!(header.len(), 58);
+        assert_eq!(&header[0..4], b"RIFF");
+        assert_eq!(u32_at(&header, 4), 50 + 80);
+        assert_eq!(&header[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&header, 16), 18);
+        assert_eq!(u16_at(&header, 20), 3);
+        assert_eq!(u16_at(&header, 22), 2);
+        assert_eq!(u32_at(&header, 24), 48000);
+        assert_eq!(u32_at(&header, 28), 48000 * 8);
+        assert_eq!(u16_at(&header, 32), 8);
+        assert_eq!(u16_at(&header, 34), 32);
+        // cbSize
+        assert_eq!(u16_at(&header, 36), 0);
+        assert_eq!(&header[38..42], b"fact");
+        assert_eq!(u32_at(&header, 42), 4);
+        assert_eq!(u32_at(&header, 46), 10);
+        assert_eq!(&header[50..54], b"data");
+        assert_eq!(u32_at(&header, 54), 80);
+    }
+
+    #[test]
+    fn writes_extensible_wav_header_for_multichannel() {
+        let mut header = Vec::new();
+        write_wav_header(&mut header, 48000, 6, 0x3f, 10).unwrap();
+
+        assert_eq!(header.len(), 80);
+        assert_eq!(u32_at(&header, 4), 72 + 240);
+        assert_eq!(u32_at(&header, 16), 40);
+        assert_eq!(u16_at(&header, 20), 0xfffe);
+        assert_eq!(u16_at(&header, 22), 6);
+        assert_eq!(u16_at(&header, 32), 24);
+        // cbSize, wValidBitsPerSample, dwChannelMask, SubFormat
+        assert_eq!(u16_at(&header, 36), 22);
+        assert_eq!(u16_at(&header, 38), 32);
+        assert_eq!(u32_at(&header, 40), 0x3f);
+        assert_eq!(&header[44..60], &IEEE_FLOAT_SUBFORMAT);
+        assert_eq!(&header[60..64], b"fact");
+        assert_eq!(u32_at(&header, 68), 10);
+        assert_eq!(&header[72..76], b"data");
+        assert_eq!(u32_at(&header, 76), 240);
+    }
+
+    #[test]
+    fn rejects_wav_larger_than_4gib() {
+        let mut header = Vec::new();
+        assert!(write_wav_header(&mut header, 48000, 2, 0, u32::MAX as u64 / 8).is_err());
+    }
+
+    #[test]
+    fn splits_buffers_into_windows() {
+        gst::init().unwrap();
+
+        let output_path = env::temp_dir().join(format!("learning-gstreamer-audio-test-{}", process::id()));
+        fs::create_dir_all(&output_path).unwrap();
+
+        let mut config = AudioExportConfig::new("input.mp4", &output_path, AudioFileFormat::F32);
+        config.window = Some(gst::ClockTime::from_mseconds(250));
+        let mut writer = AudioWriter::new(&config);
+
+        // 1000Hz mono で 300 sample ずつの buffer を二つ。 window は 250 sample
+        let info = gst_audio::AudioInfo::builder(gst_audio::AudioFormat::F32le, 1000, 1).build().unwrap();
+        let caps = info.to_caps().unwrap();
+        for pts in [0, 300] {
+            let mut buffer = gst::Buffer::from_mut_slice(vec![0u8; 300 * 4]);
+            buffer.get_mut().unwrap().set_pts(gst::ClockTime::from_mseconds(pts));
+            let sample = gst::Sample::builder().buffer(&buffer).caps(&caps).build();
+            writer.push(&sample).unwrap();
+        }
+        let export = writer.finish().unwrap();
+
+        let windows = export.windows.iter()
+            .map(|window| (window.pts.mseconds(), window.frames, fs::metadata(&window.path).unwrap().len()))
+            .collect::<Vec<_>>();
+        assert_eq!(windows, [(0, 250, 1000), (250, 250, 1000), (500, 100, 400)]);
+        assert_eq!(export.frames, 600);
+        assert!(output_path.join(INDEX_FILE_NAME).exists());
+
+        fs::remove_dir_all(&output_path).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+
+use crate::{analyze::Taps, probe::{StreamInfo, StreamKind}, timestamp, transcode::{self, Input, Transcode, TranscodeConfig}};
+
+/// 複数の入力を順番に再生して一つの encoder/mux に流す
+///
+/// (filesrc ! demux ! queue ! decodebin ! normalize)... ! concat ! (encode chain) ! mux ! filesink
+///
+/// concat は sink pad を request した順に再生して、 running time を前の入力の続きにずらしてくれる
+/// 入力ごとに解像度や sample rate が違うと encoder や mux が途中の caps 変更に対応できないので、
+/// concat の前で最初の入力に合わせて normalize する
+/// 入力ごとに映像と音声を一つずつ選んで繋ぐ。字幕や二つ目以降のトラックは使わない
+/// analyzer の tap は concat の後ろに繋ぐので、全部の入力を続けて running time で受け取る
+pub(crate) fn build_concat_pipeline(config: &TranscodeConfig, taps: &Taps) -> Result<Transcode, glib::BoolError> {
+    if config.is_trimmed() {
+        return Err(glib::bool_error!("Trimming is not supported with multiple inputs"));
+    }
+    if !config.copy_streams.is_empty() {
+        return Err(glib::bool_error!("Stream copy is not supported with multiple inputs"));
+    }
+    if !config.ladder.is_empty() {
+        return Err(glib::bool_error!("Multiple renditions are not supported with multiple inputs"));
+    }
+    if config.add_silent_audio {
+        return Err(glib::bool_error!("Silent audio is not supported with multiple inputs"));
+    }
+
+    // 入力ごとに使う stream を先に決めておく
+    let mut chosen_streams = HashMap::new();
+    for kind in [StreamKind::Video, StreamKind::Audio] {
+        if !config.output_format.accepts_kind(kind) {
+            continue;
+        }
+
+        let streams = config.inputs.iter().enumerate()
+            .map(|(input_index, input)| choose_stream(config, input_index, input, kind))
+            .collect::<Vec<_>>();
+        match (streams.iter().all(Option::is_some), streams.iter().any(Option::is_some)) {
+            (true, _) => {
+                for (input_index, stream) in streams.into_iter().enumerate() {
+                    chosen_streams.insert((input_index, kind), stream.expect("all inputs must have the stream").clone());
+                }
+            },
+            (false, true) => {
+                return Err(glib::bool_error!("Some inputs have no {:?} stream, all inputs must have the same kinds of streams", kind));
+            },
+            (false, false) => (),
+        }
+    }
+    if chosen_streams.is_empty() {
+        return Err(glib::bool_error!("No video or audio stream selected from inputs"));
+    }
+
+    let pipeline = gst::Pipeline::builder().name("transcode_pipeline").build();
+
+    let mux_el = transcode::add_output(&pipeline, config)?;
+
+    // 入力の順番通りに concat の sink pad を request しておく
+    let mut concat_pads = HashMap::new();
+    let mut eos_pads = Vec::new();
+    for kind in [StreamKind::Video, StreamKind::Audio] {
+        let Some(first_stream) = chosen_streams.get(&(0, kind)) else {
+            continue;
+        };
+
+        let concat_el = gst::ElementFactory::make("concat").build()?;
+        pipeline.add(&concat_el)?;
+
+        let chain = transcode::add_encode_chain(&pipeline, kind, config.encoding(kind))?;
+        let head_el = taps.link(&pipeline, kind, chain.first().expect("encode chain must not be empty"))?;
+        concat_el.link(&head_el)?;
+        // source に EOS を送ると concat が次の入力に切り替えてしまうので、 concat の後ろに送る
+        eos_pads.push(head_el.static_pad("sink").expect("encode chain head must have a sink pad"));
+        transcode::link_to_mux(chain.last().expect("encode chain must not be empty"), &mux_el, kind)?;
+
+        let normalized_caps = normalized_caps(kind, first_stream);
+        log::info!("Normalize {:?} streams to {}", kind, normalized_caps);
+        for input_index in 0..config.inputs.len() {
+            let sink_pad = concat_el.request_pad_simple("sink_%u").expect("concat must provide a sink pad");
+            concat_pads.insert((input_index, kind), (sink_pad, normalized_caps.clone()));
+        }
+    }
+    let concat_pads = Arc::new(Mutex::new(concat_pads));
+    let timestamp_repairs = Arc::new(Mutex::new(Vec::new()));
+
+    for (input_index, input) in config.inputs.iter().enumerate() {
+        let filesrc_el = gst::ElementFactory::make("filesrc").name(format!("src{}", input_index)).property("location", input.path.as_path()).build()?;
+        let demux_el = gst::ElementFactory::make(&input.demuxer_name).name(format!("demux{}", input_index)).build()?;
+        pipeline.add_many(&[&filesrc_el, &demux_el])?;
+        filesrc_el.link(&demux_el)?;
+
+        // 選んだ stream の pad が出てこないと concat がその入力を待ち続けて止まるので、エラーにする
+        {
+            let concat_pads = concat_pads.clone();
+            demux_el.connect_no_more_pads(move |demux_el| {
+                let concat_pads = concat_pads.lock().unwrap();
+                for kind in [StreamKind::Video, StreamKind::Audio] {
+                    if concat_pads.contains_key(&(input_index, kind)) {
+                        gst::element_error!(demux_el, gst::StreamError::Demux, ("Chosen {:?} stream of input {} was not found in {}", kind, input_index, demux_el.name()));
+                    }
+                }
+            });
+        }
+
+        let pipeline_weak = pipeline.downgrade();
+        let concat_pads = concat_pads.clone();
+        let chosen_streams = chosen_streams.clone();
+        let media_info = input.media_info.clone();
+        let repair_timestamps = config.repair_timestamps;
+        let timestamp_repairs = timestamp_repairs.clone();
+        let exposed_counts = Mutex::new(HashMap::<StreamKind, usize>::new());
+        demux_el.connect_pad_added(move |demux_el, pad| {
+            let Some(pipeline) = pipeline_weak.upgrade() else {
+                return;
+            };
+
+            let caps = pad.current_caps().expect("demuxer src pad must have caps");
+            let Some(kind) = StreamKind::from_caps(&caps) else {
+                log::debug!("Ignore demuxer pad: {} ({:?})", pad.name(), caps);
+                return;
+            };
+
+            let index = {
+                let mut exposed_counts = exposed_counts.lock().unwrap();
+                let count = exposed_counts.entry(kind).or_default();
+                *count += 1;
+                *count - 1
+            };
+
+            let stream = media_info.find_stream(pad.stream_id().as_deref(), kind, index);
+            let chosen = chosen_streams.get(&(input_index, kind)).map_or(false, |chosen| Some(chosen.index) == stream.map(|stream| stream.index));
+            let concat_pad = if chosen { concat_pads.lock().unwrap().remove(&(input_index, kind)) } else { None };
+
+            let result = match concat_pad {
+                Some((concat_pad, normalized_caps)) => {
+                    log::info!("Concat {:?} stream {} of input {} ({})", kind, index, input_index, pad.name());
+                    if repair_timestamps && kind == StreamKind::Video {
+                        let repair = timestamp::install(pad, stream.and_then(|stream| stream.framerate));
+                        timestamp_repairs.lock().unwrap().push((format!("{}:{}", demux_el.name(), pad.name()), repair));
+                    }
+                    link_normalize_branch(&pipeline, pad, kind, &normalized_caps, &concat_pad)
+                },
+                None => {
+                    log::info!("Skip {:?} stream {} of input {} ({})", kind, index, input_index, pad.name());
+                    transcode::link_discard_branch(&pipeline, pad)
+                },
+            };
+
+            if let Err(err) = result {
+                gst::element_error!(demux_el, gst::StreamError::Failed, ("Failed to build branch for {}: {}", pad.name(), err));
+            }
+        });
+    }
+
+    Ok(Transcode {
+        pipeline,
+        config: Arc::new(config.clone()),
+        trim_gates: Default::default(),
+        eos_pads,
+        timestamp_repairs,
+    })
+}
+
+/// selection にマッチした最初の stream を選ぶ
+fn choose_stream<'a>(config: &TranscodeConfig, input_index: usize, input: &'a Input, kind: StreamKind) -> Option<&'a StreamInfo> {
+    input.media_info.streams.iter()
+        .filter(|stream| stream.kind == kind)
+        .find(|stream| config.selection.is_selected(input_index, kind, stream.index, Some(stream)))
+}
+
+/// 最初の入力に合わせた raw caps
+fn normalized_caps(kind: StreamKind, stream: &StreamInfo) -> gst::Caps {
+    match kind {
+        StreamKind::Video => {
+            let mut builder = gst::Caps::builder("video/x-raw")
+                .field("format", "I420")
+                .field("pixel-aspect-ratio", gst::Fraction::new(1, 1));
+            if let (Some(width), Some(height)) = (stream.width, stream.height) {
+                builder = builder.field("width", width as i32).field("height", height as i32);
+            }
+            if let Some(framerate) = stream.framerate.filter(|framerate| framerate.numer() > 0) {
+                builder = builder.field("framerate", framerate);
+            }
+            builder.build()
+        },
+        _ => {
+            let mut builder = gst::Caps::builder("audio/x-raw")
+                .field("format", "S16LE")
+                .field("layout", "interleaved");
+            if let Some(sample_rate) = stream.sample_rate {
+                builder = builder.field("rate", sample_rate as i32);
+            }
+            if let Some(channels) = stream.channels {
+                builder = builder.field("channels", channels as i32);
+            }
+            builder.build()
+        },
+    }
+}
+
+/// src_pad ! queue ! decodebin ! convert ! scale/resample ! capsfilter ! concat_pad
+///
+/// videoscale は add-borders がデフォルトで有効なので、縦横比が違う入力は黒帯をつけて合わせる
+fn link_normalize_branch(pipeline: &gst::Pipeline, src_pad: &gst::Pad, kind: StreamKind, normalized_caps: &gst::Caps, concat_pad: &gst::Pad) -> Result<(), glib::BoolError> {
+    let converter_names: &[&str] = match kind {
+        StreamKind::Video => &["videoconvert", "videoscale", "videorate"],
+        _ => &["audioconvert", "audioresample"],
+    };
+
+    let mut chain = Vec::new();
+    for converter_name in converter_names {
+        chain.push(gst::ElementFactory::make(converter_name).build()?);
+    }
+    chain.push(gst::ElementFactory::make("capsfilter").property("caps", normalized_caps.clone()).build()?);
+
+    pipeline.add_many(&chain)?;
+    gst::Element::link_many(&chain)?;
+
+    let tail_src_pad = chain.last().expect("normalize chain must not be empty").static_pad("src").expect("capsfilter must have a src pad");
+    tail_src_pad.link(concat_pad)
+        .map_err(|err| glib::bool_error!("Failed to link to {}: {}", concat_pad.name(), err))?;
+
+    for el in chain.iter().rev() {
+        el.sync_state_with_parent()?;
+    }
+    transcode::link_decoder(pipeline, src_pad, chain.first().expect("normalize chain must not be empty"))?;
+
+    Ok(())
+}
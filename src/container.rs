@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+
+use crate::probe::StreamKind;
+
+/// 出力できる container と、それに使う muxer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputFormat {
+    /// `--format` で指定する名前で、拡張子と同じ
+    pub name: &'static str,
+    pub muxer_name: &'static str,
+    /// m4a, mka のような音声用の container では映像を捨てる
+    pub audio_only: bool,
+    /// HLS/DASH のように、出力先のディレクトリに playlist と segment を書く
+    /// muxer_name の element が filesink を含んでいる
+    pub segmented: bool,
+    /// CMAF のように映像か音声のどちらか一つしか書けない
+    pub single_stream: bool,
+}
+
+/// mp4/mov の moov や fragment の置き方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mp4Layout {
+    /// moov を最後に書く (mux のデフォルト)
+    #[default]
+    Default,
+    /// moov を先頭に書く。 mux は一時ファイルに mdat を書いておいて、最後に moov の後ろにコピーする
+    FastStart,
+    /// moof + mdat の fragment を一定の長さごとに書く (fMP4)
+    Fragmented { fragment_duration: gst::ClockTime },
+}
+
+pub const DEFAULT_FRAGMENT_DURATION: gst::ClockTime = gst::ClockTime::from_seconds(1);
+
+const OUTPUT_FORMATS: &[OutputFormat] = &[
+    OutputFormat { name: "mp4", muxer_name: "mp4mux", audio_only: false, segmented: false, single_stream: false },
+    OutputFormat { name: "mov", muxer_name: "qtmux", audio_only: false, segmented: false, single_stream: false },
+    OutputFormat { name: "m4a", muxer_name: "mp4mux", audio_only: true, segmented: false, single_stream: false },
+    OutputFormat { name: "mkv", muxer_name: "matroskamux", audio_only: false, segmented: false, single_stream: false },
+    OutputFormat { name: "mka", muxer_name: "matroskamux", audio_only: true, segmented: false, single_stream: false },
+    OutputFormat { name: "webm", muxer_name: "webmmux", audio_only: false, segmented: false, single_stream: false },
+    OutputFormat { name: "ts", muxer_name: "mpegtsmux", audio_only: false, segmented: false, single_stream: false },
+    OutputFormat { name: "ogg", muxer_name: "oggmux", audio_only: false, segmented: false, single_stream: false },
+    OutputFormat { name: "flv", muxer_name: "flvmux", audio_only: false, segmented: false, single_stream: false },
+    OutputFormat { name: "avi", muxer_name: "avimux", audio_only: false, segmented: false, single_stream: false },
+    OutputFormat { name: "hls", muxer_name: "hlssink2", audio_only: false, segmented: true, single_stream: false },
+    OutputFormat { name: "hls-fmp4", muxer_name: "hlscmafsink", audio_only: false, segmented: true, single_stream: true },
+    OutputFormat { name: "dash", muxer_name: "dashsink", audio_only: false, segmented: true, single_stream: false },
+];
+
+impl OutputFormat {
+    pub fn find(name: &str) -> Result<Self, glib::BoolError> {
+        let name = name.to_ascii_lowercase();
+        OUTPUT_FORMATS.iter()
+            .find(|format| format.name == name)
+            .copied()
+            .ok_or_else(|| {
+                let names = OUTPUT_FORMATS.iter().map(|format| format.name).collect::<Vec<_>>();
+                glib::bool_error!("Unsupported format: {} (supported: {})", name, names.join(", "))
+            })
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, glib::BoolError> {
+        let ext = path.extension()
+            .ok_or_else(|| glib::bool_error!("Output path {} has no extension, use --format", path.display()))?;
+        Self::find(&ext.to_string_lossy())
+    }
+
+    /// segment の sink の pad template は caps が ANY で字幕の pad もないので、字幕は書かない
+    pub fn accepts_kind(&self, kind: StreamKind) -> bool {
+        match kind {
+            StreamKind::Video => !self.audio_only,
+            StreamKind::Audio => true,
+            StreamKind::Subtitle => !self.segmented,
+        }
+    }
+
+    /// demuxer から出てきた codec をそのまま (stream copy で) mux できるか
+    ///
+    /// stream-format や alignment は parser が変換できるので、比較するときは無視する
+    pub fn accepts_codec(&self, caps: &gst::CapsRef) -> bool {
+        let Some(structure) = caps.structure(0) else {
+            return false;
+        };
+
+        let mut structure = structure.to_owned();
+        for field in ["stream-format", "alignment", "codec_data", "parsed", "framed"] {
+            structure.remove_field(field);
+        }
+        let mut codec_caps = gst::Caps::new_empty();
+        codec_caps.make_mut().append_structure(structure);
+
+        match pad_template_caps(self.muxer_name, gst::PadDirection::Sink) {
+            Ok(muxer_caps) => muxer_caps.iter().any(|muxer_caps| muxer_caps.can_intersect(&codec_caps)),
+            Err(_) => false,
+        }
+    }
+
+    /// encoder の src pad template の caps を muxer の sink pad template が受け付けるか
+    ///
+    /// pipeline を動かしてから not-negotiated で落ちるより先に、 registry の情報だけで確認する
+    pub fn check_encoder(&self, encoder_name: &str) -> Result<(), glib::BoolError> {
+        let muxer_caps = pad_template_caps(self.muxer_name, gst::PadDirection::Sink)?;
+        let encoder_caps = pad_template_caps(encoder_name, gst::PadDirection::Src)?;
+
+        let accepted = encoder_caps.iter().any(|encoder_caps| muxer_caps.iter().any(|muxer_caps| encoder_caps.can_intersect(muxer_caps)));
+        if !accepted {
+            return Err(glib::bool_error!(
+                "{} output is not accepted by {} ({}): {}",
+                encoder_name,
+                self.muxer_name,
+                self.name,
+                encoder_caps.iter().map(|caps| caps.to_string()).collect::<Vec<_>>().join("; "),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// moov の置き方を変えられるのは isomp4 の muxer だけ
+    pub fn check_mp4_layout(&self, layout: Mp4Layout) -> Result<(), glib::BoolError> {
+        if layout == Mp4Layout::Default {
+            return Ok(());
+        }
+        if !matches!(self.muxer_name, "mp4mux" | "qtmux") {
+            let layout_name = match layout {
+                Mp4Layout::Fragmented { .. } => "Fragmented output",
+                _ => "Fast start",
+            };
+            return Err(glib::bool_error!("{} is only supported for mp4, mov and m4a, not {}", layout_name, self.name));
+        }
+        if let Mp4Layout::Fragmented { fragment_duration } = layout {
+            // fragment-duration は ms 単位で、 0 だと fragment にならない
+            if fragment_duration.mseconds() == 0 || fragment_duration.mseconds() > u32::MAX as u64 {
+                return Err(glib::bool_error!("Invalid fragment duration: {}", fragment_duration));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `check_mp4_layout` で確認してから呼ぶこと
+    pub fn configure_muxer(&self, mux_el: &gst::Element, layout: Mp4Layout) {
+        match layout {
+            Mp4Layout::Default => (),
+            Mp4Layout::FastStart => mux_el.set_property("faststart", true),
+            Mp4Layout::Fragmented { fragment_duration } => mux_el.set_property("fragment-duration", fragment_duration.mseconds() as u32),
+        }
+    }
+}
+
+fn pad_template_caps(factory_name: &str, direction: gst::PadDirection) -> Result<Vec<gst::Caps>, glib::BoolError> {
+    let factory = gst::ElementFactory::find(factory_name)
+        .ok_or_else(|| glib::bool_error!("No such element: {}", factory_name))?;
+
+    Ok(factory.static_pad_templates()
+        .into_iter()
+        .filter(|template| template.direction() == direction)
+        .map(|template| template.caps())
+        .collect())
+}
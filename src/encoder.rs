@@ -0,0 +1,334 @@
+use std::{fmt, path::Path};
+
+use gstreamer as gst;
+use gst::{glib, glib::translate::IntoGlib, prelude::*};
+
+use crate::{probe::StreamKind, profile};
+
+/// 同じ codec の encoder を使いたい順に並べたもの
+/// 指定された encoder が入っていなければ、それより後ろにある encoder を使う
+const FALLBACK_CHAINS: &[&[&str]] = &[
+    &["x264enc", "openh264enc", "avenc_h264"],
+    &["avenc_aac", "fdkaacenc", "voaacenc"],
+];
+
+/// two-pass encode で使うプロパティ
+struct MultipassParams {
+    factory_name: &'static str,
+    /// pass を切り替えるプロパティと、 1 pass 目と 2 pass 目の値
+    mode_property: &'static str,
+    first_pass: &'static str,
+    second_pass: &'static str,
+    /// 1 pass 目で書いて 2 pass 目で読む統計ファイルのパス
+    stats_property: &'static str,
+}
+
+const MULTIPASS_PARAMS: &[MultipassParams] = &[
+    MultipassParams { factory_name: "x264enc", mode_property: "pass", first_pass: "pass1", second_pass: "pass2", stats_property: "multipass-cache-file" },
+    MultipassParams { factory_name: "vp8enc", mode_property: "multipass-mode", first_pass: "first-pass", second_pass: "last-pass", stats_property: "multipass-cache-file" },
+    MultipassParams { factory_name: "vp9enc", mode_property: "multipass-mode", first_pass: "first-pass", second_pass: "last-pass", stats_property: "multipass-cache-file" },
+];
+
+/// `x264enc bitrate=2000` のような、 element 名とプロパティの組で指定された encoder
+#[derive(Debug, Clone)]
+pub struct EncoderSpec {
+    pub factory_name: String,
+    pub properties: Vec<(String, String)>,
+}
+
+impl EncoderSpec {
+    /// 空白で区切る。空白を含む値は `option-string="ref=2 bframes=0"` のように `"` で囲む
+    pub fn parse(spec: &str) -> Result<Self, glib::BoolError> {
+        let tokens = split_spec(spec)?;
+        let mut tokens = tokens.iter();
+        let factory_name = tokens.next().ok_or_else(|| glib::bool_error!("Empty encoder spec"))?;
+
+        let mut encoder = Self { factory_name: factory_name.to_string(), properties: Vec::new() };
+        for token in tokens {
+            encoder.set_property_from_arg(token)?;
+        }
+
+        Ok(encoder)
+    }
+
+    /// 同じプロパティがすでにあれば上書きする
+    pub fn set_property(&mut self, key: &str, value: &str) {
+        self.properties.retain(|(existing_key, _)| existing_key != key);
+        self.properties.push((key.to_string(), value.to_string()));
+    }
+
+    /// `key=value` の形のプロパティ指定
+    pub fn set_property_from_arg(&mut self, arg: &str) -> Result<(), glib::BoolError> {
+        match arg.split_once('=') {
+            Some((key, value)) if !key.is_empty() => {
+                self.set_property(key, value);
+                Ok(())
+            },
+            _ => Err(glib::bool_error!("Invalid property `{}` for {}, expected key=value", arg, self.factory_name)),
+        }
+    }
+
+    pub fn make(&self) -> Result<gst::Element, glib::BoolError> {
+        let el = gst::ElementFactory::make(&self.factory_name).build()?;
+
+        for (key, value) in &self.properties {
+            // 文字列のままだと型がわからないので、プロパティの型に合わせて deserialize する
+            // parse_launch の中でやっているのと同じこと
+            let Some(pspec) = el.find_property(key) else {
+                let properties = el.list_properties();
+                return Err(match closest_match(key, properties.iter().map(|pspec| pspec.name())) {
+                    Some(candidate) => glib::bool_error!("{} has no property `{}`, did you mean `{}`?", self.factory_name, key, candidate),
+                    None => glib::bool_error!("{} has no property `{}`", self.factory_name, key),
+                });
+            };
+            if !pspec.flags().contains(glib::ParamFlags::WRITABLE) {
+                return Err(glib::bool_error!("{}:{} is not writable", self.factory_name, key));
+            }
+            let value = glib::Value::deserialize(value, pspec.value_type())
+                .map_err(|_| glib::bool_error!("Invalid value `{}` for {}:{} ({})", value, self.factory_name, key, pspec.value_type()))?;
+            el.set_property_from_value(key, &value);
+        }
+
+        Ok(el)
+    }
+
+    /// registry にない encoder なら、 fallback chain で見つかった encoder に置き換える
+    ///
+    /// bitrate のように encoder ごとに名前や単位が違うプロパティは変換して、
+    /// 置き換えた encoder にないプロパティは捨てる
+    pub fn resolve(&self) -> Result<Self, glib::BoolError> {
+        let factory_name = find_available_encoder(&self.factory_name)?;
+        if factory_name == self.factory_name {
+            return Ok(self.clone());
+        }
+
+        let el = gst::ElementFactory::make(&factory_name).build()?;
+        let mut encoder = Self { factory_name, properties: Vec::new() };
+        for (key, value) in &self.properties {
+            if let Some((key, value)) = profile::translate_property(&self.factory_name, &encoder.factory_name, key, value) {
+                encoder.set_property(&key, &value);
+            } else if el.find_property(key).is_some() {
+                encoder.set_property(key, value);
+            } else {
+                log::warn!("{} has no property `{}`, ignore {}={}", encoder.factory_name, key, key, value);
+            }
+        }
+
+        Ok(encoder)
+    }
+
+    /// pipeline を動かす前に、 element が作れて全部のプロパティが設定できるかを確認する
+    pub fn validate(&self) -> Result<(), glib::BoolError> {
+        self.make().map(|_| ())
+    }
+
+    /// encoder の src pad template の caps
+    pub fn src_template_caps(&self) -> Result<gst::Caps, glib::BoolError> {
+        let factory = gst::ElementFactory::find(&self.factory_name)
+            .ok_or_else(|| glib::bool_error!("No such element: {}", self.factory_name))?;
+
+        factory.static_pad_templates()
+            .into_iter()
+            .find(|template| template.direction() == gst::PadDirection::Src)
+            .map(|template| template.caps())
+            .ok_or_else(|| glib::bool_error!("{} has no src pad template", self.factory_name))
+    }
+}
+
+/// `parse` で読める形で書く
+impl fmt::Display for EncoderSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.factory_name)?;
+        for (key, value) in &self.properties {
+            if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+                write!(f, " {}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\""))?;
+            } else {
+                write!(f, " {}={}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 空白で区切って、 `"` で囲んだところは空白も含めて一つにする。 `"` の中では `\"` と `\\` で `"` と `\` を書く
+fn split_spec(spec: &str) -> Result<Vec<String>, glib::BoolError> {
+    let mut tokens = Vec::new();
+    let mut token: Option<String> = None;
+    let mut chars = spec.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let token = token.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => token.push(escaped),
+                            None => return Err(glib::bool_error!("Unterminated quote in encoder spec `{}`", spec)),
+                        },
+                        Some(c) => token.push(c),
+                        None => return Err(glib::bool_error!("Unterminated quote in encoder spec `{}`", spec)),
+                    }
+                }
+            },
+            c if c.is_whitespace() => tokens.extend(token.take()),
+            c => token.get_or_insert_with(String::new).push(c),
+        }
+    }
+    tokens.extend(token);
+
+    Ok(tokens)
+}
+
+/// encoder と、その前後に挟む caps filter
+#[derive(Debug, Clone)]
+pub struct Encoding {
+    pub encoder: EncoderSpec,
+    /// encoder に入れる前の raw caps (解像度、 framerate 、 sample rate など)
+    pub raw_caps: Option<gst::Caps>,
+    /// encoder が出す caps (h264 の profile や level など)
+    pub encoded_caps: Option<gst::Caps>,
+}
+
+impl From<EncoderSpec> for Encoding {
+    fn from(encoder: EncoderSpec) -> Self {
+        Self { encoder, raw_caps: None, encoded_caps: None }
+    }
+}
+
+impl Encoding {
+    pub fn validate(&self) -> Result<(), glib::BoolError> {
+        self.encoder.validate()?;
+
+        if let Some(encoded_caps) = &self.encoded_caps {
+            let template_caps = self.encoder.src_template_caps()?;
+            if !template_caps.can_intersect(encoded_caps) {
+                return Err(glib::bool_error!("{} cannot output {}", self.encoder.factory_name, encoded_caps));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 1 pass 目 (統計ファイルを書くだけ) と 2 pass 目 (統計ファイルを読んで encode する) の encoding
+    pub fn two_pass(&self, stats_path: &Path) -> Result<(Self, Self), glib::BoolError> {
+        let factory_name = &self.encoder.factory_name;
+        let params = MULTIPASS_PARAMS.iter()
+            .find(|params| params.factory_name == factory_name)
+            .ok_or_else(|| glib::bool_error!("{} has no multipass property, two-pass encoding is not supported", factory_name))?;
+
+        let stats_path = stats_path.to_string_lossy();
+        let mut first = self.clone();
+        first.encoder.set_property(params.mode_property, params.first_pass);
+        first.encoder.set_property(params.stats_property, &stats_path);
+        let mut second = self.clone();
+        second.encoder.set_property(params.mode_property, params.second_pass);
+        second.encoder.set_property(params.stats_property, &stats_path);
+
+        first.validate()?;
+        second.validate()?;
+
+        Ok((first, second))
+    }
+}
+
+/// registry にある encoder
+#[derive(Debug, Clone)]
+pub struct EncoderInfo {
+    pub name: String,
+    pub long_name: String,
+    pub kind: StreamKind,
+    pub rank: i32,
+    pub src_caps: Vec<gst::Caps>,
+}
+
+/// registry にある映像と音声の encoder を、種類ごとに rank が高い順に返す
+pub fn list_encoders() -> Vec<EncoderInfo> {
+    let mut encoders = Vec::new();
+    for (kind, factory_type) in [(StreamKind::Video, gst::ElementFactoryType::VIDEO_ENCODER), (StreamKind::Audio, gst::ElementFactoryType::AUDIO_ENCODER)] {
+        let mut factories = gst::ElementFactory::factories_with_type(factory_type, gst::Rank::None).into_iter().collect::<Vec<_>>();
+        factories.sort_by_key(|factory| (-factory.rank().into_glib(), factory.name().to_string()));
+
+        for factory in factories {
+            let src_caps = factory.static_pad_templates()
+                .into_iter()
+                .filter(|template| template.direction() == gst::PadDirection::Src)
+                .map(|template| template.caps())
+                .collect();
+            encoders.push(EncoderInfo {
+                name: factory.name().to_string(),
+                long_name: factory.longname().to_string(),
+                kind,
+                rank: factory.rank().into_glib(),
+                src_caps,
+            });
+        }
+    }
+    encoders
+}
+
+/// registry にある encoder の名前を返す
+///
+/// なければ fallback chain でそれより後ろにある encoder を探して、それもなければ名前が近い encoder を提案する
+pub fn find_available_encoder(factory_name: &str) -> Result<String, glib::BoolError> {
+    if let Some(factory) = gst::ElementFactory::find(factory_name) {
+        if !factory.has_type(gst::ElementFactoryType::ENCODER) {
+            return Err(glib::bool_error!("{} is not an encoder ({})", factory_name, factory.longname()));
+        }
+        return Ok(factory_name.to_string());
+    }
+
+    let fallback = FALLBACK_CHAINS.iter()
+        .filter_map(|chain| chain.iter().position(|name| *name == factory_name).map(|i| &chain[i + 1..]))
+        .flatten()
+        .find(|name| gst::ElementFactory::find(name).is_some());
+    if let Some(fallback) = fallback {
+        log::warn!("{} is not installed, fall back to {}", factory_name, fallback);
+        return Ok(fallback.to_string());
+    }
+
+    let encoders = list_encoders();
+    Err(match closest_match(factory_name, encoders.iter().map(|encoder| encoder.name.as_str())) {
+        Some(candidate) => glib::bool_error!("No such encoder: {}, did you mean `{}`?", factory_name, candidate),
+        None => glib::bool_error!("No such encoder: {} (see list-encoders)", factory_name),
+    })
+}
+
+/// typo したときに候補を出すため、編集距離が一番近いものを返す
+pub fn closest_match<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    candidates.into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= name.len().max(3) / 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev_row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let cost = if a_char == *b_char { 0 } else { 1 };
+            row[j + 1] = (prev_row[j] + cost).min(prev_row[j + 1] + 1).min(row[j] + 1);
+        }
+        prev_row = row;
+    }
+    prev_row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoder_spec_quotes_values_with_whitespace() {
+        let encoder = EncoderSpec::parse(r#"x264enc option-string="ref=2 bframes=0" bitrate=2000"#).unwrap();
+        assert_eq!(encoder.properties, [
+            ("option-string".to_string(), "ref=2 bframes=0".to_string()),
+            ("bitrate".to_string(), "2000".to_string()),
+        ]);
+        assert_eq!(encoder.to_string(), r#"x264enc option-string="ref=2 bframes=0" bitrate=2000"#);
+        assert!(EncoderSpec::parse(r#"x264enc option-string="ref=2"#).is_err());
+    }
+}
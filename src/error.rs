@@ -0,0 +1,110 @@
+use std::{fmt, io};
+
+use gstreamer as gst;
+use gst::glib;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// library の公開 API が返すエラー
+///
+/// pipeline を組み立てる中の関数は `glib::BoolError` を返すので、 `Gst` に包んで返す
+#[derive(Debug)]
+pub enum Error {
+    /// gst::init に失敗した
+    Init(glib::Error),
+    /// job の内容が正しくない (encoder 名の typo や、使えない option の組み合わせなど)
+    InvalidJob(glib::BoolError),
+    /// pipeline や element を作れなかった
+    Gst(glib::BoolError),
+    StateChange(gst::StateChangeError),
+    /// pipeline が bus に error を流した
+    Pipeline {
+        /// error を出した object の path
+        source: Option<String>,
+        error: glib::Error,
+        debug: Option<String>,
+        /// caps の negotiation を記録していたときの、どの link で caps が合わなかったか
+        negotiation_report: Option<String>,
+    },
+    /// EOS を送ってから、 mux が出力を書き終えなかった
+    EosTimeout,
+    /// 出力を書く前に中断された (two-pass の 1 pass 目など)
+    Interrupted,
+    Io(io::Error),
+    Mp4(mp4::Error),
+    /// ISO-BMFF の中身が想定と違う
+    Isobmff(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Init(err) => write!(f, "Failed to init gstreamer: {}", err),
+            Self::InvalidJob(err) => write!(f, "{}", err),
+            Self::Gst(err) => write!(f, "{}", err),
+            Self::StateChange(err) => write!(f, "Failed to change pipeline state: {}", err),
+            Self::Pipeline { source, error, debug, negotiation_report } => {
+                write!(f, "Error from {:?}: {} ({:?})", source, error, debug)?;
+                if let Some(negotiation_report) = negotiation_report {
+                    write!(f, "\n{}", negotiation_report)?;
+                }
+                Ok(())
+            },
+            Self::EosTimeout => write!(f, "Timed out waiting for the output to be finalized"),
+            Self::Interrupted => write!(f, "Interrupted before any output was written"),
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Mp4(err) => write!(f, "{}", err),
+            Self::Isobmff(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Init(err) => Some(err),
+            Self::InvalidJob(err) | Self::Gst(err) => Some(err),
+            Self::StateChange(err) => Some(err),
+            Self::Pipeline { error, .. } => Some(error),
+            Self::Io(err) => Some(err),
+            Self::Mp4(err) => Some(err),
+            Self::EosTimeout | Self::Interrupted | Self::Isobmff(_) => None,
+        }
+    }
+}
+
+impl From<glib::BoolError> for Error {
+    fn from(err: glib::BoolError) -> Self {
+        Self::Gst(err)
+    }
+}
+
+impl From<gst::StateChangeError> for Error {
+    fn from(err: gst::StateChangeError) -> Self {
+        Self::StateChange(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<mp4::Error> for Error {
+    fn from(err: mp4::Error) -> Self {
+        Self::Mp4(err)
+    }
+}
+
+impl Error {
+    /// bus に流れてきた error message から作る
+    pub(crate) fn from_message(msg: &gst::Message, err: &gst::message::Error, negotiation_report: Option<String>) -> Self {
+        Self::Pipeline {
+            source: msg.src().map(|src| src.path_string().to_string()),
+            error: err.error(),
+            debug: err.debug().map(|debug| debug.to_string()),
+            negotiation_report,
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{ops::ControlFlow, path::Path, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+use gstreamer_app as gst_app;
+
+use crate::{
+    error::{Error, Result},
+    negotiation::NegotiationTracer,
+    probe::{self, MediaInfo, StreamKind},
+    transcode,
+};
+
+/// 取り出すのを途中でやめたことを bus の message loop に伝える application message の名前
+const EXTRACT_DONE_MESSAGE: &str = "extract-done";
+
+/// filesrc ! demux ! queue ! decodebin ! convert ! appsink で、 kind の index 番目の stream を raw_caps にして取り出す
+///
+/// appsink に来た sample ごとに `on_sample` を streaming thread から呼ぶ
+/// `on_sample` が `ControlFlow::Break` を返したら、 EOS を待たずにそこで止める
+/// `on_sample` が返したエラーは pipeline のエラーより優先して返す
+/// diagnose_caps なら `NegotiationTracer` を attach する
+pub(crate) fn decode_to_appsink<F>(path: &Path, kind: StreamKind, index: usize, raw_caps: &gst::Caps, diagnose_caps: bool, mut on_sample: F) -> Result<MediaInfo>
+where
+    F: FnMut(&gst::Sample) -> Result<ControlFlow<()>> + Send + 'static,
+{
+    // 拡張子ではなく中身 (typefind) で container を判定して demuxer を選ぶ
+    let media_info = probe::discover(path)?;
+    let demuxer_name = probe::find_demuxer(&media_info);
+    if !media_info.streams.iter().any(|stream| stream.kind == kind && stream.index == index) {
+        return Err(Error::Gst(glib::bool_error!("{} has no {:?} stream {}", path.display(), kind, index)));
+    }
+
+    let pipeline = gst::Pipeline::builder().name("extract_pipeline").build();
+    let filesrc_el = gst::ElementFactory::make("filesrc").name("src").property("location", path).build()?;
+    let demux_el = gst::ElementFactory::make(&demuxer_name).name("demux").build()?;
+    pipeline.add_many(&[&filesrc_el, &demux_el])?;
+    gst::Element::link_many(&[&filesrc_el, &demux_el])?;
+
+    let converter_names: &[&str] = match kind {
+        StreamKind::Video => &["videoconvert", "videoscale"],
+        _ => &["audioconvert", "audioresample"],
+    };
+    let mut chain = Vec::new();
+    for converter_name in converter_names {
+        chain.push(gst::ElementFactory::make(converter_name).build()?);
+    }
+    // 解像度や sample rate を変えずに済むなら converter は passthrough になってコピーしない
+    let appsink = gst_app::AppSink::builder().caps(raw_caps).sync(false).build();
+    chain.push(appsink.clone().upcast());
+    pipeline.add_many(&chain)?;
+    gst::Element::link_many(&chain)?;
+
+    let failure = Arc::new(Mutex::new(None));
+    {
+        let failure = failure.clone();
+        appsink.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    match on_sample(&sample) {
+                        Ok(ControlFlow::Continue(())) => Ok(gst::FlowSuccess::Ok),
+                        Ok(ControlFlow::Break(())) => {
+                            let msg = gst::message::Application::builder(gst::Structure::new_empty(EXTRACT_DONE_MESSAGE)).src(appsink).build();
+                            let _ = appsink.post_message(msg);
+                            Err(gst::FlowError::Eos)
+                        },
+                        Err(err) => {
+                            *failure.lock().unwrap() = Some(err);
+                            Err(gst::FlowError::Error)
+                        },
+                    }
+                })
+                .build(),
+        );
+    }
+
+    {
+        let pipeline_weak = pipeline.downgrade();
+        let head_el = chain[0].clone();
+        let exposed_count = AtomicUsize::new(0);
+        demux_el.connect_pad_added(move |demux_el, pad| {
+            let Some(pipeline) = pipeline_weak.upgrade() else {
+                return;
+            };
+
+            let pad_kind = pad.current_caps().and_then(|caps| StreamKind::from_caps(&caps));
+            let matched = pad_kind == Some(kind) && exposed_count.fetch_add(1, Ordering::SeqCst) == index;
+
+            // 使わない stream も繋いでおかないと、 demuxer が not-linked で止まることがある
+            let result = if matched {
+                log::debug!("Extract {:?} stream {} ({})", kind, index, pad.name());
+                transcode::link_decoder(&pipeline, pad, &head_el)
+            } else {
+                transcode::link_discard_branch(&pipeline, pad)
+            };
+            if let Err(err) = result {
+                gst::element_error!(demux_el, gst::StreamError::Failed, ("Failed to link {}: {}", pad.name(), err));
+            }
+        });
+    }
+
+    // decodebin の中の element も後から作られるので、 PLAYING にする前に probe をつけておく
+    let negotiation_tracer = diagnose_caps.then(|| NegotiationTracer::attach(pipeline.upcast_ref()));
+
+    let result = run(&pipeline, negotiation_tracer.as_ref());
+    let state_result = pipeline.set_state(gst::State::Null);
+
+    if let Some(err) = failure.lock().unwrap().take() {
+        return Err(err);
+    }
+    result?;
+    state_result?;
+
+    Ok(media_info)
+}
+
+/// appsink から来た sample を受け取ってファイルに書くもの
+pub(crate) trait SampleWriter: Send + 'static {
+    fn push(&mut self, sample: &gst::Sample) -> Result<()>;
+}
+
+/// `decode_to_appsink` で来た sample を全部 writer に渡して、 EOS まで書いた writer を返す
+pub(crate) fn decode_to_writer<W: SampleWriter>(path: &Path, kind: StreamKind, index: usize, raw_caps: &gst::Caps, diagnose_caps: bool, writer: W) -> Result<W> {
+    // appsink の callback は streaming thread で動くので、終わった後に取り出せるように共有する
+    let writer = Arc::new(Mutex::new(Some(writer)));
+    {
+        let writer = writer.clone();
+        decode_to_appsink(path, kind, index, raw_caps, diagnose_caps, move |sample| {
+            writer.lock().unwrap().as_mut().expect("writer must exist until decoding finishes").push(sample)?;
+            Ok(ControlFlow::Continue(()))
+        })?;
+    }
+
+    let writer = writer.lock().unwrap().take().expect("writer must exist until decoding finishes");
+    Ok(writer)
+}
+
+/// PLAYING にして、 EOS か `on_sample` が止めるまで bus を見る
+fn run(pipeline: &gst::Pipeline, negotiation_tracer: Option<&NegotiationTracer>) -> Result<()> {
+    pipeline.set_state(gst::State::Playing)?;
+
+    let bus = pipeline.bus().expect("pipeline must have a bus");
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        match msg.view() {
+            gst::MessageView::Eos(..) => break,
+            gst::MessageView::Application(application) if application.structure().map_or(false, |s| s.name() == EXTRACT_DONE_MESSAGE) => break,
+            gst::MessageView::Error(err) => {
+                let negotiation_report = negotiation_tracer
+                    .filter(|_| NegotiationTracer::is_negotiation_error(err))
+                    .map(NegotiationTracer::report);
+                return Err(Error::from_message(&msg, err, negotiation_report));
+            },
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
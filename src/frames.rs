@@ -0,0 +1,253 @@
+use std::{collections::VecDeque, fs, ops::ControlFlow, path::PathBuf};
+
+use gstreamer as gst;
+use gst::glib;
+use gstreamer_video as gst_video;
+
+use crate::{error::{Error, Result}, extract, probe::StreamKind};
+
+/// PNG/JPEG に encode するのを待つ時間
+const CONVERT_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(10);
+
+/// どのフレームを書き出すか
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameSelection {
+    /// この間隔ごとに、その時刻に表示されているフレーム
+    Interval(gst::ClockTime),
+    /// それぞれの時刻に表示されているフレーム
+    Timestamps(Vec<gst::ClockTime>),
+    /// keyframe だけ
+    Keyframes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    pub fn find(name: &str) -> std::result::Result<Self, glib::BoolError> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Ok(Self::Png),
+            "jpg" | "jpeg" => Ok(Self::Jpeg),
+            _ => Err(glib::bool_error!("Unsupported image format: {} (supported: png, jpeg)", name)),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+        }
+    }
+
+    /// `gst_video::convert_sample` に渡す caps 。 registry から pngenc/jpegenc が選ばれる
+    fn caps(&self) -> gst::Caps {
+        match self {
+            Self::Png => gst::Caps::new_empty_simple("image/png"),
+            Self::Jpeg => gst::Caps::new_empty_simple("image/jpeg"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameExportConfig {
+    pub path: PathBuf,
+    /// 書き出す先のディレクトリ。なければ作る
+    pub output_dir: PathBuf,
+    pub selection: FrameSelection,
+    pub format: ImageFormat,
+    /// 何番目の映像の stream から書き出すか
+    pub stream_index: usize,
+    pub diagnose_caps: bool,
+}
+
+impl FrameExportConfig {
+    pub fn new(path: impl Into<PathBuf>, output_dir: impl Into<PathBuf>, selection: FrameSelection) -> Self {
+        Self { path: path.into(), output_dir: output_dir.into(), selection, format: ImageFormat::Png, stream_index: 0, diagnose_caps: false }
+    }
+}
+
+/// 書き出した一つのフレーム
+#[derive(Debug, Clone)]
+pub struct ExportedFrame {
+    pub pts: gst::ClockTime,
+    pub keyframe: bool,
+    pub path: PathBuf,
+}
+
+/// 入力の映像を RGB に decode して、選んだフレームを画像ファイルに書く
+///
+/// ファイル名には PTS を入れる (`frame_000012.345678.png`)
+/// `on_frame` は書き出すたびに streaming thread から呼ばれる
+pub fn export_frames<F>(config: &FrameExportConfig, mut on_frame: F) -> Result<()>
+where
+    F: FnMut(ExportedFrame) + Send + 'static,
+{
+    if config.selection == FrameSelection::Interval(gst::ClockTime::ZERO) {
+        return Err(Error::Gst(glib::bool_error!("Frame interval must be greater than zero")));
+    }
+    fs::create_dir_all(&config.output_dir)?;
+
+    // appsink の前で videoconvert に RGB にさせる
+    let raw_caps = gst_video::VideoCapsBuilder::new().format(gst_video::VideoFormat::Rgb).build();
+    let output_dir = config.output_dir.clone();
+    let format = config.format;
+    let mut sampler = FrameSampler::new(&config.selection);
+
+    let on_sample = move |sample: &gst::Sample| -> Result<ControlFlow<()>> {
+        let Some(buffer) = sample.buffer() else {
+            return Ok(ControlFlow::Continue(()));
+        };
+        let Some(pts) = buffer.pts() else {
+            log::warn!("Skip decoded frame without PTS");
+            return Ok(ControlFlow::Continue(()));
+        };
+        // decoder は keyframe から decode したフレームに DELTA_UNIT をつけない
+        let keyframe = !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT);
+
+        if sampler.accept(pts, buffer.duration(), keyframe) {
+            let image = gst_video::convert_sample(sample, &format.caps(), CONVERT_TIMEOUT)
+                .map_err(|err| glib::bool_error!("Failed to encode frame at {}: {}", pts, err))?;
+            let image_buffer = image.buffer().ok_or_else(|| glib::bool_error!("Encoded frame at {} has no buffer", pts))?;
+            let map = image_buffer.map_readable().map_err(|err| glib::bool_error!("Failed to map encoded frame at {}: {}", pts, err))?;
+
+            let path = output_dir.join(file_name(pts, format));
+            fs::write(&path, map.as_slice())?;
+            log::debug!("Wrote frame at {} to {}", pts, path.display());
+            on_frame(ExportedFrame { pts, keyframe, path });
+        }
+
+        Ok(if sampler.is_done() { ControlFlow::Break(()) } else { ControlFlow::Continue(()) })
+    };
+
+    extract::decode_to_appsink(&config.path, StreamKind::Video, config.stream_index, &raw_caps, config.diagnose_caps, on_sample)?;
+
+    Ok(())
+}
+
+/// 秒の整数部と小数部 (マイクロ秒) を分けて書くと、ファイル名の順番が PTS の順番になる
+fn file_name(pts: gst::ClockTime, format: ImageFormat) -> String {
+    format!("frame_{:06}.{:06}.{}", pts.seconds(), pts.useconds() % 1_000_000, format.extension())
+}
+
+/// decode されたフレームを PTS の順に受け取って、書き出すかを決める
+#[derive(Debug)]
+enum FrameSampler {
+    Interval { interval: gst::ClockTime, next: gst::ClockTime },
+    /// まだ書いていない時刻を早い順に
+    Timestamps(VecDeque<gst::ClockTime>),
+    Keyframes,
+}
+
+impl FrameSampler {
+    fn new(selection: &FrameSelection) -> Self {
+        match selection {
+            FrameSelection::Interval(interval) => Self::Interval { interval: *interval, next: gst::ClockTime::ZERO },
+            FrameSelection::Timestamps(timestamps) => {
+                let mut timestamps = timestamps.clone();
+                timestamps.sort();
+                timestamps.dedup();
+                Self::Timestamps(timestamps.into())
+            },
+            FrameSelection::Keyframes => Self::Keyframes,
+        }
+    }
+
+    /// 目標の時刻がこのフレームの表示中 (pts から pts + duration の前まで) か、もう過ぎていれば書き出す
+    ///
+    /// 一つのフレームの間に目標の時刻が複数あっても、書き出すのは一回だけ
+    fn accept(&mut self, pts: gst::ClockTime, duration: Option<gst::ClockTime>, keyframe: bool) -> bool {
+        let frame_end = pts + duration.unwrap_or(gst::ClockTime::ZERO);
+        let reached = |target: gst::ClockTime| target <= pts || target < frame_end;
+
+        match self {
+            Self::Interval { interval, next } => {
+                if !reached(*next) {
+                    return false;
+                }
+                while reached(*next) {
+                    *next += *interval;
+                }
+                true
+            },
+            Self::Timestamps(timestamps) => {
+                let mut accepted = false;
+                while timestamps.front().map_or(false, |target| reached(*target)) {
+                    timestamps.pop_front();
+                    accepted = true;
+                }
+                accepted
+            },
+            Self::Keyframes => keyframe,
+        }
+    }
+
+    /// 書き出す時刻が残っていなければ、残りを decode しなくてよい
+    fn is_done(&self) -> bool {
+        matches!(self, Self::Timestamps(timestamps) if timestamps.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> gst::ClockTime {
+        gst::ClockTime::from_mseconds(ms)
+    }
+
+    /// 25fps で start から count 枚のフレームを渡して、書き出した PTS (ms) を返す
+    fn accepted(sampler: &mut FrameSampler, start: u64, count: u64, duration: Option<gst::ClockTime>) -> Vec<u64> {
+        (0..count)
+            .map(|index| start + index * 40)
+            .filter(|pts| sampler.accept(ms(*pts), duration, false))
+            .collect()
+    }
+
+    #[test]
+    fn interval_picks_frame_shown_at_each_target() {
+        let mut sampler = FrameSampler::new(&FrameSelection::Interval(ms(100)));
+        assert_eq!(accepted(&mut sampler, 0, 7, Some(ms(40))), [0, 80, 200]);
+    }
+
+    #[test]
+    fn timestamps_between_frames_pick_frame_shown_at_target() {
+        let mut sampler = FrameSampler::new(&FrameSelection::Timestamps(vec![ms(130), ms(50)]));
+        assert_eq!(accepted(&mut sampler, 0, 5, Some(ms(40))), [40, 120]);
+        assert!(sampler.is_done());
+    }
+
+    #[test]
+    fn timestamps_in_one_frame_write_it_once() {
+        let mut sampler = FrameSampler::new(&FrameSelection::Timestamps(vec![ms(10), ms(20), ms(30)]));
+        assert_eq!(accepted(&mut sampler, 0, 3, Some(ms(40))), [0]);
+        assert!(sampler.is_done());
+    }
+
+    #[test]
+    fn timestamps_without_duration_pick_next_frame() {
+        let mut sampler = FrameSampler::new(&FrameSelection::Timestamps(vec![ms(50)]));
+        assert_eq!(accepted(&mut sampler, 0, 3, None), [80]);
+    }
+
+    #[test]
+    fn stream_not_starting_at_zero() {
+        // 先頭より前の目標はまとめて最初のフレームで書く
+        let mut sampler = FrameSampler::new(&FrameSelection::Interval(ms(100)));
+        assert_eq!(accepted(&mut sampler, 1000, 4, Some(ms(40))), [1000, 1080]);
+
+        let mut sampler = FrameSampler::new(&FrameSelection::Timestamps(vec![ms(500), ms(1050)]));
+        assert_eq!(accepted(&mut sampler, 1000, 3, Some(ms(40))), [1000, 1040]);
+        assert!(sampler.is_done());
+    }
+
+    #[test]
+    fn keyframes_pick_only_keyframes() {
+        let mut sampler = FrameSampler::new(&FrameSelection::Keyframes);
+        assert!(sampler.accept(ms(0), None, true));
+        assert!(!sampler.accept(ms(40), None, false));
+        assert!(!sampler.is_done());
+    }
+}
@@ -0,0 +1,308 @@
+use std::{fs::File, io::{BufReader, Cursor, Read, Seek, SeekFrom}, path::PathBuf, str::FromStr};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use h264_reader::nal::{self, Nal, RefNal};
+
+use crate::error::{Error, Result};
+
+/// box の中身を dump するときに読む最大の byte 数のデフォルト
+pub const DEFAULT_MAX_DUMP_BYTES: u64 = 128;
+
+#[derive(Debug, Clone)]
+pub struct IsobmffConfig {
+    pub path: PathBuf,
+    /// box の header と body をそれぞれこの byte 数まで読む
+    pub max_dump_bytes: u64,
+}
+
+impl IsobmffConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), max_dump_bytes: DEFAULT_MAX_DUMP_BYTES }
+    }
+}
+
+/// ファイルの中の一つの box
+#[derive(Debug, Clone)]
+pub struct BoxDump {
+    /// moov > trak > ... の深さ
+    pub depth: usize,
+    pub header: mp4::BoxHeader,
+    pub offset: u64,
+    pub header_bytes: Vec<u8>,
+    /// 子 box を持つ box (moov, trak など) は None
+    pub body_bytes: Option<Vec<u8>>,
+    pub body_size: u64,
+    /// max_dump_bytes で切ったか
+    pub truncated: bool,
+}
+
+/// 映像トラックの一つのサンプル
+#[derive(Debug, Clone)]
+pub struct SampleInfo {
+    pub index: usize,
+    pub chunk_index: usize,
+    pub offset: u64,
+    pub size: u32,
+    /// サンプルの最初の NAL の種類
+    pub nal_unit_type: Option<nal::UnitType>,
+}
+
+/// 先頭から順番に box を読む。 moov, trak, mdia, minf, stbl は中の box も読む
+pub fn dump_boxes(config: &IsobmffConfig) -> Result<Vec<BoxDump>> {
+    let file = File::open(&config.path)?;
+    let size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut boxes = Vec::new();
+    read_boxes(&mut reader, size, 0, config.max_dump_bytes, &mut boxes)?;
+    Ok(boxes)
+}
+
+fn read_boxes<R: Read + Seek>(reader: &mut BufReader<R>, size: u64, depth: usize, max_dump_bytes: u64, boxes: &mut Vec<BoxDump>) -> Result<()> {
+    while reader.stream_position()? < size {
+        let header_start_pos = reader.stream_position()?;
+        let header = mp4::BoxHeader::read(reader)?;
+        let header_end_pos = reader.stream_position()?;
+
+        if header.size == 0 {
+            break;
+        };
+
+        let header_itself_size = header_end_pos - header_start_pos;
+        // 途中で切れたファイルや壊れた box は、 size が header より小さかったり親の box をはみ出したりする
+        let body_size = header.size.checked_sub(header_itself_size)
+            .ok_or_else(|| Error::Isobmff(format!("Box {:?} at {} has size {} smaller than its header", header.name, header_start_pos, header.size)))?;
+        if header_end_pos.checked_add(body_size).map_or(true, |box_end_pos| box_end_pos > size) {
+            return Err(Error::Isobmff(format!("Box {:?} at {} with size {} exceeds its parent ending at {}", header.name, header_start_pos, header.size, size)));
+        }
+
+        reader.seek(SeekFrom::Start(header_start_pos))?;
+        let (header_bytes, header_truncated) = read(reader, header_itself_size, max_dump_bytes)?;
+        reader.seek(SeekFrom::Start(header_end_pos))?;
+
+        match header.name {
+            mp4::BoxType::MoovBox | mp4::BoxType::TrakBox | mp4::BoxType::MdiaBox | mp4::BoxType::MinfBox | mp4::BoxType::StblBox => {
+                boxes.push(BoxDump { depth, header, offset: header_start_pos, header_bytes, body_bytes: None, body_size, truncated: header_truncated });
+                read_boxes(reader, header_end_pos + body_size, depth + 1, max_dump_bytes, boxes)?;
+            },
+            _ => {
+                let (body_bytes, body_truncated) = read(reader, body_size, max_dump_bytes)?;
+                boxes.push(BoxDump {
+                    depth,
+                    header,
+                    offset: header_start_pos,
+                    header_bytes,
+                    body_bytes: Some(body_bytes),
+                    body_size,
+                    truncated: header_truncated || body_truncated,
+                });
+            },
+        }
+        reader.seek(SeekFrom::Start(header_end_pos + body_size))?;
+    };
+
+    Ok(())
+}
+
+fn read<R: Read>(reader: &mut BufReader<R>, size: u64, max_size: u64) -> Result<(Vec<u8>, bool)> {
+    let (truncated, size) = if max_size < size { (true, max_size) } else { (false, size) };
+    let mut buf = vec![0u8; size as usize];
+    reader.read_exact(&mut buf)?;
+    Ok((buf, truncated))
+}
+
+/// h264 の映像トラックのサンプルを stsc/stsz/stco (co64) から辿る
+///
+/// 映像トラックが一つだけのファイルにしか対応しない。
+/// サンプルの中身は最初の NAL の header までしか読まないので、メモリはサンプルの数 × `SampleInfo` の大きさで済む
+pub fn video_samples(config: &IsobmffConfig) -> Result<Vec<SampleInfo>> {
+    let file = File::open(&config.path)?;
+    let size = file.metadata()?.len();
+    let mp4 = mp4::Mp4Reader::read_header(BufReader::new(file), size)?;
+
+    let vide = mp4::FourCC::from_str("vide").expect("vide must be a valid FourCC");
+    let video_traks = mp4.moov.traks.iter().filter(|t| t.mdia.hdlr.handler_type == vide).collect::<Vec<_>>();
+    let [video_trak] = video_traks.as_slice() else {
+        return Err(Error::Isobmff(format!("Expected exactly one video track, found {}", video_traks.len())));
+    };
+    let Some(avc1) = &video_trak.mdia.minf.stbl.stsd.avc1 else {
+        return Err(Error::Isobmff("Not a h264 codec".to_string()));
+    };
+    let nal_size_length: usize = (avc1.avcc.length_size_minus_one + 1).into();
+
+    let video_stbl = &video_trak.mdia.minf.stbl;
+    let chunk_offsets = match (&video_stbl.stco, &video_stbl.co64) {
+        (Some(stco), None) => stco.entries.iter().map(|&offset| offset as u64).collect::<Vec<_>>(),
+        (None, Some(co64)) => co64.entries.clone(),
+        _ => return Err(Error::Isobmff("Invalid chunk offset block".to_string())),
+    };
+    let chunk_runs = video_stbl.stsc.entries.iter().map(|entry| (entry.first_chunk, entry.samples_per_chunk)).collect::<Vec<_>>();
+    let mut samples = sample_locations(&chunk_runs, video_stbl.stsz.sample_size, &video_stbl.stsz.sample_sizes, &chunk_offsets)?;
+
+    let mut reader = BufReader::new(File::open(&config.path)?);
+    for sample in &mut samples {
+        // 壊れた stsz や stco でファイルの外を指していないかを先に見る
+        if sample.offset.checked_add(sample.size as u64).map_or(true, |sample_end| sample_end > size) {
+            return Err(Error::Isobmff(format!("Sample {} at {} with size {} exceeds the file size {}", sample.index, sample.offset, sample.size, size)));
+        }
+        // NAL の長さと NAL の header (1 byte) だけ読む
+        let mut head = vec![0u8; (nal_size_length + 1).min(sample.size as usize)];
+        reader.seek(SeekFrom::Start(sample.offset))?;
+        reader.read_exact(&mut head)?;
+        sample.nal_unit_type = first_nal_unit_type(&head, sample.size, nal_size_length)?;
+    }
+
+    Ok(samples)
+}
+
+/// stsc の (first_chunk, samples_per_chunk) と stsz と chunk の位置から、サンプルの位置と大きさを並べる
+///
+/// stsz の sample_size が 0 でなければ全部のサンプルがその大きさで、 0 なら sample_sizes に一つずつ入っている
+fn sample_locations(chunk_runs: &[(u32, u32)], sample_size: u32, sample_sizes: &[u32], chunk_offsets: &[u64]) -> Result<Vec<SampleInfo>> {
+    let mut samples = Vec::new();
+
+    // stsc の entry はその entry の first_chunk から次の entry の first_chunk の前までの chunk に入っているサンプルの数
+    let mut runs = chunk_runs.iter().peekable();
+    while let Some(&(first_chunk, samples_per_chunk)) = runs.next() {
+        let chunk_start_index = first_chunk.checked_sub(1)
+            .ok_or_else(|| Error::Isobmff("stsc first_chunk must start from 1".to_string()))? as usize;
+        let chunk_end_index = match runs.peek() {
+            Some(&&(next_first_chunk, _)) => (next_first_chunk.saturating_sub(1) as usize).min(chunk_offsets.len()),
+            None => chunk_offsets.len(),
+        };
+
+        for (chunk_index, &chunk_offset) in chunk_offsets.iter().enumerate().take(chunk_end_index).skip(chunk_start_index) {
+            let mut sample_offset = chunk_offset;
+            for _ in 0..samples_per_chunk {
+                let size = if 0 < sample_size {
+                    sample_size
+                } else {
+                    *sample_sizes.get(samples.len())
+                        .ok_or_else(|| Error::Isobmff(format!("stsz has no size for sample {}", samples.len())))?
+                };
+
+                samples.push(SampleInfo { index: samples.len(), chunk_index, offset: sample_offset, size, nal_unit_type: None });
+                sample_offset += size as u64;
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+/// サンプルは NAL の長さ (nal_size_length byte) と NAL の並び。 head はサンプルの先頭の何 byte か
+fn first_nal_unit_type(head: &[u8], sample_size: u32, nal_size_length: usize) -> Result<Option<nal::UnitType>> {
+    if head.len() < nal_size_length {
+        return Err(Error::Isobmff(format!("Sample size {} is smaller than the NAL length field {}", sample_size, nal_size_length)));
+    }
+    let mut cursor = Cursor::new(head);
+    let nal_size = cursor.read_uint::<BigEndian>(nal_size_length)? as usize;
+
+    let Some(nal_end) = nal_size_length.checked_add(nal_size).filter(|&nal_end| nal_end <= sample_size as usize) else {
+        return Err(Error::Isobmff(format!("NAL size {} exceeds the sample size {}", nal_size, sample_size)));
+    };
+    // RefNal は空の NAL を受け付けない
+    let nal_bytes = &head[nal_size_length..nal_end.min(head.len())];
+    if nal_bytes.is_empty() {
+        return Ok(None);
+    }
+    let nal = RefNal::new(nal_bytes, &[], true);
+
+    Ok(nal.header().ok().map(|header| header.nal_unit_type()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// size と type の header をつけた box
+    fn mp4_box(name: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = ((8 + body.len()) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(name);
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    fn dump(bytes: Vec<u8>, max_dump_bytes: u64) -> Result<Vec<BoxDump>> {
+        let size = bytes.len() as u64;
+        let mut boxes = Vec::new();
+        read_boxes(&mut BufReader::new(Cursor::new(bytes)), size, 0, max_dump_bytes, &mut boxes)?;
+        Ok(boxes)
+    }
+
+    #[test]
+    fn dumps_nested_boxes() {
+        let moov = mp4_box(b"moov", &mp4_box(b"udta", &[1, 2, 3]));
+        let bytes = [mp4_box(b"ftyp", b"isom\0\0\0\0"), moov].concat();
+        let boxes = dump(bytes, DEFAULT_MAX_DUMP_BYTES).unwrap();
+
+        let summary = boxes.iter().map(|box_dump| (box_dump.depth, box_dump.offset, box_dump.body_size)).collect::<Vec<_>>();
+        assert_eq!(summary, [(0, 0, 8), (0, 16, 11), (1, 24, 3)]);
+        assert!(boxes[1].body_bytes.is_none());
+        assert_eq!(boxes[2].body_bytes.as_deref(), Some(&[1u8, 2, 3][..]));
+    }
+
+    #[test]
+    fn truncates_dump_of_large_body() {
+        let boxes = dump(mp4_box(b"free", &[0; 200]), 128).unwrap();
+        assert_eq!(boxes[0].body_size, 200);
+        assert_eq!(boxes[0].body_bytes.as_ref().map(Vec::len), Some(128));
+        assert!(boxes[0].truncated);
+    }
+
+    #[test]
+    fn rejects_box_smaller_than_header() {
+        let mut bytes = mp4_box(b"free", &[0; 8]);
+        bytes[..4].copy_from_slice(&4u32.to_be_bytes());
+        assert!(matches!(dump(bytes, DEFAULT_MAX_DUMP_BYTES), Err(Error::Isobmff(_))));
+    }
+
+    #[test]
+    fn rejects_box_cut_off_at_end_of_file() {
+        let mut bytes = mp4_box(b"free", &[0; 8]);
+        bytes.truncate(12);
+        assert!(matches!(dump(bytes, DEFAULT_MAX_DUMP_BYTES), Err(Error::Isobmff(_))));
+    }
+
+    #[test]
+    fn rejects_child_box_exceeding_parent() {
+        let mut child = mp4_box(b"trak", &[0; 8]);
+        child[..4].copy_from_slice(&32u32.to_be_bytes());
+        let bytes = [mp4_box(b"moov", &child), mp4_box(b"free", &[0; 16])].concat();
+        assert!(matches!(dump(bytes, DEFAULT_MAX_DUMP_BYTES), Err(Error::Isobmff(_))));
+    }
+
+    #[test]
+    fn walks_multi_entry_stsc() {
+        // chunk 1-2 は 2 サンプルずつ、 chunk 3 は 1 サンプル
+        let sizes = [10, 20, 30, 40, 50];
+        let samples = sample_locations(&[(1, 2), (3, 1)], 0, &sizes, &[100, 200, 300]).unwrap();
+
+        let summary = samples.iter().map(|sample| (sample.index, sample.chunk_index, sample.offset, sample.size)).collect::<Vec<_>>();
+        assert_eq!(summary, [(0, 0, 100, 10), (1, 0, 110, 20), (2, 1, 200, 30), (3, 1, 230, 40), (4, 2, 300, 50)]);
+    }
+
+    #[test]
+    fn walks_stsc_with_fixed_sample_size() {
+        let samples = sample_locations(&[(1, 3), (2, 1)], 8, &[], &[0, 100]).unwrap();
+        let offsets = samples.iter().map(|sample| sample.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, [0, 8, 16, 100]);
+    }
+
+    #[test]
+    fn rejects_broken_sample_table() {
+        assert!(sample_locations(&[(0, 1)], 8, &[], &[0]).is_err());
+        // stsz のサンプルの数が stsc より少ない
+        assert!(sample_locations(&[(1, 2)], 0, &[10], &[0]).is_err());
+    }
+
+    #[test]
+    fn reads_first_nal_unit_type_from_head() {
+        // 4 byte の長さ 5 と IDR の header (0x65)
+        let head = [0, 0, 0, 5, 0x65];
+        assert_eq!(first_nal_unit_type(&head, 9, 4).unwrap(), Some(nal::UnitType::SliceLayerWithoutPartitioningIdr));
+        assert!(first_nal_unit_type(&head, 8, 4).is_err());
+        assert!(first_nal_unit_type(&head[..2], 2, 4).is_err());
+        assert_eq!(first_nal_unit_type(&[0, 0, 0, 0], 4, 4).unwrap(), None);
+    }
+}
@@ -0,0 +1,540 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use gstreamer as gst;
+use gst::glib;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    container::{self, Mp4Layout, OutputFormat},
+    encoder::{EncoderSpec, Encoding},
+    error::Error,
+    probe::{self, StreamKind},
+    profile::{self, Profile},
+    segment,
+    selection::{StreamMap, StreamSelection},
+    time,
+    transcode::{Input, Rendition, TranscodeConfig},
+};
+
+/// transcode の内容をまとめたもの。 TOML か JSON のファイルに書いて `--job` で渡す
+///
+/// ```toml
+/// inputs = ["opening.mp4", "main.mp4"]
+/// two_pass = true
+///
+/// [selection]
+/// audio_language = "jpn"
+///
+/// [trim]
+/// end = "10:00"
+///
+/// [container]
+/// faststart = true
+///
+/// [output]
+/// path = "out.mp4"
+/// profile = "web-h264-aac"
+/// video_params = ["bitrate=3000"]
+///
+/// [[renditions]]
+/// path = "out-480p.mp4"
+/// profile = "web-h264-480p"
+/// ```
+///
+/// 時間は `--start` などと同じ書き方で、 argv の option と同じ意味を持つ
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// 二つ目以降の入力は最初の入力の後ろに順番に繋げる
+    pub inputs: Vec<PathBuf>,
+    #[serde(default)]
+    pub two_pass: bool,
+    #[serde(default)]
+    pub silent_audio: bool,
+    #[serde(default)]
+    pub repair_timestamps: bool,
+    #[serde(default)]
+    pub selection: JobSelection,
+    #[serde(default)]
+    pub trim: JobTrim,
+    #[serde(default)]
+    pub container: JobContainer,
+    pub output: JobOutput,
+    /// 同じ decode から書く別の出力 (bitrate ladder)
+    #[serde(default)]
+    pub renditions: Vec<JobOutput>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobSelection {
+    /// `--map` と同じ `<input>[:<v|a|s>[:<index>]]`
+    #[serde(default)]
+    pub maps: Vec<String>,
+    pub audio_language: Option<String>,
+    pub subtitle_language: Option<String>,
+    /// `--copy` と同じ `<input>[:<v|a>[:<index>]]`
+    #[serde(default)]
+    pub copy: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobTrim {
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobContainer {
+    #[serde(default)]
+    pub faststart: bool,
+    #[serde(default)]
+    pub fragmented: bool,
+    pub fragment_duration: Option<String>,
+    pub segment_duration: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobOutput {
+    pub path: PathBuf,
+    /// なければ path の拡張子から決める
+    pub format: Option<String>,
+    /// encoder を指定しないときに使う
+    pub profile: Option<String>,
+    /// `x264enc bitrate=2000` のような encoder の指定。空白を含む値は `"` で囲む
+    pub video_encoder: Option<String>,
+    pub audio_encoder: Option<String>,
+    /// profile や encoder のプロパティを上書きする `key=value`
+    #[serde(default)]
+    pub video_params: Vec<String>,
+    #[serde(default)]
+    pub audio_params: Vec<String>,
+    #[serde(default)]
+    pub filters: JobFilters,
+    #[serde(default)]
+    pub codec: JobCodec,
+}
+
+/// encoder の前で変換する解像度や sample rate (profile の指定を上書きする)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobFilters {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// `30` や `30000/1001`
+    pub framerate: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+}
+
+/// encoder が出す映像の profile と level (profile の指定を上書きする)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobCodec {
+    pub profile: Option<String>,
+    pub level: Option<String>,
+}
+
+/// 検証して、 pipeline を作れるようにした job
+#[derive(Debug, Clone)]
+pub struct ResolvedJob {
+    pub config: TranscodeConfig,
+    pub two_pass: bool,
+}
+
+impl Job {
+    /// 拡張子が .json なら JSON 、それ以外は TOML として読む
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| Error::InvalidJob(glib::bool_error!("Failed to read job file {}: {}", path.display(), err)))?;
+
+        let job = if path.extension().map_or(false, |extension| extension.eq_ignore_ascii_case("json")) {
+            serde_json::from_str(&contents).map_err(|err| err.to_string())
+        } else {
+            toml::from_str(&contents).map_err(|err| err.to_string())
+        };
+        job.map_err(|err| Error::InvalidJob(glib::bool_error!("Invalid job file {}: {}", path.display(), err)))
+    }
+
+    pub fn to_toml(&self) -> Result<String, Error> {
+        toml::to_string_pretty(self).map_err(|err| Error::InvalidJob(glib::bool_error!("Failed to write job: {}", err)))
+    }
+
+    /// encoder の名前やプロパティ、出力の container との組み合わせなどを pipeline を動かす前に全部確認する
+    ///
+    /// 入力は probe して demuxer を決めるので、 gst::init した後に呼ぶこと
+    pub fn resolve(&self) -> Result<ResolvedJob, Error> {
+        self.resolve_config().map_err(Error::InvalidJob)
+    }
+
+    fn resolve_config(&self) -> Result<ResolvedJob, glib::BoolError> {
+        if self.inputs.is_empty() {
+            return Err(glib::bool_error!("Job has no inputs"));
+        }
+
+        let start = self.trim.start.as_deref().map(time::parse_clock_time).transpose()?;
+        let end = self.trim.end.as_deref().map(time::parse_clock_time).transpose()?;
+        if let (Some(start), Some(end)) = (start, end) {
+            if end <= start {
+                return Err(glib::bool_error!("Trim end ({}) must be after start ({})", end, start));
+            }
+        }
+
+        let mp4_layout = self.container.mp4_layout()?;
+
+        let mut selection = StreamSelection::default();
+        for map in &self.selection.maps {
+            selection.maps.push(StreamMap::parse(map)?);
+        }
+        selection.audio_language = self.selection.audio_language.clone();
+        selection.subtitle_language = self.selection.subtitle_language.clone();
+        let copy_streams = self.selection.copy.iter().map(|map| StreamMap::parse(map)).collect::<Result<Vec<_>, _>>()?;
+        // 字幕は指定しなくてもそのまま mux するので、 copy できるのは映像と音声だけ
+        if let Some(map) = copy_streams.iter().find(|map| map.kind == Some(StreamKind::Subtitle)) {
+            return Err(glib::bool_error!("Invalid stream copy `{}`, only video and audio streams can be copied (<input>[:<v|a>[:<index>]])", map));
+        }
+
+        let (output_format, video_encoding, audio_encoding) = self.output.resolve(mp4_layout)?;
+
+        let segment_duration = self.container.segment_duration.as_deref().map(time::parse_clock_time).transpose()?;
+        if segment_duration.is_some() && !output_format.segmented {
+            return Err(glib::bool_error!("Segment duration is only supported for segmented formats (hls, hls-fmp4, dash)"));
+        }
+        let segment_duration = segment_duration.unwrap_or(segment::DEFAULT_SEGMENT_DURATION);
+        segment::check_segment_duration(segment_duration)?;
+
+        let mut ladder = Vec::new();
+        for rendition in &self.renditions {
+            let (output_format, video_encoding, audio_encoding) = rendition.resolve(mp4_layout)?;
+            ladder.push(Rendition { output_path: rendition.path.clone(), output_format, video_encoding, audio_encoding });
+        }
+
+        let mut inputs = Vec::new();
+        for path in &self.inputs {
+            let media_info = probe::discover(path)
+                .map_err(|err| glib::bool_error!("Failed to probe input {}: {}", path.display(), err))?;
+            let demuxer_name = probe::find_demuxer(&media_info);
+            inputs.push(Input { path: path.clone(), demuxer_name, media_info });
+        }
+
+        // 入力にある stream の encoder だけ、出力の container が受け付けるか確認する
+        let has_stream = |kind: StreamKind| inputs.iter().any(|input| input.media_info.streams.iter().any(|stream| stream.kind == kind));
+        let outputs = [(output_format, &video_encoding, &audio_encoding)].into_iter()
+            .chain(ladder.iter().map(|rendition| (rendition.output_format, &rendition.video_encoding, &rendition.audio_encoding)));
+        for (output_format, video_encoding, audio_encoding) in outputs {
+            for (kind, encoding) in [(StreamKind::Video, video_encoding), (StreamKind::Audio, audio_encoding)] {
+                if has_stream(kind) && output_format.accepts_kind(kind) {
+                    output_format.check_encoder(&encoding.encoder.factory_name)?;
+                }
+            }
+        }
+
+        // hls-fmp4 のように一つしか書けない出力に、 stream が複数行かないか確認する。
+        // 複数の入力は concat するので、映像と音声を一つずつ使う
+        let outputs = [output_format].into_iter().chain(ladder.iter().map(|rendition| rendition.output_format));
+        for output_format in outputs.filter(|output_format| output_format.single_stream) {
+            let mut selected_kinds = Vec::new();
+            for stream in &inputs[0].media_info.streams {
+                let kind = stream.kind;
+                if kind != StreamKind::Subtitle
+                    && output_format.accepts_kind(kind)
+                    && selection.is_selected(0, kind, stream.index, Some(stream))
+                    && (inputs.len() == 1 || !selected_kinds.contains(&kind))
+                {
+                    selected_kinds.push(kind);
+                }
+            }
+            if self.silent_audio && !selected_kinds.contains(&StreamKind::Audio) {
+                selected_kinds.push(StreamKind::Audio);
+            }
+            if selected_kinds.len() > 1 {
+                return Err(glib::bool_error!(
+                    "{} can write only one stream but {} are selected, select one with --map (e.g. --map 0:v)",
+                    output_format.name, selected_kinds.len()
+                ));
+            }
+        }
+
+        if self.two_pass && !has_stream(StreamKind::Video) {
+            return Err(glib::bool_error!("Two-pass encoding requires a video stream in the input"));
+        }
+
+        let config = TranscodeConfig {
+            inputs,
+            output_path: self.output.path.clone(),
+            output_format,
+            mp4_layout,
+            segment_duration,
+            video_encoding,
+            audio_encoding,
+            selection,
+            copy_streams,
+            add_silent_audio: self.silent_audio,
+            start,
+            end,
+            ladder,
+            repair_timestamps: self.repair_timestamps,
+            stats_pass: false,
+        };
+
+        Ok(ResolvedJob { config, two_pass: self.two_pass })
+    }
+}
+
+impl JobContainer {
+    fn mp4_layout(&self) -> Result<Mp4Layout, glib::BoolError> {
+        let fragment_duration = self.fragment_duration.as_deref().map(time::parse_clock_time).transpose()?;
+        match (self.faststart, self.fragmented, fragment_duration) {
+            (false, false, None) => Ok(Mp4Layout::Default),
+            (true, false, None) => Ok(Mp4Layout::FastStart),
+            (false, true, fragment_duration) => Ok(Mp4Layout::Fragmented {
+                fragment_duration: fragment_duration.unwrap_or(container::DEFAULT_FRAGMENT_DURATION),
+            }),
+            (true, true, _) => Err(glib::bool_error!("Faststart and fragmented cannot be used together")),
+            (_, false, Some(_)) => Err(glib::bool_error!("Fragment duration requires fragmented")),
+        }
+    }
+}
+
+impl JobOutput {
+    /// profile か encoder から encoding を作って、 params, filters, codec で上書きする
+    fn resolve(&self, mp4_layout: Mp4Layout) -> Result<(OutputFormat, Encoding, Encoding), glib::BoolError> {
+        let output_format = match &self.format {
+            Some(format) => OutputFormat::find(format)?,
+            None => OutputFormat::from_path(&self.path)?,
+        };
+        output_format.check_mp4_layout(mp4_layout)?;
+
+        let (mut video_encoding, mut audio_encoding) = match (&self.profile, &self.video_encoder, &self.audio_encoder) {
+            (None, Some(video_encoder), Some(audio_encoder)) => {
+                let video_encoder = EncoderSpec::parse(video_encoder)
+                    .map_err(|err| glib::bool_error!("Invalid video encoder: {}", err))?;
+                let audio_encoder = EncoderSpec::parse(audio_encoder)
+                    .map_err(|err| glib::bool_error!("Invalid audio encoder: {}", err))?;
+                (Encoding::from(video_encoder), Encoding::from(audio_encoder))
+            },
+            (Some(profile_name), None, None) => {
+                let profile = Profile::find(profile_name)?;
+                let encodings = profile.video.encoding().and_then(|video| Ok((video, profile.audio.encoding()?)));
+                encodings.map_err(|err| glib::bool_error!("Invalid profile {}: {}", profile.name, err))?
+            },
+            _ => return Err(glib::bool_error!("Specify either both encoders or a profile for {}", self.path.display())),
+        };
+
+        for (encoding, params) in [(&mut video_encoding, &self.video_params), (&mut audio_encoding, &self.audio_params)] {
+            for param in params {
+                encoding.encoder.set_property_from_arg(param)?;
+            }
+            encoding.encoder = encoding.encoder.resolve()?;
+        }
+
+        video_encoding.raw_caps = self.filters.video_caps(video_encoding.raw_caps.take())?;
+        audio_encoding.raw_caps = self.filters.audio_caps(audio_encoding.raw_caps.take());
+        if self.codec.profile.is_some() || self.codec.level.is_some() {
+            video_encoding.encoded_caps = profile::encoded_caps(&video_encoding.encoder, self.codec.profile.as_deref(), self.codec.level.as_deref())?;
+        }
+
+        for encoding in [&video_encoding, &audio_encoding] {
+            encoding.validate().map_err(|err| glib::bool_error!("Invalid encoder settings for {}: {}", self.path.display(), err))?;
+        }
+
+        Ok((output_format, video_encoding, audio_encoding))
+    }
+
+    /// profile を展開して encoder と filters, codec で書いた出力
+    fn from_resolved(path: &Path, output_format: OutputFormat, video_encoding: &Encoding, audio_encoding: &Encoding) -> Self {
+        let raw_video = video_encoding.raw_caps.as_ref().and_then(|caps| caps.structure(0));
+        let raw_audio = audio_encoding.raw_caps.as_ref().and_then(|caps| caps.structure(0));
+        let encoded_video = video_encoding.encoded_caps.as_ref().and_then(|caps| caps.structure(0));
+
+        Self {
+            path: path.to_path_buf(),
+            format: Some(output_format.name.to_string()),
+            profile: None,
+            video_encoder: Some(video_encoding.encoder.to_string()),
+            audio_encoder: Some(audio_encoding.encoder.to_string()),
+            video_params: Vec::new(),
+            audio_params: Vec::new(),
+            filters: JobFilters {
+                width: raw_video.and_then(|s| s.get::<i32>("width").ok()).map(|width| width as u32),
+                height: raw_video.and_then(|s| s.get::<i32>("height").ok()).map(|height| height as u32),
+                framerate: raw_video.and_then(|s| s.get::<gst::Fraction>("framerate").ok())
+                    .map(|framerate| format!("{}/{}", framerate.numer(), framerate.denom())),
+                sample_rate: raw_audio.and_then(|s| s.get::<i32>("rate").ok()).map(|rate| rate as u32),
+                channels: raw_audio.and_then(|s| s.get::<i32>("channels").ok()).map(|channels| channels as u32),
+            },
+            codec: JobCodec {
+                profile: encoded_video.and_then(|s| s.get::<String>("profile").ok()),
+                level: encoded_video.and_then(|s| s.get::<String>("level").ok()),
+            },
+        }
+    }
+}
+
+impl JobFilters {
+    fn video_caps(&self, raw_caps: Option<gst::Caps>) -> Result<Option<gst::Caps>, glib::BoolError> {
+        if self.width.is_none() && self.height.is_none() && self.framerate.is_none() {
+            return Ok(raw_caps);
+        }
+
+        let framerate = self.framerate.as_deref().map(parse_framerate).transpose()?;
+        let mut caps = raw_caps.unwrap_or_else(|| gst::Caps::new_empty_simple("video/x-raw"));
+        {
+            let structure = caps.make_mut().structure_mut(0).expect("raw caps must have a structure");
+            if let Some(width) = self.width {
+                structure.set("width", width as i32);
+            }
+            if let Some(height) = self.height {
+                structure.set("height", height as i32);
+            }
+            if let Some(framerate) = framerate {
+                structure.set("framerate", framerate);
+            }
+        }
+
+        Ok(Some(caps))
+    }
+
+    fn audio_caps(&self, raw_caps: Option<gst::Caps>) -> Option<gst::Caps> {
+        if self.sample_rate.is_none() && self.channels.is_none() {
+            return raw_caps;
+        }
+
+        let mut caps = raw_caps.unwrap_or_else(|| gst::Caps::new_empty_simple("audio/x-raw"));
+        {
+            let structure = caps.make_mut().structure_mut(0).expect("raw caps must have a structure");
+            if let Some(sample_rate) = self.sample_rate {
+                structure.set("rate", sample_rate as i32);
+            }
+            if let Some(channels) = self.channels {
+                structure.set("channels", channels as i32);
+            }
+        }
+
+        Some(caps)
+    }
+}
+
+impl ResolvedJob {
+    /// profile や拡張子から決めたものを全部書き出した job
+    ///
+    /// そのまま job ファイルとして使うと同じ transcode になる
+    pub fn to_job(&self) -> Job {
+        let config = &self.config;
+        let seconds = |time: gst::ClockTime| format!("{}", time.nseconds() as f64 / 1_000_000_000.0);
+        let segmented = config.output_format.segmented || config.ladder.iter().any(|rendition| rendition.output_format.segmented);
+
+        Job {
+            inputs: config.inputs.iter().map(|input| input.path.clone()).collect(),
+            two_pass: self.two_pass,
+            silent_audio: config.add_silent_audio,
+            repair_timestamps: config.repair_timestamps,
+            selection: JobSelection {
+                maps: config.selection.maps.iter().map(ToString::to_string).collect(),
+                audio_language: config.selection.audio_language.clone(),
+                subtitle_language: config.selection.subtitle_language.clone(),
+                copy: config.copy_streams.iter().map(ToString::to_string).collect(),
+            },
+            trim: JobTrim {
+                start: config.start.map(seconds),
+                end: config.end.map(seconds),
+            },
+            container: JobContainer {
+                faststart: config.mp4_layout == Mp4Layout::FastStart,
+                fragmented: matches!(config.mp4_layout, Mp4Layout::Fragmented { .. }),
+                fragment_duration: match config.mp4_layout {
+                    Mp4Layout::Fragmented { fragment_duration } => Some(seconds(fragment_duration)),
+                    _ => None,
+                },
+                segment_duration: segmented.then(|| seconds(config.segment_duration)),
+            },
+            output: JobOutput::from_resolved(&config.output_path, config.output_format, &config.video_encoding, &config.audio_encoding),
+            renditions: config.ladder.iter()
+                .map(|rendition| JobOutput::from_resolved(&rendition.output_path, rendition.output_format, &rendition.video_encoding, &rendition.audio_encoding))
+                .collect(),
+        }
+    }
+}
+
+/// `30` や `30000/1001`
+fn parse_framerate(s: &str) -> Result<gst::Fraction, glib::BoolError> {
+    let invalid = || glib::bool_error!("Invalid framerate `{}`, expected <numerator>[/<denominator>]", s);
+
+    let (numer, denom) = s.split_once('/').unwrap_or((s, "1"));
+    let numer = numer.trim().parse::<i32>().map_err(|_| invalid())?;
+    let denom = denom.trim().parse::<i32>().map_err(|_| invalid())?;
+    if numer <= 0 || denom <= 0 {
+        return Err(invalid());
+    }
+
+    Ok(gst::Fraction::new(numer, denom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::MediaInfo;
+
+    /// 入力と出力と encoder だけの job
+    fn resolved_job() -> ResolvedJob {
+        gst::init().unwrap();
+
+        let mut video_encoder = EncoderSpec::parse("x264enc").unwrap();
+        // split_whitespace で読むと壊れていた、空白を含む値
+        video_encoder.set_property("option-string", "ref=2 bframes=0");
+
+        let config = TranscodeConfig {
+            inputs: vec![Input {
+                path: "input.mp4".into(),
+                demuxer_name: "qtdemux".to_string(),
+                media_info: MediaInfo { container_caps: None, duration: None, streams: Vec::new() },
+            }],
+            output_path: "out.mp4".into(),
+            output_format: OutputFormat::find("mp4").unwrap(),
+            mp4_layout: Mp4Layout::Default,
+            segment_duration: segment::DEFAULT_SEGMENT_DURATION,
+            video_encoding: video_encoder.into(),
+            audio_encoding: EncoderSpec::parse("avenc_aac").unwrap().into(),
+            selection: StreamSelection { maps: vec![StreamMap::parse("0:a:1").unwrap()], ..Default::default() },
+            copy_streams: Vec::new(),
+            add_silent_audio: false,
+            start: Some(gst::ClockTime::from_mseconds(1500)),
+            end: None,
+            ladder: Vec::new(),
+            repair_timestamps: false,
+            stats_pass: false,
+        };
+
+        ResolvedJob { config, two_pass: false }
+    }
+
+    #[test]
+    fn job_round_trips_through_toml() {
+        let resolved = resolved_job();
+        let job = resolved.to_job();
+        let parsed = toml::from_str::<Job>(&job.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed, job);
+
+        // 書き出した文字列を resolve と同じように読むと、元の値に戻る
+        let config = &resolved.config;
+        let maps = parsed.selection.maps.iter().map(|map| StreamMap::parse(map).unwrap()).collect::<Vec<_>>();
+        assert_eq!(maps, config.selection.maps);
+        assert_eq!(parsed.trim.start.as_deref().map(|start| time::parse_clock_time(start).unwrap()), config.start);
+        assert_eq!(parsed.container.mp4_layout().unwrap(), config.mp4_layout);
+
+        let encoder = EncoderSpec::parse(parsed.output.video_encoder.as_deref().unwrap()).unwrap();
+        assert_eq!(encoder.factory_name, config.video_encoding.encoder.factory_name);
+        assert_eq!(encoder.properties, config.video_encoding.encoder.properties);
+    }
+
+    #[test]
+    fn parses_framerate() {
+        assert_eq!(parse_framerate("30").unwrap(), gst::Fraction::new(30, 1));
+        assert_eq!(parse_framerate("30000/1001").unwrap(), gst::Fraction::new(30000, 1001));
+        assert!(parse_framerate("0").is_err());
+        assert!(parse_framerate("30/").is_err());
+    }
+}
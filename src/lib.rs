@@ -0,0 +1,31 @@
+pub mod analyze;
+pub mod audio;
+pub mod concat;
+pub mod container;
+pub mod encoder;
+pub mod error;
+pub(crate) mod extract;
+pub mod frames;
+pub mod isobmff;
+pub mod job;
+pub mod nal;
+pub mod negotiation;
+pub(crate) mod npy;
+pub mod probe;
+pub mod progress;
+pub mod profile;
+pub mod segment;
+pub mod selection;
+pub mod tensors;
+pub mod time;
+pub mod timestamp;
+pub mod transcode;
+pub mod transcoder;
+pub mod verify;
+
+pub use error::{Error, Result};
+
+/// library の関数を使う前に一度だけ呼ぶ
+pub fn init() -> Result<()> {
+    gstreamer::init().map_err(Error::Init)
+}
@@ -1,148 +1,490 @@
-use gstreamer::prelude::*;
-use std::{time::Duration, env, path::Path, process, thread, sync::{Arc, Mutex}};
+use std::{env, io, path::{Path, PathBuf}, process, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+
+use gstreamer as gst;
 use log;
 use env_logger;
 
+use learning_gstreamer::{analyze, audio, encoder, frames, job, probe, profile, progress, tensors, time, transcoder, verify, Error};
+
+/// pad ごとに表示する timestamp の修正の記録の数
+const MAX_PRINTED_CORRECTIONS: usize = 20;
+
 fn main() {
     env_logger::init();
 
     let args: Vec<String> = env::args().collect();
-    if args.len() != 5 {
-        eprintln!("Usage: {} <input video path> <output path> <video encoder> <audio encoder>", args[0]);
-        process::exit(1);
+
+    if args.get(1).map(String::as_str) == Some("list-encoders") {
+        if let Err(err) = learning_gstreamer::init() {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        print_encoders();
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("export-frames") {
+        export_frames(&args);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("export-audio") {
+        export_audio(&args);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("export-tensors") {
+        export_tensors(&args);
+        return;
     }
 
-    log::info!("Start init gstreamer");
-    gstreamer::init().unwrap();
+    let mut positional_args = Vec::new();
+    // argv で指定された job。 --job のときは空のまま
+    let mut argv_job = job::Job::default();
+    let mut append_paths = Vec::new();
+    let mut job_path = None;
+    let mut print_job = false;
+    let mut progress_format = None;
+    let mut diagnose_caps = false;
+    let mut no_verify = false;
+    let mut analyzer_names = Vec::new();
+    let mut analysis_output = None;
+    let mut args_iter = args.iter().skip(1);
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--job" => job_path = Some(option_value(&mut args_iter, arg)),
+            "--print-job" => print_job = true,
+            "--progress" => progress_format = Some(option_value(&mut args_iter, arg)),
+            "--diagnose-caps" => diagnose_caps = true,
+            "--no-verify" => no_verify = true,
+            "--analyze" => analyzer_names.extend(option_value(&mut args_iter, arg).split(',')),
+            "--analysis-output" => analysis_output = Some(PathBuf::from(option_value(&mut args_iter, arg))),
+            "--silent-audio" => argv_job.silent_audio = true,
+            "--two-pass" => argv_job.two_pass = true,
+            "--repair-timestamps" => argv_job.repair_timestamps = true,
+            "--faststart" => argv_job.container.faststart = true,
+            "--fragmented" => argv_job.container.fragmented = true,
+            "--segment-duration" => argv_job.container.segment_duration = Some(option_value(&mut args_iter, arg).to_string()),
+            "--fragment-duration" => argv_job.container.fragment_duration = Some(option_value(&mut args_iter, arg).to_string()),
+            "--format" => argv_job.output.format = Some(option_value(&mut args_iter, arg).to_string()),
+            "--profile" => argv_job.output.profile = Some(option_value(&mut args_iter, arg).to_string()),
+            "--video-param" => argv_job.output.video_params.push(option_value(&mut args_iter, arg).to_string()),
+            "--audio-param" => argv_job.output.audio_params.push(option_value(&mut args_iter, arg).to_string()),
+            "--start" => argv_job.trim.start = Some(option_value(&mut args_iter, arg).to_string()),
+            "--end" => argv_job.trim.end = Some(option_value(&mut args_iter, arg).to_string()),
+            "--rendition" => argv_job.renditions.push(parse_rendition(option_value(&mut args_iter, arg))),
+            "--append" => append_paths.push(option_value(&mut args_iter, arg)),
+            "--audio-lang" => argv_job.selection.audio_language = Some(option_value(&mut args_iter, arg).to_string()),
+            "--subtitle-lang" => argv_job.selection.subtitle_language = Some(option_value(&mut args_iter, arg).to_string()),
+            "--map" => argv_job.selection.maps.push(option_value(&mut args_iter, arg).to_string()),
+            "--copy" => argv_job.selection.copy.push(option_value(&mut args_iter, arg).to_string()),
+            flag if flag.starts_with("--") => {
+                eprintln!("Unknown option: {}", flag);
+                process::exit(1);
+            },
+            _ => positional_args.push(arg),
+        }
+    }
 
-    let input_path = &args[1];
-    let output_path = &args[2];
-    let video_encoder = &args[3];
-    let audio_encoder = &args[4];
+    let job = match job_path {
+        // job ファイルに書いたものと argv の指定が混ざると再現できなくなるので、一緒には使わない
+        Some(job_path) => {
+            if !positional_args.is_empty() || !append_paths.is_empty() || argv_job != job::Job::default() {
+                eprintln!("--job cannot be combined with inputs, outputs or transcode options");
+                process::exit(1);
+            }
+            job::Job::load(Path::new(job_path)).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            })
+        },
+        None => {
+            if !(positional_args.len() == 4 || (positional_args.len() == 2 && argv_job.output.profile.is_some())) {
+                let profile_names = profile::PROFILES.iter().map(|profile| profile.name).collect::<Vec<_>>();
+                eprintln!("Usage: {} <input video path> <output path or directory> (<video encoder> <audio encoder> | --profile <{}>) [--video-param <key=value>]... [--audio-param <key=value>]... [--two-pass] [--rendition <profile>=<output path>]... [--append <input path>]... [--start <time>] [--end <time>] [--format <format>] [--faststart | --fragmented [--fragment-duration <time>]] [--segment-duration <time>] [--progress <text|json>] [--silent-audio] [--repair-timestamps] [--diagnose-caps] [--no-verify] [--analyze <{}>[,...]]... [--analysis-output <path>] [--map <input>[:<v|a|s>[:<index>]]]... [--audio-lang <lang>] [--subtitle-lang <lang>] [--copy <input>[:<v|a>[:<index>]]]... [--print-job]", args[0], profile_names.join("|"), analyze::BUILTIN_ANALYZERS.join("|"));
+                eprintln!("       {} --job <job.toml|job.json> [--progress <text|json>] [--diagnose-caps] [--no-verify] [--analyze <{}>[,...]]... [--analysis-output <path>] [--print-job]", args[0], analyze::BUILTIN_ANALYZERS.join("|"));
+                eprintln!("       {} list-encoders", args[0]);
+                eprintln!("       {} export-frames <input video path> <output directory> (--every <time> | --at <time>[,<time>]... | --keyframes) [--image-format <png|jpeg>] [--stream <index>] [--diagnose-caps]", args[0]);
+                eprintln!("       {} export-audio <input video path> <output path or directory> [--format <wav|f32|npy>] [--rate <hz>] [--channels <count>] [--window <time>] [--stream <index>] [--diagnose-caps]", args[0]);
+                eprintln!("       {} export-tensors <input video path> <output directory> [--format <npy|npz>] [--color <rgb|bgr|gray>] [--width <px>] [--height <px>] [--stride <frames>] [--batch <frames> (required for npz)] [--stream <index>] [--diagnose-caps]", args[0]);
+                process::exit(1);
+            }
 
-    let pipeline_str = format!(
-        "filesrc location={} ! qtdemux name=demux \
-        demux.video_0 ! decodebin ! videoconvert name=vconv ! {} name=venc ! mux. \
-        demux.audio_0 ! decodebin ! audioconvert name=aconv ! {} name=aenc ! mux. \
-        {} name=mux ! filesink location={}",
-        input_path,
-        get_muxer_from_extension(&Path::new(output_path).extension().unwrap().to_string_lossy()), output_path,
-        video_encoder,
-        audio_encoder,
-    );
+            // --append で指定した入力は、最初の入力の後ろに順番に繋げる
+            argv_job.inputs = [positional_args[0].as_str()].into_iter().chain(append_paths).map(PathBuf::from).collect();
+            argv_job.output.path = positional_args[1].into();
+            if let [video_encoder, audio_encoder] = &positional_args[2..] {
+                argv_job.output.video_encoder = Some(video_encoder.to_string());
+                argv_job.output.audio_encoder = Some(audio_encoder.to_string());
+            }
+            argv_job
+        },
+    };
 
+    let progress_json = match progress_format {
+        Some("json") => true,
+        Some("text") | None => false,
+        Some(progress_format) => {
+            eprintln!("Unknown progress format: {} (available: text, json)", progress_format);
+            process::exit(1);
+        },
+    };
 
-    log::info!("Start parse launch pipeline: {:}", pipeline_str);
-    let pipeline = Arc::new(Mutex::new(gstreamer::parse_launch(&pipeline_str).unwrap()));
+    log::info!("Start init gstreamer");
+    if let Err(err) = learning_gstreamer::init() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 
-/*
-    {
-        let pipeline = pipeline.lock().unwrap().clone();
-        if let Some(dec_el) = pipeline.dynamic_cast::<gstreamer::Bin>().unwrap().by_name("fix") {
-            dec_el.connect("handoff", false, move |args| {
-                // let identity = args[0].get::<gstreamer::Element>().unwrap();
-                let buffer = args[1].get::<gstreamer::buffer::Buffer>().unwrap();
-                println!("BUFFER={:?}", buffer);
-
-                // DTSやPTSを取得して変更する
-                // let dts = buffer.dts();
-                let pts = buffer.pts();
-                let dts = buffer.dts();
-                println!("PTS={:?} DTS={:?}", pts, dts);
-
-                None
-            });
+    // encoder 名やプロパティ名、値の typo は pipeline を動かす前に弾く
+    let resolved = job.resolve().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    if print_job {
+        let toml = resolved.to_job().to_toml().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        print!("{}", toml);
+        return;
+    }
+
+    for (index, input) in resolved.config.inputs.iter().enumerate() {
+        println!("Input #{}: {}\n{}Demuxer: {}", index, input.path.display(), input.media_info, input.demuxer_name);
+    }
+
+    // sidecar はデフォルトで出力の隣に書く
+    let sidecar_path = analysis_output.unwrap_or_else(|| PathBuf::from(format!("{}.analysis.json", resolved.config.output_path.display())));
+    let mut analysis = analyze::Analysis::new(sidecar_path);
+    for analyzer_name in analyzer_names {
+        if let Err(err) = analysis.add_builtin(analyzer_name) {
+            eprintln!("{}", err);
+            process::exit(1);
         }
     }
 
+    let options = transcoder::TranscodeOptions {
+        progress: Some(progress_listener(progress_json)),
+        diagnose_caps,
+        verify: (!no_verify).then(verify::Tolerances::default),
+        analysis: (!analysis.is_empty()).then_some(analysis),
+        ..Default::default()
+    };
+    let transcoder = Arc::new(transcoder::Transcoder::new(resolved, options));
+
+    // 1 回目の Ctrl-C (SIGINT/SIGTERM) では EOS を送って出力ファイルを書き終えさせる。 2 回目はすぐに終了する
+    let interrupts = Arc::new(AtomicUsize::new(0));
     {
-        dec_el.connect_pad_added(|_, pad| {
-            let caps = pad.current_caps().unwrap();
-            let structures = caps.iter().collect::<Vec<_>>();
-            assert!(structures.len() == 1);
-            if pad.direction() == gstreamer::PadDirection::Src {
-                if structures[0].name().to_string().starts_with("video") {
-                    pad.add_probe(gstreamer::PadProbeType::BUFFER_LIST, |_, info| {
-                        if let Some(gstreamer::PadProbeData::BufferList(buffers)) = &info.data {
-                            for data in buffers.iter() {
-                                let pts = data.pts();
-                                let dts = data.dts();
-                                println!("BUFFERLIST EL PTS: {:?}, DTS: {:?}", pts, dts);
-                            }
-                        }
-
-                        gstreamer::PadProbeReturn::Ok
-                    });
-                    pad.add_probe(gstreamer::PadProbeType::BUFFER, |_, info| {
-                        if let Some(gstreamer::PadProbeData::Buffer(data)) = &mut info.data {
-                            let pts = data.pts();
-                            let dts = data.dts();
-                            if dts == None && pts < dts {
-                                data.get_mut().unwrap().set_dts(pts);
-                            }
-                            println!("PTS: {:?}, DTS: {:?}", pts, dts);
-                        }
-
-                        gstreamer::PadProbeReturn::Ok
-                    });
-                }
+        let transcoder = transcoder.clone();
+        let interrupts = interrupts.clone();
+        let result = ctrlc::set_handler(move || {
+            if interrupts.fetch_add(1, Ordering::SeqCst) > 0 {
+                eprintln!("Aborted");
+                process::exit(130);
+            }
+
+            eprintln!("Interrupted, finishing output (press Ctrl-C again to abort)");
+            if let Err(err) = transcoder.interrupt() {
+                eprintln!("{}", err);
+                process::exit(1);
             }
         });
+        if let Err(err) = result {
+            eprintln!("Failed to set signal handler: {}", err);
+            process::exit(1);
+        }
+    }
+
+    let report = match transcoder.run() {
+        Ok(report) => report,
+        Err(Error::Interrupted) => {
+            // 始まる前か、 two-pass の 1 pass 目 (統計が途中までしかない) で中断した
+            eprintln!("Interrupted, no output written");
+            process::exit(130);
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
     };
-*/
 
-    let pipeline_weak = Arc::downgrade(&pipeline);
+    for (pad_name, corrections) in &report.timestamp_corrections {
+        if corrections.is_empty() {
+            continue;
+        }
+        // 続けて直したフレームはまとめてあるが、壊れた入力だと記録が多いので先頭だけ出す
+        let frames = corrections.iter().map(|correction| correction.frames).sum::<u64>();
+        println!("Repaired timestamps of {} frames on {}", frames, pad_name);
+        for correction in corrections.iter().take(MAX_PRINTED_CORRECTIONS) {
+            println!("  {}", correction);
+        }
+        if corrections.len() > MAX_PRINTED_CORRECTIONS {
+            println!("  ... and {} more", corrections.len() - MAX_PRINTED_CORRECTIONS);
+        }
+    }
 
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_secs(1));
+    for analyzer_report in &report.analysis {
+        println!("Analyzed {} {} buffers with {} ({} dropped)", analyzer_report.analyzed, analyzer_report.kind, analyzer_report.name, analyzer_report.dropped);
+    }
 
-        if let Some(pipeline) = pipeline_weak.upgrade() {
-            let pipeline = pipeline.lock().unwrap();
-            if let Some(position) = pipeline.query_position::<gstreamer::ClockTime>() {
-                if let Some(duration) = pipeline.query_duration::<gstreamer::ClockTime>() {
-                    println!("Position: {} / {}", position.display(), duration.display());
-                }
+    // 出力が入力と合わなければ、変換自体は終わっていても失敗にする
+    for verify_report in &report.verification {
+        print!("{}", verify_report);
+    }
+    if !report.verified() {
+        eprintln!("Output verification failed");
+        process::exit(1);
+    }
+}
+
+/// `list-encoders` で registry にある encoder を表示する
+fn print_encoders() {
+    for kind in [probe::StreamKind::Video, probe::StreamKind::Audio] {
+        println!("{:?} encoders:", kind);
+        for encoder in encoder::list_encoders().into_iter().filter(|encoder| encoder.kind == kind) {
+            println!("  {} (rank {}): {}", encoder.name, encoder.rank, encoder.long_name);
+            for caps in &encoder.src_caps {
+                println!("    src: {}", caps);
             }
-        };
-    });
+        }
+    }
+}
+
+/// `export-frames` で映像のフレームを画像ファイルに書き出す
+fn export_frames(args: &[String]) {
+    let mut positional_args = Vec::new();
+    let mut selection = None;
+    let mut image_format = frames::ImageFormat::Png;
+    let mut stream_index = 0;
+    let mut diagnose_caps = false;
+    let mut args_iter = args.iter().skip(2);
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--every" => selection = Some(frames::FrameSelection::Interval(parse_time(option_value(&mut args_iter, arg)))),
+            "--at" => {
+                let timestamps = option_value(&mut args_iter, arg).split(',').map(parse_time).collect();
+                selection = Some(frames::FrameSelection::Timestamps(timestamps));
+            },
+            "--keyframes" => selection = Some(frames::FrameSelection::Keyframes),
+            "--image-format" => image_format = frames::ImageFormat::find(option_value(&mut args_iter, arg)).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            }),
+            "--stream" => stream_index = parse_number(option_value(&mut args_iter, arg), arg),
+            "--diagnose-caps" => diagnose_caps = true,
+            flag if flag.starts_with("--") => {
+                eprintln!("Unknown option: {}", flag);
+                process::exit(1);
+            },
+            _ => positional_args.push(arg),
+        }
+    }
+
+    let (Some(selection), [input_path, output_dir]) = (selection, positional_args.as_slice()) else {
+        eprintln!("Usage: {} export-frames <input video path> <output directory> (--every <time> | --at <time>[,<time>]... | --keyframes) [--image-format <png|jpeg>] [--stream <index>] [--diagnose-caps]", args[0]);
+        process::exit(1);
+    };
+
+    if let Err(err) = learning_gstreamer::init() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 
-    log::info!("Set state to playing");
-    let bus = {
-        let pipeline = pipeline.lock().unwrap();
-        pipeline.set_state(gstreamer::State::Playing).unwrap();
-        pipeline.bus().unwrap()
+    let config = frames::FrameExportConfig {
+        format: image_format,
+        stream_index,
+        diagnose_caps,
+        ..frames::FrameExportConfig::new(input_path.as_str(), output_dir.as_str(), selection)
     };
+    let result = frames::export_frames(&config, |frame| {
+        println!("{}{}: {}", frame.pts, if frame.keyframe { " (keyframe)" } else { "" }, frame.path.display());
+    });
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
 
-    log::info!("Before message loop");
-    for msg in bus.iter_timed(gstreamer::ClockTime::NONE) {
-        match msg.view() {
-            gstreamer::MessageView::Eos(..) => break,
-            gstreamer::MessageView::Error(err) => {
-                eprintln!(
-                    "Error from {:?}: {} ({:?})",
-                    msg.src().map(|s| s.path_string()),
-                    err.error(),
-                    err.debug()
-                );
+/// `export-audio` で音声を float32 の sample で書き出す
+fn export_audio(args: &[String]) {
+    let mut positional_args = Vec::new();
+    let mut format = None;
+    let mut sample_rate = None;
+    let mut channels = None;
+    let mut window = None;
+    let mut stream_index = 0;
+    let mut diagnose_caps = false;
+    let mut args_iter = args.iter().skip(2);
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--format" => format = Some(audio::AudioFileFormat::find(option_value(&mut args_iter, arg)).unwrap_or_else(|err| {
+                eprintln!("{}", err);
                 process::exit(1);
+            })),
+            "--rate" => sample_rate = Some(parse_number(option_value(&mut args_iter, arg), arg)),
+            "--channels" => channels = Some(parse_number(option_value(&mut args_iter, arg), arg)),
+            "--window" => window = Some(parse_time(option_value(&mut args_iter, arg))),
+            "--stream" => stream_index = parse_number(option_value(&mut args_iter, arg), arg),
+            "--diagnose-caps" => diagnose_caps = true,
+            flag if flag.starts_with("--") => {
+                eprintln!("Unknown option: {}", flag);
+                process::exit(1);
+            },
+            _ => positional_args.push(arg),
+        }
+    }
+
+    let [input_path, output_path] = positional_args.as_slice() else {
+        eprintln!("Usage: {} export-audio <input video path> <output path or directory> [--format <wav|f32|npy>] [--rate <hz>] [--channels <count>] [--window <time>] [--stream <index>] [--diagnose-caps]", args[0]);
+        process::exit(1);
+    };
+
+    // window に分けるときは出力がディレクトリなので、拡張子からは決めない
+    let format = match (format, window) {
+        (Some(format), _) => format,
+        (None, Some(_)) => {
+            eprintln!("--window requires --format");
+            process::exit(1);
+        },
+        (None, None) => audio::AudioFileFormat::from_path(Path::new(output_path.as_str())).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        }),
+    };
+
+    if let Err(err) = learning_gstreamer::init() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    let config = audio::AudioExportConfig {
+        sample_rate,
+        channels,
+        window,
+        stream_index,
+        diagnose_caps,
+        ..audio::AudioExportConfig::new(input_path.as_str(), output_path.as_str(), format)
+    };
+    match audio::export_audio(&config) {
+        Ok(export) => {
+            for window in &export.windows {
+                println!("{}: {} samples at {}", window.path.display(), window.frames, window.pts);
             }
-            _ => (),
+            println!("{} samples, {}Hz {}ch", export.frames, export.sample_rate, export.channels);
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
+    }
+}
+
+/// `export-tensors` で映像のフレームを (N, H, W, C) の配列で書き出す
+fn export_tensors(args: &[String]) {
+    let mut positional_args = Vec::new();
+    let mut format = tensors::TensorFormat::Npy;
+    let mut color_space = tensors::ColorSpace::Rgb;
+    let mut width = None;
+    let mut height = None;
+    let mut frame_stride = 1;
+    let mut batch_size = None;
+    let mut stream_index = 0;
+    let mut diagnose_caps = false;
+    let mut args_iter = args.iter().skip(2);
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--format" => format = tensors::TensorFormat::find(option_value(&mut args_iter, arg)).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            }),
+            "--color" => color_space = tensors::ColorSpace::find(option_value(&mut args_iter, arg)).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            }),
+            "--width" => width = Some(parse_number(option_value(&mut args_iter, arg), arg)),
+            "--height" => height = Some(parse_number(option_value(&mut args_iter, arg), arg)),
+            "--stride" => frame_stride = parse_number(option_value(&mut args_iter, arg), arg),
+            "--batch" => batch_size = Some(parse_number(option_value(&mut args_iter, arg), arg)),
+            "--stream" => stream_index = parse_number(option_value(&mut args_iter, arg), arg),
+            "--diagnose-caps" => diagnose_caps = true,
+            flag if flag.starts_with("--") => {
+                eprintln!("Unknown option: {}", flag);
+                process::exit(1);
+            },
+            _ => positional_args.push(arg),
         }
     }
-    log::info!("After message loop");
 
-    pipeline.lock().unwrap().set_state(gstreamer::State::Null).unwrap();
+    let [input_path, output_dir] = positional_args.as_slice() else {
+        eprintln!("Usage: {} export-tensors <input video path> <output directory> [--format <npy|npz>] [--color <rgb|bgr|gray>] [--width <px>] [--height <px>] [--stride <frames>] [--batch <frames> (required for npz)] [--stream <index>] [--diagnose-caps]", args[0]);
+        process::exit(1);
+    };
+
+    if let Err(err) = learning_gstreamer::init() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    let config = tensors::TensorExportConfig {
+        color_space,
+        width,
+        height,
+        frame_stride,
+        batch_size,
+        stream_index,
+        diagnose_caps,
+        ..tensors::TensorExportConfig::new(input_path.as_str(), output_dir.as_str(), format)
+    };
+    match tensors::export_tensors(&config) {
+        Ok(batches) => {
+            for batch in batches {
+                let (height, width, channels) = batch.shape;
+                println!("{}: ({}, {}, {}, {})", batch.path.display(), batch.pts.len(), height, width, channels);
+            }
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str, flag: &str) -> T {
+    s.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid number for {}: {}", flag, s);
+        process::exit(1);
+    })
+}
+
+fn parse_time(s: &str) -> gst::ClockTime {
+    time::parse_clock_time(s).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    })
 }
 
-fn get_muxer_from_extension(ext: &str) -> &'static str {
-    match ext {
-        "mp4" => "mp4mux",
-        "mkv" => "matroskamux",
-        "avi" => "avimux",
-        _ => {
-            eprintln!("Unsupported format: {}", ext);
+fn progress_listener(progress_json: bool) -> Box<dyn progress::ProgressListener> {
+    if progress_json {
+        Box::new(progress::JsonLinesListener::new(io::stdout()))
+    } else {
+        Box::new(progress::TextListener)
+    }
+}
+
+/// `<profile>=<output path>`
+fn parse_rendition(spec: &str) -> job::JobOutput {
+    let Some((profile_name, output_path)) = spec.split_once('=') else {
+        eprintln!("Invalid rendition `{}`, expected <profile>=<output path>", spec);
+        process::exit(1);
+    };
+
+    job::JobOutput { path: output_path.into(), profile: Some(profile_name.to_string()), ..Default::default() }
+}
+
+fn option_value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> &'a str {
+    match args.next() {
+        Some(value) => value,
+        None => {
+            eprintln!("Missing value for {}", flag);
             process::exit(1);
         }
     }
 }
-
@@ -0,0 +1,421 @@
+use std::{path::PathBuf, sync::Mutex, thread};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+
+use h264_reader::nal::{self, Nal, RefNal};
+
+use crate::{error::{Error, Result}, negotiation::NegotiationTracer, probe::{self, MediaInfo}};
+
+/// h264parse の後ろで NAL を区切りやすい形にしてもらう
+/// (mp4 の avc は NAL の長さが前につくが、 byte-stream なら start code で区切れる)
+fn access_unit_caps() -> gst::Caps {
+    gst::Caps::builder("video/x-h264").field("stream-format", "byte-stream").field("alignment", "au").build()
+}
+
+#[derive(Debug, Clone)]
+pub struct InspectConfig {
+    /// h264 の映像を含む入力。 container は中身 (typefind) で判定する
+    pub path: PathBuf,
+    pub diagnose_caps: bool,
+}
+
+impl InspectConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), diagnose_caps: false }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NalUnit {
+    /// header が読めなかったときは None
+    pub unit_type: Option<nal::UnitType>,
+    /// start code を除いた長さ
+    pub size: usize,
+}
+
+/// 一つの buffer に入っている NAL の並び (一つのフレーム)
+#[derive(Debug, Clone)]
+pub struct AccessUnit {
+    pub pts: Option<gst::ClockTime>,
+    pub dts: Option<gst::ClockTime>,
+    pub keyframe: bool,
+    pub nal_units: Vec<NalUnit>,
+}
+
+/// filesrc ! demux ! h264parse ! capsfilter ! fakesink で最初の h264 の映像を流して、
+/// fakesink に来た access unit ごとに `on_access_unit` を呼ぶ
+///
+/// `on_access_unit` は streaming thread から呼ばれる
+pub fn inspect<F>(config: &InspectConfig, on_access_unit: F) -> Result<MediaInfo>
+where
+    F: FnMut(AccessUnit) + Send + 'static,
+{
+    let pipeline = gst::Pipeline::builder()
+        // 含まれる子 elements 全部の messages をフォワードして pipeline から取れるようにする
+        // ちなみに messages と events の違い:
+        // - messages はアプリケーションと elements の非同期メッセージ
+        // - events は elements 間の非同期メッセージ
+        // .message_forward(true)
+        .name("main_pipeline")
+        .build();
+
+    let filesrc_el = gst::ElementFactory::make("filesrc").name("src").property("location", config.path.as_path()).build()?;
+
+    // 拡張子ではなく中身 (typefind) で container を判定して demuxer を選ぶ
+    let media_info = probe::discover(&config.path)?;
+    let demuxer_name = probe::find_demuxer(&media_info);
+    log::debug!("Demuxer: {}", demuxer_name);
+
+    let demux_el = gst::ElementFactory::make(&demuxer_name).name("demux").build()?;
+    let h264parse_el = gst::ElementFactory::make("h264parse").name("dec").build()?;
+    let capsfilter_el = gst::ElementFactory::make("capsfilter")
+        .name("filter")
+        .property("caps", access_unit_caps())
+        .build()?;
+    let fakesink_el = gst::ElementFactory::make("fakesink").name("sink").property("signal-handoffs", true).build()?;
+
+    let on_access_unit = Mutex::new(on_access_unit);
+    let handoff_signal_handler_id = fakesink_el.connect("handoff", false, move |args| {
+        log::trace!("Started handling handoff signal: {:?}", thread::current().id());
+
+        let Some(src_el) = args.first().and_then(|arg| arg.get::<gst::Element>().ok()) else {
+            log::warn!("Handoff signal without src element");
+            return None;
+        };
+        let Some(buffer) = args.get(1).and_then(|arg| arg.get::<gst::Buffer>().ok()) else {
+            gst::element_error!(src_el, gst::StreamError::Failed, ("Handoff signal without buffer"));
+            return None;
+        };
+
+        let map = match buffer.map_readable() {
+            Ok(map) => map,
+            Err(err) => {
+                gst::element_error!(src_el, gst::StreamError::Failed, ("Failed to map buffer: {}", err));
+                return None;
+            },
+        };
+
+        let nal_units = split_annexb(map.as_slice())
+            .map(|data| {
+                let nal = RefNal::new(data, &[], true);
+                log::trace!("Nal = {:?}", nal);
+                NalUnit { unit_type: nal.header().ok().map(|header| header.nal_unit_type()), size: data.len() }
+            })
+            .collect();
+        let access_unit = AccessUnit {
+            pts: buffer.pts(),
+            dts: buffer.dts(),
+            keyframe: !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT),
+            nal_units,
+        };
+        let mut on_access_unit = on_access_unit.lock().unwrap();
+        (*on_access_unit)(access_unit);
+
+        None
+    });
+    log::debug!("Set handoff signal handler: {:?}", handoff_signal_handler_id);
+
+    let pipeline_weak = pipeline.downgrade();
+    let h264parse_el_clone = h264parse_el.clone();
+    let capsfilter_el_clone = capsfilter_el.clone();
+    let fakesink_el_clone = fakesink_el.clone();
+    demux_el.connect_pad_added(move |el, pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+
+        let is_h264 = pad.current_caps().map_or(false, |caps| caps.iter().any(|structure| structure.name().as_str() == "video/x-h264"));
+        let linked = h264parse_el_clone.static_pad("sink").map_or(true, |h264parse_pad| h264parse_pad.is_linked());
+        if linked || !is_h264 {
+            log::debug!("Ignore demuxer pad than the other: {}", pad.name());
+            return;
+        }
+
+        let chain = [&h264parse_el_clone, &capsfilter_el_clone, &fakesink_el_clone];
+        let result = link_parser(&pipeline, pad, &chain);
+
+        match result {
+            Ok(()) => log::debug!("Connected demuxer first video pad to h264parse: {}", pad.name()),
+            Err(err) => gst::element_error!(el, gst::StreamError::Failed, ("Failed to link h264parse: {}", err)),
+        }
+    });
+
+    pipeline.add_many(&[&filesrc_el, &demux_el])?;
+
+    // demux_el の src pad は presence が sometimes なので、この時点では存在しないので
+    // fakesink_el をつなげない
+    gst::Element::link_many(&[&filesrc_el, &demux_el])?;
+
+    // demuxer の src pad はまだないので、ここで繋がっていない pad があれば組み立て方の間違い
+    debug_assert_eq!((pipeline.find_unlinked_pad(gst::PadDirection::Sink), pipeline.find_unlinked_pad(gst::PadDirection::Src)), (None, None));
+
+    // demuxer の pad-added で後から作られる pad にも probe をつける
+    let negotiation_tracer = config.diagnose_caps.then(|| NegotiationTracer::attach(pipeline.upcast_ref()));
+
+    let result = run(&pipeline, negotiation_tracer.as_ref());
+
+    // set_state したら、次のステップで状態が変わる時もあれば変わらない時もある
+    // (詳しくは run の中のコメント)
+    let state_result = match pipeline.set_state(gst::State::Null) {
+        Ok(gst::StateChangeSuccess::Success) => {
+            // 成功
+            log::debug!("Set pipeline null immediately");
+            Ok(())
+        },
+        Ok(gst::StateChangeSuccess::Async) => {
+            // 非同期処理で変更された場合
+            log::debug!("Started to set pipeline null async");
+            Ok(())
+        },
+        Ok(gst::StateChangeSuccess::NoPreroll) => {
+            // XXX ここにくるパターンを体験してない
+            log::debug!("Set pipeline null immediately, and knowing the stream coudln't be prerolled: {:?}", pipeline.state(None));
+            Ok(())
+        },
+        Err(err) => {
+            // state を変更できなかった。返り値にエラーメッセージは含まれない
+            log::error!("Failed to set pileline null");
+
+            // bus から取得できないか試みる
+            Err(pop_error(&pipeline, negotiation_tracer.as_ref()).unwrap_or(Error::StateChange(err)))
+        },
+    };
+
+    if let Some(bus) = pipeline.bus() {
+        for msg in bus.iter() {
+            log::debug!("MESSAGE: Remaining message after EOS: {:?}", msg.view());
+        }
+    }
+
+    result?;
+    state_result?;
+
+    Ok(media_info)
+}
+
+/// PLAYING にして EOS が来るまで bus を見る
+fn run(pipeline: &gst::Pipeline, negotiation_tracer: Option<&NegotiationTracer>) -> Result<()> {
+    // set_state したら、次のステップで状態が変わる時もあれば変わらない時もある
+    // あくまでも target となる state を set して、状態の遷移は非同期に行われることがある
+    // 内部的には以下のように複数の state がある
+    // - current_state
+    // - pending_state
+    // - next_state
+    // - target_state
+    //
+    // pipeline は実際には set された state に向かって以下のような遷移を行う
+    // - NULL → READY:
+    //   bus の flush が終わったり、必要なかったらすぐ READY になる
+    // - READY → PAUSED:
+    //   running_time を 0 にリセットにされた状態
+    // - PAUSED → PLAYING:
+    //   Clock の選択（変換の時は使わないけど、再生の時に開始位置と現在の時間から再生位置を合わせるために必要）
+    //   PLAYING になった時の Clock time が base_time になる（base_time + running_time の位置が処理すべきフレームや音声になる）
+    //   再生タスクの場合は latency もここで計算される
+    //   全ての elements に base_time と clock が伝達される
+    // - PLAYING → PAUSED:
+    //   処理が止まっているけど base_time, running_time は保持している
+    // - PAUSED → NULL:
+    //   bus の flush が始まる。必要がないならすぐ READY になる
+    //
+    // メモ: Clock, running_time, latency
+    // は再生の時に、どこまで再生してどのフレームを描画してどのペースでレンダリングすればいいかを調整するために使われる。変換タスクでは使われない。たぶん。
+    //
+    // 上記の説明はあくまでも内部的な話で、 get_state では内部的な state を知ることはできないっぽい。
+    // message を通じて本当の内部 state を知ることができる
+
+    debug_assert_eq!(pipeline.state(gst::ClockTime::ZERO).1, gst::State::Null);
+
+    match pipeline.set_state(gst::State::Playing) {
+        Ok(gst::StateChangeSuccess::Success) => {
+            // 成功 (
+            log::debug!("Set pipeline playing immediately");
+        },
+        Ok(gst::StateChangeSuccess::Async) => {
+            // 非同期処理で変更されるのでコールバックまち
+            log::debug!("Started to set pipeline playing async");
+        },
+        Ok(gst::StateChangeSuccess::NoPreroll) => {
+            // ライブ配信などで、 Paused にしても続きから再生できないとき
+            // あくまでも Success なので、状態遷移は行われている
+            log::debug!("Set pipeline playing without preroll");
+        },
+        Err(err) => {
+            // state を変更できなかった。返り値にエラーメッセージは含まれない
+            log::error!("Failed to set pileline playing");
+
+            // bus から取得できないか試みる
+            return Err(pop_error(pipeline, negotiation_tracer).unwrap_or(Error::StateChange(err)));
+        },
+    }
+
+    let bus = pipeline.bus().ok_or_else(|| glib::bool_error!("Pipeline has no bus"))?;
+
+    // bus を監視する。メッセージがあれば iter_timed が中で msg を timed_pop してくる。
+    // iter だと non-blocking メソッドとなりすぐに return しちゃう
+    // これ自体が event loop なわけではなく、 msg queue を poll しているだけ
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        // message を出した element や pad の名前 (ログ用)
+        let src_name = msg.src().map(|src| src.name().to_string()).unwrap_or_default();
+        match msg.view() {
+            gst::MessageView::StateChanged(state_changed) => {
+                let old_state = state_changed.old();
+                let current_state = state_changed.current();
+                let pending_state = state_changed.pending();
+
+                log::debug!("MESSAGE: State changed: [{}] {:?} -> {:?} {}", src_name, old_state, current_state, if pending_state == gst::State::VoidPending { "".into() } else { format!("(final: {:?})", pending_state) });
+            },
+            gst::MessageView::StreamStatus(stream_status) => {
+                let (status_type, owner_el) = stream_status.get();
+
+                // 公式 doc 曰く
+                // gst::Task が入っているが、将来にわたってそういう保証はないとのこと
+                let task = stream_status.stream_status_object().and_then(|object| object.get::<gst::Task>().ok());
+
+                // stream に関連づけられた task の名前はそのストリームが最初に出てきた element_name:pad_name らしい。
+                // 例えば安直なコードだと filesrc0:src とかになる
+
+                // [el_name:pad_name] (task_state) stream_status
+                log::debug!("MESSAGE: Stream: [{}:{}] (task={}) {:?}", owner_el.name(), src_name, task.map(|task| format!("{:?}", task.state())).unwrap_or("Unknown".into()), status_type);
+            },
+            gst::MessageView::AsyncDone(async_done) => {
+                // AsyncDone メッセージは 全ての sink elements の preroll が済んだことを示している
+                // ちなみにこれと対をなす AsyncStart は pipeline まで upward されずに pipeline
+                // で消費される。なぜ、そうなっているのかはわからないけど、 gstbin.c の
+                // gst_bin_handle_message_func 関数のヘッダコメントにそう書かれてる
+                log::debug!("MESSAGE: Async (Preroll) done: [{}] {}", src_name, async_done.running_time().display());
+            },
+            gst::MessageView::StreamStart(stream_start) => {
+                // stream は group_id というものを持つ、 stream の group_id は以下のような振る舞いをする
+                // - demux, queue など元の stream を派生させたり透過させたりする場合、そのストリームは派生元の group_id を持つ
+                // - filesrc, audiotestsrc など一からストリームを作るような stream は group_id を新しく割り当てる
+                // - mux など複数のストリームから一つのストリームを作るような stream も group_id を新しく割り当てる
+                //
+                // この挙動によって、どのストリームの源泉から来たストリームなのかを確認することができる
+                log::debug!("MESSAGE: Stream started: [{}] {:?}", src_name, stream_start.group_id());
+            },
+            gst::MessageView::Latency(..) => {
+                log::debug!("MESSAGE: Need recalculate latency: [{}]", src_name);
+                pipeline.recalculate_latency()?;
+            },
+            gst::MessageView::NewClock(new_clock) => {
+                log::debug!("MESSAGE: Clock set: [{}] {:?}", src_name, new_clock.clock());
+            },
+            gst::MessageView::Eos(..) => {
+                log::debug!("MESSAGE: EOS: [{}]", src_name);
+                break;
+            },
+            gst::MessageView::Error(err) => {
+                return Err(pipeline_error(&msg, err, negotiation_tracer));
+            },
+            view => {
+                log::info!("MESSAGE: Unknown message: {:?}", view);
+            },
+        }
+    }
+
+    log::debug!("Pipeline state after EOS: {:?}", pipeline.state(gst::ClockTime::ZERO));
+
+    Ok(())
+}
+
+/// demuxer の pad ! h264parse ! capsfilter ! fakesink
+fn link_parser(pipeline: &gst::Pipeline, src_pad: &gst::Pad, chain: &[&gst::Element]) -> std::result::Result<(), glib::BoolError> {
+    pipeline.add_many(chain)?;
+    gst::Element::link_many(chain)?;
+
+    let sink_pad = chain[0].static_pad("sink").ok_or_else(|| glib::bool_error!("h264parse has no sink pad"))?;
+    src_pad.link(&sink_pad)
+        .map_err(|err| glib::bool_error!("Failed to link {} to h264parse: {}", src_pad.name(), err))?;
+
+    for el in chain.iter().rev() {
+        el.sync_state_with_parent()?;
+    }
+
+    Ok(())
+}
+
+/// state を変えられなかったときに、 bus に理由が流れていれば取り出す
+fn pop_error(pipeline: &gst::Pipeline, negotiation_tracer: Option<&NegotiationTracer>) -> Option<Error> {
+    let msg = pipeline.bus()?.pop_filtered(&[gst::MessageType::Error])?;
+    match msg.view() {
+        gst::MessageView::Error(err) => Some(pipeline_error(&msg, err, negotiation_tracer)),
+        _ => None,
+    }
+}
+
+/// negotiation の失敗なら、どの pad の間で caps が合わなかったかを入れる
+fn pipeline_error(msg: &gst::Message, err: &gst::message::Error, negotiation_tracer: Option<&NegotiationTracer>) -> Error {
+    let negotiation_report = negotiation_tracer
+        .filter(|_| NegotiationTracer::is_negotiation_error(err))
+        .map(NegotiationTracer::report);
+    Error::from_message(msg, err, negotiation_report)
+}
+
+/// byte-stream の buffer を start code (00 00 01 か 00 00 00 01) で区切った NAL
+fn split_annexb(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    (0..starts.len())
+        .map(move |n| {
+            let start = starts[n];
+            let end = match starts.get(n + 1) {
+                Some(next_start) => {
+                    // 次の start code の前の 0 は 4 byte の start code の先頭か trailing_zero_8bits なので落とす
+                    let mut end = next_start - 3;
+                    while end > start && data[end - 1] == 0 {
+                        end -= 1;
+                    }
+                    end
+                },
+                None => data.len(),
+            };
+            &data[start..end]
+        })
+        // RefNal は空の NAL を受け付けない
+        .filter(|nal| !nal.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(data: &[u8]) -> Vec<&[u8]> {
+        split_annexb(data).collect()
+    }
+
+    #[test]
+    fn splits_three_byte_start_codes() {
+        let data = [0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3];
+        assert_eq!(split(&data), [&[0x67, 1, 2][..], &[0x68, 3][..]]);
+    }
+
+    #[test]
+    fn splits_four_byte_start_codes() {
+        let data = [0, 0, 0, 1, 0x09, 0x10, 0, 0, 0, 1, 0x65, 4, 5];
+        assert_eq!(split(&data), [&[0x09, 0x10][..], &[0x65, 4, 5][..]]);
+    }
+
+    #[test]
+    fn strips_trailing_zero_bytes_only_before_next_start_code() {
+        let data = [0, 0, 1, 0x06, 7, 0, 0, 0, 0, 1, 0x65, 8, 0];
+        assert_eq!(split(&data), [&[0x06, 7][..], &[0x65, 8, 0][..]]);
+    }
+
+    #[test]
+    fn ignores_bytes_before_first_start_code_and_empty_nal() {
+        assert_eq!(split(&[9, 9, 0, 0, 1, 0x41, 0, 0, 1]), [&[0x41][..]]);
+        assert!(split(&[]).is_empty());
+        assert!(split(&[0, 0]).is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{collections::HashMap, fmt::{self, Write}, sync::{Arc, Mutex}};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+
+/// pad ごとに記録した caps
+#[derive(Debug, Default, Clone)]
+struct PadRecord {
+    /// src pad から流れた CAPS event の caps (決まった caps)
+    caps_event: Option<gst::Caps>,
+    /// この pad が caps query に返した caps
+    /// src pad なら出せる caps、 sink pad なら受け付けられる caps
+    query_filter: Option<gst::Caps>,
+    query_result: Option<gst::Caps>,
+    /// sink pad に来た最後の accept-caps query の caps と結果
+    accept_caps: Option<(gst::Caps, bool)>,
+}
+
+struct TracedPad {
+    pad: glib::WeakRef<gst::Pad>,
+    record: PadRecord,
+}
+
+type TracedPads = Arc<Mutex<HashMap<String, TracedPad>>>;
+
+/// negotiation に失敗した link
+#[derive(Debug, Clone)]
+pub struct NegotiationFailure {
+    pub src_pad: String,
+    pub sink_pad: String,
+    /// src pad が出そうとした caps (accept-caps で断られた caps、 CAPS event の caps、 caps query の結果の順に使う)
+    pub offered: Option<gst::Caps>,
+    /// sink pad が caps query に返した caps
+    pub accepted: Option<gst::Caps>,
+    pub intersection: Option<gst::Caps>,
+    /// sink pad が accept-caps で offered を断った
+    pub rejected: bool,
+}
+
+impl fmt::Display for NegotiationFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let caps = |caps: &Option<gst::Caps>| caps.as_ref().map_or_else(|| "(not recorded)".to_string(), |caps| caps.to_string());
+        writeln!(f, "{} -> {}{}", self.src_pad, self.sink_pad, if self.rejected { " (rejected by accept-caps)" } else { "" })?;
+        writeln!(f, "  offered:      {}", caps(&self.offered))?;
+        writeln!(f, "  accepted:     {}", caps(&self.accepted))?;
+        write!(f, "  intersection: {}", caps(&self.intersection))
+    }
+}
+
+/// pipeline の全部の pad で CAPS event と caps/accept-caps query を記録して、
+/// not-negotiated で止まったときにどの link で caps が合わなかったかを調べる
+///
+/// 後から追加される element (decodebin の中など) と pad (demuxer の pad-added など) にも probe をつけるので、
+/// pipeline を作った直後、 PLAYING にする前に `attach` すること
+///
+/// transcode や export の config の `diagnose_caps` を true にするとこれを attach して、
+/// negotiation のエラーで止まったときに `Error::Pipeline` にどの link で失敗したかを入れる
+#[derive(Default)]
+pub struct NegotiationTracer {
+    pads: TracedPads,
+}
+
+impl NegotiationTracer {
+    pub fn attach(bin: &gst::Bin) -> Self {
+        let tracer = Self::default();
+
+        {
+            let pads = tracer.pads.clone();
+            bin.connect_deep_element_added(move |_, _, element| trace_element(&pads, element));
+        }
+        for element in bin.iterate_recurse().into_iter().flatten() {
+            trace_element(&tracer.pads, &element);
+        }
+
+        tracer
+    }
+
+    /// bus の error が negotiation の失敗によるものか
+    ///
+    /// streaming thread の not-negotiated は flow error として stream error で上がってくるので、 debug の文字列で見分ける
+    /// (StreamError::Format は壊れた入力などでも出るので、それだけでは negotiation の失敗とみなさない)
+    pub fn is_negotiation_error(err: &gst::message::Error) -> bool {
+        err.error().matches(gst::CoreError::Negotiation)
+            || err.debug().map_or(false, |debug| debug.contains("not-negotiated"))
+    }
+
+    /// 記録した caps から、 caps が合わなかった src pad と sink pad の組を探す
+    pub fn failures(&self) -> Vec<NegotiationFailure> {
+        let pads = self.pads.lock().unwrap();
+
+        let mut failures = Vec::new();
+        for (src_path, traced) in pads.iter() {
+            let Some(src_pad) = traced.pad.upgrade() else {
+                continue;
+            };
+            if src_pad.direction() != gst::PadDirection::Src {
+                continue;
+            }
+            let Some(sink_pad) = src_pad.peer() else {
+                continue;
+            };
+            let sink_path = sink_pad.path_string().to_string();
+            let sink_record = pads.get(&sink_path).map(|traced| &traced.record);
+
+            let rejected_caps = sink_record
+                .and_then(|record| record.accept_caps.clone())
+                .and_then(|(caps, accepted)| (!accepted).then_some(caps));
+            let offered = rejected_caps.clone()
+                .or_else(|| traced.record.caps_event.clone())
+                .or_else(|| traced.record.query_result.clone());
+            let accepted = sink_record.and_then(|record| record.query_result.clone());
+            let intersection = match (&offered, &accepted) {
+                (Some(offered), Some(accepted)) => Some(offered.intersect(accepted)),
+                _ => None,
+            };
+
+            // caps が決まる前に、出せる caps と受け付けられる caps が重ならなかった
+            let no_common_caps = traced.record.caps_event.is_none() && intersection.as_ref().map_or(false, |caps| caps.is_empty());
+            if rejected_caps.is_none() && !no_common_caps {
+                continue;
+            }
+
+            failures.push(NegotiationFailure {
+                src_pad: src_path.clone(),
+                sink_pad: sink_path,
+                offered,
+                accepted,
+                intersection,
+                rejected: rejected_caps.is_some(),
+            });
+        }
+
+        failures.sort_by(|a, b| a.src_pad.cmp(&b.src_pad));
+        failures
+    }
+
+    /// 失敗した link の説明。見つからなかったときは caps が決まらなかった pad を並べる
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+
+        let failures = self.failures();
+        if !failures.is_empty() {
+            let _ = writeln!(report, "Caps negotiation failed on {} link(s):", failures.len());
+            for failure in failures {
+                let _ = writeln!(report, "{}", failure);
+            }
+            return report;
+        }
+
+        let _ = writeln!(report, "No link with incompatible caps was recorded");
+        let pads = self.pads.lock().unwrap();
+        let mut unnegotiated = pads.iter()
+            .filter(|(_, traced)| traced.pad.upgrade().map_or(false, |pad| pad.direction() == gst::PadDirection::Src && pad.is_linked()))
+            .filter(|(_, traced)| traced.record.caps_event.is_none())
+            .collect::<Vec<_>>();
+        unnegotiated.sort_by(|a, b| a.0.cmp(b.0));
+        for (path, traced) in unnegotiated {
+            let _ = writeln!(report, "{} has no caps (query filter: {:?}, result: {:?})", path, traced.record.query_filter, traced.record.query_result);
+        }
+
+        report
+    }
+}
+
+fn trace_element(pads: &TracedPads, element: &gst::Element) {
+    {
+        let pads = pads.clone();
+        element.connect_pad_added(move |_, pad| trace_pad(&pads, pad));
+    }
+    for pad in element.pads() {
+        trace_pad(pads, &pad);
+    }
+}
+
+/// src pad では下流に流れる CAPS event と下流からの caps query、
+/// sink pad では上流からの caps query と accept-caps query を記録する
+fn trace_pad(pads: &TracedPads, pad: &gst::Pad) {
+    let path = pad.path_string().to_string();
+    {
+        let mut pads = pads.lock().unwrap();
+        if pads.contains_key(&path) {
+            return;
+        }
+        pads.insert(path.clone(), TracedPad { pad: pad.downgrade(), record: PadRecord::default() });
+    }
+
+    let mask = match pad.direction() {
+        gst::PadDirection::Src => gst::PadProbeType::EVENT_DOWNSTREAM | gst::PadProbeType::QUERY_UPSTREAM,
+        gst::PadDirection::Sink => gst::PadProbeType::QUERY_DOWNSTREAM,
+        _ => return,
+    };
+
+    let pads = pads.clone();
+    pad.add_probe(mask, move |_, info| {
+        // query は答えが入った後 (PULL) だけ見る
+        let answered = info.mask.contains(gst::PadProbeType::PULL);
+        let mut pads = pads.lock().unwrap();
+        let Some(traced) = pads.get_mut(&path) else {
+            return gst::PadProbeReturn::Remove;
+        };
+        let record = &mut traced.record;
+
+        match &info.data {
+            Some(gst::PadProbeData::Event(event)) => {
+                if let gst::EventView::Caps(caps) = event.view() {
+                    log::trace!("CAPS event on {}: {}", path, caps.caps());
+                    record.caps_event = Some(caps.caps_owned());
+                }
+            },
+            Some(gst::PadProbeData::Query(query)) if answered => match query.view() {
+                gst::QueryView::Caps(caps) => {
+                    log::trace!("Caps query on {}: filter {:?}, result {:?}", path, caps.filter(), caps.result());
+                    record.query_filter = caps.filter().map(|caps| caps.to_owned());
+                    record.query_result = caps.result().map(|caps| caps.to_owned());
+                },
+                gst::QueryView::AcceptCaps(accept_caps) => {
+                    log::trace!("Accept-caps query on {}: {} -> {}", path, accept_caps.caps(), accept_caps.result());
+                    record.accept_caps = Some((accept_caps.caps().to_owned(), accept_caps.result()));
+                },
+                _ => (),
+            },
+            _ => (),
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+}
@@ -0,0 +1,236 @@
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// header (magic から dict の後ろの改行まで) の長さ
+///
+/// 書き終わるまで shape が分からないので、長さを固定しておいて後から同じ長さの header で上書きする
+/// (データの先頭が 64 byte 境界に揃うように 64 の倍数にする)
+pub(crate) const HEADER_LEN: usize = 128;
+
+/// format version 1.0 の .npy の header を書く
+///
+/// descr は numpy の dtype の表記 (`<f4`, `|u1` など) で、データは C の順 (最後の次元が連続) に並べる
+pub(crate) fn write_header<W: Write>(writer: &mut W, descr: &str, shape: &[u64]) -> io::Result<()> {
+    let shape = match shape {
+        // 要素が一つの tuple は python の表記でカンマが要る
+        [length] => format!("({},)", length),
+        _ => format!("({})", shape.iter().map(u64::to_string).collect::<Vec<_>>().join(", ")),
+    };
+    let dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+
+    // magic, version (2 byte), dict の長さ (2 byte) の後ろに dict を書いて、空白で埋めて改行で終える
+    let dict_len = HEADER_LEN - MAGIC.len() - 4;
+    if dict.len() + 1 > dict_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("npy header is too long: {}", dict)));
+    }
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_u16::<LittleEndian>(dict_len as u16)?;
+    writer.write_all(dict.as_bytes())?;
+    writer.write_all(&vec![b' '; dict_len - dict.len() - 1])?;
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+/// header を書いた Vec を返す (npz の中に入れるとき用)
+pub(crate) fn header(descr: &str, shape: &[u64]) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    write_header(&mut header, descr, shape)?;
+    Ok(header)
+}
+
+/// zip64 を使わないので、 .npz 全体がこの長さに収まらないといけない
+pub(crate) const NPZ_MAX_LEN: u64 = u32::MAX as u64;
+
+/// .npz の中の一つの .npy 。大きいデータを繋げてコピーしないように header とデータを分けて渡す
+pub(crate) struct NpzEntry<'a> {
+    pub name: &'a str,
+    pub header: &'a [u8],
+    pub data: &'a [u8],
+}
+
+/// numpy.savez と同じ、圧縮しない (stored) zip に .npy を並べた .npz を書く
+///
+/// zip64 は使わないので、全体で 4 GiB までしか書けない
+pub(crate) fn write_npz<W: Write>(writer: &mut W, entries: &[NpzEntry]) -> io::Result<()> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, "npz output must be smaller than 4 GiB");
+
+    let mut offset = 0u64;
+    let mut central_directory = Vec::new();
+    for entry in entries {
+        let size = u32::try_from(entry.header.len() + entry.data.len()).map_err(|_| too_large())?;
+        let crc = crc32(crc32(0, entry.header), entry.data);
+        let local_header_offset = u32::try_from(offset).map_err(|_| too_large())?;
+
+        // local file header
+        writer.write_u32::<LittleEndian>(0x04034b50)?;
+        write_entry_fields(writer, entry.name, crc, size)?;
+        writer.write_all(entry.name.as_bytes())?;
+        writer.write_all(entry.header)?;
+        writer.write_all(entry.data)?;
+        offset += 30 + entry.name.len() as u64 + size as u64;
+
+        // central directory file header
+        central_directory.write_u32::<LittleEndian>(0x02014b50)?;
+        central_directory.write_u16::<LittleEndian>(ZIP_VERSION)?;
+        write_entry_fields(&mut central_directory, entry.name, crc, size)?;
+        // comment の長さ, disk 番号, 内部属性, 外部属性
+        central_directory.write_u16::<LittleEndian>(0)?;
+        central_directory.write_u16::<LittleEndian>(0)?;
+        central_directory.write_u16::<LittleEndian>(0)?;
+        central_directory.write_u32::<LittleEndian>(0)?;
+        central_directory.write_u32::<LittleEndian>(local_header_offset)?;
+        central_directory.write_all(entry.name.as_bytes())?;
+    }
+
+    let central_directory_offset = u32::try_from(offset).map_err(|_| too_large())?;
+    writer.write_all(&central_directory)?;
+
+    // end of central directory record
+    writer.write_u32::<LittleEndian>(0x06054b50)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(entries.len() as u16)?;
+    writer.write_u16::<LittleEndian>(entries.len() as u16)?;
+    writer.write_u32::<LittleEndian>(central_directory.len() as u32)?;
+    writer.write_u32::<LittleEndian>(central_directory_offset)?;
+    writer.write_u16::<LittleEndian>(0)?;
+
+    Ok(())
+}
+
+/// zip 2.0 (stored のみなら十分)
+const ZIP_VERSION: u16 = 20;
+
+/// local file header と central directory で共通の、 version needed から extra field の長さまで
+fn write_entry_fields<W: Write>(writer: &mut W, name: &str, crc: u32, size: u32) -> io::Result<()> {
+    writer.write_u16::<LittleEndian>(ZIP_VERSION)?;
+    // flags, 圧縮方式 (0 = stored)
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    // 更新時刻と日付。 numpy は見ないので MS-DOS の日付の最小値 (1980-01-01 00:00) にする
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(0x21)?;
+    writer.write_u32::<LittleEndian>(crc)?;
+    // 圧縮後と圧縮前のサイズ
+    writer.write_u32::<LittleEndian>(size)?;
+    writer.write_u32::<LittleEndian>(size)?;
+    writer.write_u16::<LittleEndian>(name.len() as u16)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    Ok(())
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// zip の CRC-32 。 crc に前のデータの結果を渡すと続きから計算する
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    #[test]
+    fn crc32_matches_known_value() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+        // 続きから計算しても同じになる
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn header_is_aligned_and_ends_with_newline() {
+        let header = header("<f4", &[2, 3]).unwrap();
+        assert_eq!(header.len() % 64, 0);
+        assert_eq!(&header[..6], MAGIC);
+        assert_eq!(&header[6..8], &[1, 0]);
+        assert_eq!(u16_at(&header, 8) as usize, header.len() - 10);
+        assert_eq!(header.last(), Some(&b'\n'));
+
+        let dict = std::str::from_utf8(&header[10..]).unwrap();
+        assert!(dict.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"));
+    }
+
+    #[test]
+    fn header_writes_one_element_tuple() {
+        let header = header("|u1", &[5]).unwrap();
+        let dict = std::str::from_utf8(&header[10..]).unwrap();
+        assert!(dict.contains("'shape': (5,)"));
+    }
+
+    #[test]
+    fn header_rejects_too_long_dict() {
+        assert!(header("<f4", &[u64::MAX; 8]).is_err());
+    }
+
+    #[test]
+    fn npz_central_directory_points_to_entries() {
+        let entries = [
+            NpzEntry { name: "a.npy", header: b"header-a", data: b"data" },
+            NpzEntry { name: "bb.npy", header: b"header-b", data: b"more data" },
+        ];
+        let mut npz = Vec::new();
+        write_npz(&mut npz, &entries).unwrap();
+
+        // end of central directory record から central directory を辿る
+        let eocd = npz.len() - 22;
+        assert_eq!(u32_at(&npz, eocd), 0x06054b50);
+        assert_eq!(u16_at(&npz, eocd + 10) as usize, entries.len());
+        let central_directory_size = u32_at(&npz, eocd + 12) as usize;
+        let mut offset = u32_at(&npz, eocd + 16) as usize;
+        assert_eq!(offset + central_directory_size, eocd);
+
+        for entry in &entries {
+            assert_eq!(u32_at(&npz, offset), 0x02014b50);
+            let crc = u32_at(&npz, offset + 16);
+            let size = u32_at(&npz, offset + 24) as usize;
+            let name_len = u16_at(&npz, offset + 28) as usize;
+            let local_header_offset = u32_at(&npz, offset + 42) as usize;
+            assert_eq!(&npz[offset + 46..offset + 46 + name_len], entry.name.as_bytes());
+            offset += 46 + name_len;
+
+            // local file header の後ろに header とデータが続いている
+            assert_eq!(u32_at(&npz, local_header_offset), 0x04034b50);
+            assert_eq!(u32_at(&npz, local_header_offset + 14), crc);
+            let data_offset = local_header_offset + 30 + name_len;
+            assert_eq!(&npz[local_header_offset + 30..data_offset], entry.name.as_bytes());
+            let contents = [entry.header, entry.data].concat();
+            assert_eq!(&npz[data_offset..data_offset + size], contents.as_slice());
+            assert_eq!(crc32(0, &contents), crc);
+        }
+        assert_eq!(offset, eocd);
+    }
+}
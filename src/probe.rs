@@ -0,0 +1,209 @@
+use std::{fmt, path::Path};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+use gstreamer_pbutils as gst_pbutils;
+use gst_pbutils::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+impl StreamKind {
+    /// `--map 0:a:1` の a の部分
+    pub fn selector(&self) -> char {
+        match self {
+            Self::Video => 'v',
+            Self::Audio => 'a',
+            Self::Subtitle => 's',
+        }
+    }
+
+    /// demuxer の src pad の caps から stream の種類を判定する
+    pub fn from_caps(caps: &gst::CapsRef) -> Option<Self> {
+        let media_type = caps.structure(0)?.name();
+        if media_type.starts_with("video/") {
+            Some(Self::Video)
+        } else if media_type.starts_with("audio/") {
+            Some(Self::Audio)
+        } else if media_type.starts_with("text/") || media_type.starts_with("subtitle/") || media_type.starts_with("application/x-subtitle") || ["application/x-ssa", "application/x-ass", "application/x-usf"].contains(&media_type.as_str()) {
+            Some(Self::Subtitle)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub kind: StreamKind,
+    /// 種類ごとの通し番号 (`--map 0:a:1` の 1)
+    pub index: usize,
+    pub stream_id: Option<String>,
+    pub caps: Option<gst::Caps>,
+    pub language: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub framerate: Option<gst::Fraction>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+}
+
+impl fmt::Display for StreamInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.kind.selector(), self.index)?;
+        match self.caps.as_ref().and_then(|caps| caps.structure(0)) {
+            Some(structure) => write!(f, " {}", structure.name())?,
+            None => write!(f, " unknown")?,
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            write!(f, " {}x{}", width, height)?;
+        }
+        if let Some(framerate) = self.framerate {
+            write!(f, " {}/{}fps", framerate.numer(), framerate.denom())?;
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            write!(f, " {}Hz", sample_rate)?;
+        }
+        if let Some(channels) = self.channels {
+            write!(f, " {}ch", channels)?;
+        }
+        if let Some(language) = &self.language {
+            write!(f, " language={}", language)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaInfo {
+    /// typefind で判定された container の caps 。 container に入っていない elementary stream なら None
+    pub container_caps: Option<gst::Caps>,
+    pub duration: Option<gst::ClockTime>,
+    pub streams: Vec<StreamInfo>,
+}
+
+impl fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.container_caps {
+            Some(caps) => writeln!(f, "Container: {}", caps)?,
+            None => writeln!(f, "Container: none")?,
+        }
+        writeln!(f, "Duration: {}", self.duration.display())?;
+        for stream in &self.streams {
+            writeln!(f, "Stream: {}", stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl MediaInfo {
+    /// demuxer の pad に対応する stream を探す
+    ///
+    /// stream-id が一致するものを優先して、なければ種類ごとの順番で対応づける
+    pub fn find_stream(&self, stream_id: Option<&str>, kind: StreamKind, index: usize) -> Option<&StreamInfo> {
+        stream_id
+            .and_then(|stream_id| self.streams.iter().find(|stream| stream.stream_id.as_deref() == Some(stream_id)))
+            .or_else(|| self.streams.iter().find(|stream| stream.kind == kind && stream.index == index))
+    }
+}
+
+/// pipeline を組む前に Discoverer で入力に含まれる stream を調べる
+///
+/// 言語などの tag は demuxer の pad-added の時点ではまだ流れてきていないので、先に調べておく必要がある
+pub fn discover(path: &Path) -> Result<MediaInfo, glib::BoolError> {
+    let path = path.canonicalize().map_err(|err| glib::bool_error!("Failed to resolve {}: {}", path.display(), err))?;
+    let uri = gst::filename_to_uri(&path).map_err(|err| glib::bool_error!("Failed to make uri from {}: {}", path.display(), err))?;
+
+    let discoverer = gst_pbutils::Discoverer::new(gst::ClockTime::from_seconds(10))
+        .map_err(|err| glib::bool_error!("Failed to create discoverer: {}", err))?;
+    let info = discoverer.discover_uri(&uri)
+        .map_err(|err| glib::bool_error!("Failed to discover {}: {}", uri, err))?;
+
+    // 一番上の stream info が container なら、その caps が typefind の結果になっている
+    let container_caps = info.stream_info()
+        .and_then(|stream| stream.downcast::<gst_pbutils::DiscovererContainerInfo>().ok())
+        .and_then(|container| container.caps());
+
+    let mut streams = Vec::new();
+    for (index, stream) in info.video_streams().iter().enumerate() {
+        streams.push(StreamInfo {
+            kind: StreamKind::Video,
+            index,
+            stream_id: stream.stream_id().map(|id| id.to_string()),
+            caps: stream.caps(),
+            language: stream.tags().and_then(|tags| tags.get::<gst::tags::LanguageCode>().map(|tag| tag.get().to_string())),
+            width: Some(stream.width()),
+            height: Some(stream.height()),
+            framerate: Some(stream.framerate()),
+            sample_rate: None,
+            channels: None,
+        });
+    }
+    for (index, stream) in info.audio_streams().iter().enumerate() {
+        streams.push(StreamInfo {
+            kind: StreamKind::Audio,
+            index,
+            stream_id: stream.stream_id().map(|id| id.to_string()),
+            caps: stream.caps(),
+            language: stream.language().map(|language| language.to_string()),
+            width: None,
+            height: None,
+            framerate: None,
+            sample_rate: Some(stream.sample_rate()),
+            channels: Some(stream.channels()),
+        });
+    }
+    for (index, stream) in info.subtitle_streams().iter().enumerate() {
+        streams.push(StreamInfo {
+            kind: StreamKind::Subtitle,
+            index,
+            stream_id: stream.stream_id().map(|id| id.to_string()),
+            caps: stream.caps(),
+            language: stream.language().map(|language| language.to_string()),
+            width: None,
+            height: None,
+            framerate: None,
+            sample_rate: None,
+            channels: None,
+        });
+    }
+
+    for stream in &streams {
+        log::debug!("Discovered stream: {:?}", stream);
+    }
+
+    Ok(MediaInfo { container_caps, duration: info.duration(), streams })
+}
+
+/// container の caps を受け付ける demuxer を registry から rank 順に選ぶ
+///
+/// container に入っていない入力や、対応する demuxer がない場合は parsebin に任せる
+pub fn find_demuxer(media_info: &MediaInfo) -> String {
+    let Some(container_caps) = &media_info.container_caps else {
+        return "parsebin".to_string();
+    };
+
+    match find_factory(gst::ElementFactoryType::DEMUXER, container_caps) {
+        Some(factory) => factory.name().to_string(),
+        None => {
+            log::warn!("No demuxer found for {}, fallback to parsebin", container_caps);
+            "parsebin".to_string()
+        },
+    }
+}
+
+/// elementary stream の caps を受け付ける parser (h264parse, aacparse など) を選ぶ
+pub fn find_parser(caps: &gst::Caps) -> Option<String> {
+    find_factory(gst::ElementFactoryType::PARSER, caps).map(|factory| factory.name().to_string())
+}
+
+fn find_factory(factory_type: gst::ElementFactoryType, caps: &gst::Caps) -> Option<gst::ElementFactory> {
+    gst::ElementFactory::factories_with_type(factory_type, gst::Rank::Marginal)
+        .into_iter()
+        .filter(|factory| factory.can_sink_any_caps(caps))
+        .max_by_key(|factory| factory.rank())
+}
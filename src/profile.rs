@@ -0,0 +1,304 @@
+use gstreamer as gst;
+use gst::glib;
+
+use crate::encoder::{self, EncoderSpec, Encoding};
+
+/// 名前で選べる encode の設定
+#[derive(Debug, Clone, Copy)]
+pub struct Profile {
+    pub name: &'static str,
+    pub video: VideoProfile,
+    pub audio: AudioProfile,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VideoProfile {
+    pub encoder: &'static str,
+    /// kbit/s
+    pub bitrate: Option<u32>,
+    pub speed_preset: Option<&'static str>,
+    pub key_int_max: Option<u32>,
+    /// encoder の src caps の profile (h264 なら baseline, main, high など)
+    pub profile: Option<&'static str>,
+    pub level: Option<&'static str>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub framerate: Option<(i32, i32)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AudioProfile {
+    pub encoder: &'static str,
+    /// kbit/s
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+}
+
+pub const PROFILES: &[Profile] = &[
+    Profile {
+        name: "web-h264-1080p",
+        video: VideoProfile {
+            encoder: "x264enc",
+            bitrate: Some(5000),
+            speed_preset: Some("medium"),
+            key_int_max: Some(60),
+            profile: Some("high"),
+            level: Some("4.1"),
+            width: Some(1920),
+            height: Some(1080),
+            framerate: Some((30, 1)),
+        },
+        audio: AudioProfile { encoder: "avenc_aac", bitrate: Some(192), sample_rate: Some(48000), channels: Some(2) },
+    },
+    Profile {
+        name: "web-h264-aac",
+        video: VideoProfile {
+            encoder: "x264enc",
+            bitrate: Some(2500),
+            speed_preset: Some("medium"),
+            key_int_max: Some(60),
+            profile: Some("main"),
+            level: Some("4"),
+            width: Some(1280),
+            height: Some(720),
+            framerate: Some((30, 1)),
+        },
+        audio: AudioProfile { encoder: "avenc_aac", bitrate: Some(128), sample_rate: Some(48000), channels: Some(2) },
+    },
+    Profile {
+        name: "web-h264-480p",
+        video: VideoProfile {
+            encoder: "x264enc",
+            bitrate: Some(1000),
+            speed_preset: Some("medium"),
+            key_int_max: Some(60),
+            profile: Some("main"),
+            level: Some("3.1"),
+            width: Some(854),
+            height: Some(480),
+            framerate: Some((30, 1)),
+        },
+        audio: AudioProfile { encoder: "avenc_aac", bitrate: Some(96), sample_rate: Some(48000), channels: Some(2) },
+    },
+    Profile {
+        name: "archive-x265-flac",
+        video: VideoProfile {
+            encoder: "x265enc",
+            bitrate: Some(8000),
+            speed_preset: Some("slow"),
+            key_int_max: Some(250),
+            profile: Some("main"),
+            level: None,
+            width: None,
+            height: None,
+            framerate: None,
+        },
+        audio: AudioProfile { encoder: "flacenc", bitrate: None, sample_rate: None, channels: None },
+    },
+    Profile {
+        name: "preview-lowres",
+        video: VideoProfile {
+            encoder: "x264enc",
+            bitrate: Some(300),
+            speed_preset: Some("ultrafast"),
+            key_int_max: Some(30),
+            profile: Some("baseline"),
+            level: None,
+            width: Some(640),
+            height: Some(360),
+            framerate: Some((15, 1)),
+        },
+        audio: AudioProfile { encoder: "avenc_aac", bitrate: Some(64), sample_rate: Some(44100), channels: Some(1) },
+    },
+];
+
+/// 同じ意味のパラメータでも encoder ごとにプロパティ名や単位が違うので、ここで吸収する
+struct EncoderParams {
+    factory_name: &'static str,
+    /// プロパティ名と、 kbit/s からの倍率
+    bitrate: Option<(&'static str, u32)>,
+    speed_preset: Option<&'static str>,
+    key_int_max: Option<&'static str>,
+}
+
+const ENCODER_PARAMS: &[EncoderParams] = &[
+    EncoderParams { factory_name: "x264enc", bitrate: Some(("bitrate", 1)), speed_preset: Some("speed-preset"), key_int_max: Some("key-int-max") },
+    EncoderParams { factory_name: "x265enc", bitrate: Some(("bitrate", 1)), speed_preset: Some("speed-preset"), key_int_max: Some("key-int-max") },
+    EncoderParams { factory_name: "openh264enc", bitrate: Some(("bitrate", 1000)), speed_preset: None, key_int_max: Some("gop-size") },
+    EncoderParams { factory_name: "avenc_h264", bitrate: Some(("bitrate", 1000)), speed_preset: None, key_int_max: Some("gop-size") },
+    EncoderParams { factory_name: "vp8enc", bitrate: Some(("target-bitrate", 1000)), speed_preset: None, key_int_max: Some("keyframe-max-dist") },
+    EncoderParams { factory_name: "vp9enc", bitrate: Some(("target-bitrate", 1000)), speed_preset: None, key_int_max: Some("keyframe-max-dist") },
+    EncoderParams { factory_name: "avenc_aac", bitrate: Some(("bitrate", 1000)), speed_preset: None, key_int_max: None },
+    EncoderParams { factory_name: "fdkaacenc", bitrate: Some(("bitrate", 1000)), speed_preset: None, key_int_max: None },
+    EncoderParams { factory_name: "voaacenc", bitrate: Some(("bitrate", 1000)), speed_preset: None, key_int_max: None },
+    EncoderParams { factory_name: "opusenc", bitrate: Some(("bitrate", 1000)), speed_preset: None, key_int_max: None },
+    EncoderParams { factory_name: "vorbisenc", bitrate: Some(("bitrate", 1000)), speed_preset: None, key_int_max: None },
+    EncoderParams { factory_name: "lamemp3enc", bitrate: Some(("bitrate", 1)), speed_preset: None, key_int_max: None },
+    EncoderParams { factory_name: "flacenc", bitrate: None, speed_preset: None, key_int_max: None },
+];
+
+impl Profile {
+    pub fn find(name: &str) -> Result<&'static Self, glib::BoolError> {
+        PROFILES.iter().find(|profile| profile.name == name).ok_or_else(|| {
+            let names = PROFILES.iter().map(|profile| profile.name).collect::<Vec<_>>();
+            glib::bool_error!("Unknown profile: {} (available: {})", name, names.join(", "))
+        })
+    }
+}
+
+impl VideoProfile {
+    /// encoder が入っていなければ fallback chain の encoder を使い、その encoder にないパラメータは捨てる
+    pub fn encoding(&self) -> Result<Encoding, glib::BoolError> {
+        let factory_name = encoder::find_available_encoder(self.encoder)?;
+        let fallback = factory_name != self.encoder;
+        let params = encoder_params(&factory_name)?;
+        let mut encoder = EncoderSpec { factory_name, properties: Vec::new() };
+
+        if let Some(bitrate) = self.bitrate {
+            match params.bitrate {
+                Some((key, scale)) => encoder.set_property(key, &(bitrate * scale).to_string()),
+                None => unsupported(&encoder.factory_name, "bitrate", fallback)?,
+            }
+        }
+        if let Some(speed_preset) = self.speed_preset {
+            match params.speed_preset {
+                Some(key) => encoder.set_property(key, speed_preset),
+                None => unsupported(&encoder.factory_name, "speed preset", fallback)?,
+            }
+        }
+        if let Some(key_int_max) = self.key_int_max {
+            match params.key_int_max {
+                Some(key) => encoder.set_property(key, &key_int_max.to_string()),
+                None => unsupported(&encoder.factory_name, "key-int-max", fallback)?,
+            }
+        }
+
+        let raw_caps = (self.width.is_some() || self.height.is_some() || self.framerate.is_some()).then(|| {
+            let mut builder = gst::Caps::builder("video/x-raw");
+            if let Some(width) = self.width {
+                builder = builder.field("width", width);
+            }
+            if let Some(height) = self.height {
+                builder = builder.field("height", height);
+            }
+            if let Some((numer, denom)) = self.framerate {
+                builder = builder.field("framerate", gst::Fraction::new(numer, denom));
+            }
+            builder.build()
+        });
+
+        let encoded_caps = encoded_caps(&encoder, self.profile, self.level)?;
+
+        Ok(Encoding { encoder, raw_caps, encoded_caps })
+    }
+}
+
+impl AudioProfile {
+    pub fn encoding(&self) -> Result<Encoding, glib::BoolError> {
+        let factory_name = encoder::find_available_encoder(self.encoder)?;
+        let fallback = factory_name != self.encoder;
+        let params = encoder_params(&factory_name)?;
+        let mut encoder = EncoderSpec { factory_name, properties: Vec::new() };
+
+        if let Some(bitrate) = self.bitrate {
+            match params.bitrate {
+                Some((key, scale)) => encoder.set_property(key, &(bitrate * scale).to_string()),
+                None => unsupported(&encoder.factory_name, "bitrate", fallback)?,
+            }
+        }
+
+        let raw_caps = (self.sample_rate.is_some() || self.channels.is_some()).then(|| {
+            let mut builder = gst::Caps::builder("audio/x-raw");
+            if let Some(sample_rate) = self.sample_rate {
+                builder = builder.field("rate", sample_rate);
+            }
+            if let Some(channels) = self.channels {
+                builder = builder.field("channels", channels);
+            }
+            builder.build()
+        });
+
+        Ok(Encoding { encoder, raw_caps, encoded_caps: None })
+    }
+}
+
+/// profile, level は encoder のプロパティではなく src caps で指定する
+pub(crate) fn encoded_caps(encoder: &EncoderSpec, profile: Option<&str>, level: Option<&str>) -> Result<Option<gst::Caps>, glib::BoolError> {
+    if profile.is_none() && level.is_none() {
+        return Ok(None);
+    }
+
+    let template_caps = encoder.src_template_caps()?;
+    let media_type = template_caps.structure(0)
+        .ok_or_else(|| glib::bool_error!("{} has empty src caps", encoder.factory_name))?
+        .name()
+        .to_string();
+    let mut builder = gst::Caps::builder(media_type.as_str());
+    if let Some(profile) = profile {
+        builder = builder.field("profile", profile);
+    }
+    if let Some(level) = level {
+        builder = builder.field("level", level);
+    }
+
+    Ok(Some(builder.build()))
+}
+
+/// profile で指定した encoder のパラメータがない。 fallback した encoder なら捨てて続ける
+fn unsupported(factory_name: &str, param_name: &str, fallback: bool) -> Result<(), glib::BoolError> {
+    if !fallback {
+        return Err(glib::bool_error!("{} does not support {}", factory_name, param_name));
+    }
+    log::warn!("{} does not support {}, ignore it", factory_name, param_name);
+    Ok(())
+}
+
+/// fallback した encoder に合わせてプロパティの名前と値を変換する
+///
+/// どちらかの encoder のパラメータがわからないか、変換するプロパティでなければ None
+pub(crate) fn translate_property(from_factory_name: &str, to_factory_name: &str, key: &str, value: &str) -> Option<(String, String)> {
+    let from = encoder_params(from_factory_name).ok()?;
+    let to = encoder_params(to_factory_name).ok()?;
+
+    if let (Some((from_key, from_scale)), Some((to_key, to_scale))) = (from.bitrate, to.bitrate) {
+        if key == from_key {
+            // bit/s から kbit/s にするときに切り捨てないように、先に掛けてから四捨五入する
+            let value = value.parse::<u64>().ok()?;
+            let (from_scale, to_scale) = (from_scale as u64, to_scale as u64);
+            let converted = (value * to_scale + from_scale / 2) / from_scale;
+            return Some((to_key.to_string(), converted.to_string()));
+        }
+    }
+    if let (Some(from_key), Some(to_key)) = (from.key_int_max, to.key_int_max) {
+        if key == from_key {
+            return Some((to_key.to_string(), value.to_string()));
+        }
+    }
+    if let (Some(from_key), Some(to_key)) = (from.speed_preset, to.speed_preset) {
+        if key == from_key {
+            return Some((to_key.to_string(), value.to_string()));
+        }
+    }
+
+    None
+}
+
+fn encoder_params(factory_name: &str) -> Result<&'static EncoderParams, glib::BoolError> {
+    ENCODER_PARAMS.iter()
+        .find(|params| params.factory_name == factory_name)
+        .ok_or_else(|| glib::bool_error!("No typed parameters are known for {}", factory_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_bitrate_with_rounding() {
+        assert_eq!(translate_property("x264enc", "openh264enc", "bitrate", "2500"), Some(("bitrate".to_string(), "2500000".to_string())));
+        assert_eq!(translate_property("openh264enc", "x264enc", "bitrate", "2500000"), Some(("bitrate".to_string(), "2500".to_string())));
+        assert_eq!(translate_property("openh264enc", "x264enc", "bitrate", "1500"), Some(("bitrate".to_string(), "2".to_string())));
+        assert_eq!(translate_property("openh264enc", "x264enc", "bitrate", "1499"), Some(("bitrate".to_string(), "1".to_string())));
+    }
+}
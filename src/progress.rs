@@ -0,0 +1,288 @@
+use std::{io::Write, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+
+use crate::{probe::StreamKind, transcode::Transcode};
+
+/// 進捗を出す間隔のデフォルト
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// clock から bus に流す、進捗を出すタイミングを知らせる application message の名前
+const PROGRESS_TICK_MESSAGE: &str = "progress-tick";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// pipeline を PLAYING にするまで (trim の seek を含む)
+    Preparing,
+    Transcoding,
+    /// EOS を送って、 mux が出力を書き終えるのを待っている
+    Finalizing,
+    Done,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Preparing => "preparing",
+            Self::Transcoding => "transcoding",
+            Self::Finalizing => "finalizing",
+            Self::Done => "done",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProgressEvent {
+    pub stage: Stage,
+    /// two-pass encode のときの何 pass 目か
+    pub pass: Option<u32>,
+    /// 出力に書いた長さ (trim しているときは --start からの長さ)
+    pub position: Option<gst::ClockTime>,
+    /// 出力の予定の長さ
+    pub duration: Option<gst::ClockTime>,
+    pub percent: Option<f64>,
+    /// 前回の event から今回までに mux に入った映像のフレーム数から計算した fps
+    pub fps: Option<f64>,
+    pub eta: Option<Duration>,
+    pub bytes_written: Option<u64>,
+}
+
+/// 進捗を受け取る
+pub trait ProgressListener: Send {
+    fn on_progress(&mut self, event: &ProgressEvent);
+}
+
+/// 人が読むための 1 行の表示
+pub struct TextListener;
+
+impl ProgressListener for TextListener {
+    fn on_progress(&mut self, event: &ProgressEvent) {
+        let percent = event.percent.map_or_else(|| "-".to_string(), |percent| format!("{:.1}%", percent));
+        let fps = event.fps.map_or_else(|| "-".to_string(), |fps| format!("{:.1}", fps));
+        let eta = event.eta.map_or_else(|| "-".to_string(), |eta| format!("{}s", eta.as_secs()));
+        let pass = event.pass.map_or_else(String::new, |pass| format!(" pass {}", pass));
+        println!(
+            "[{}{}] Position: {} / {} ({}) fps: {} ETA: {} Written: {} bytes",
+            event.stage.name(),
+            pass,
+            event.position.display(),
+            event.duration.display(),
+            percent,
+            fps,
+            eta,
+            event.bytes_written.unwrap_or(0),
+        );
+    }
+}
+
+/// job runner が読むための JSON lines
+///
+/// `{"stage":"transcoding","pass":null,"position":12.5,"duration":60.0,"percent":20.8,"fps":87.3,"eta":31.2,"bytes_written":1048576}`
+/// 時間は秒、わからない値は null
+pub struct JsonLinesListener<W: Write + Send> {
+    writer: W,
+}
+
+impl<W: Write + Send> JsonLinesListener<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write + Send> ProgressListener for JsonLinesListener<W> {
+    fn on_progress(&mut self, event: &ProgressEvent) {
+        let seconds = |time: Option<gst::ClockTime>| time.map(|time| time.nseconds() as f64 / 1_000_000_000.0);
+        let line = serde_json::json!({
+            "stage": event.stage.name(),
+            "pass": event.pass,
+            "position": seconds(event.position),
+            "duration": seconds(event.duration),
+            "percent": event.percent,
+            "fps": event.fps,
+            "eta": event.eta.map(|eta| eta.as_secs_f64()),
+            "bytes_written": event.bytes_written,
+        });
+        // 読む側がいなくなっても transcode は続ける
+        if writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush()).is_err() {
+            log::warn!("Failed to write progress event");
+        }
+    }
+}
+
+/// bus の message から進捗を計算して listener に渡す
+///
+/// system clock の periodic clock id で一定間隔に bus へ message を流すので、
+/// 別のスレッドで sleep しながら pipeline を query しなくてよい
+/// bus の message loop で `handle_message` を呼ぶこと
+pub struct ProgressReporter {
+    pipeline: gst::Pipeline,
+    /// `into_listener` で返すまで Some
+    listener: Option<Box<dyn ProgressListener>>,
+    stage: Stage,
+    pass: Option<u32>,
+    /// trim しているときの --start
+    start: Option<gst::ClockTime>,
+    duration: Option<gst::ClockTime>,
+    frames: Arc<AtomicU64>,
+    last_frames: u64,
+    last_tick: Instant,
+    started_at: Option<Instant>,
+    clock_id: gst::PeriodicClockId,
+}
+
+impl ProgressReporter {
+    /// pipeline を PLAYING にする前に作ること (mux の pad が作られる前に frame を数え始めるため)
+    pub fn new(transcode: &Transcode, listener: Box<dyn ProgressListener>, interval: Duration) -> Result<Self, glib::BoolError> {
+        let config = &transcode.config;
+        let duration = if config.is_trimmed() {
+            let end = config.end.or(config.inputs[0].media_info.duration);
+            end.map(|end| end.saturating_sub(config.start.unwrap_or(gst::ClockTime::ZERO)))
+        } else {
+            // 複数の入力を繋げるときは長さの合計
+            config.inputs.iter().try_fold(gst::ClockTime::ZERO, |total, input| input.media_info.duration.map(|duration| total + duration))
+        };
+
+        // mux に入った映像の buffer を数える
+        // 複数の入力を繋げるときは mux の pad を先に作っているので、今ある pad にも probe をつける
+        let frames = Arc::new(AtomicU64::new(0));
+        let mux_el = transcode.pipeline.by_name("mux").ok_or_else(|| glib::bool_error!("Pipeline has no mux"))?;
+        for pad in mux_el.sink_pads() {
+            count_video_frames(&pad, frames.clone());
+        }
+        {
+            let frames = frames.clone();
+            mux_el.connect_pad_added(move |_, pad| {
+                if pad.direction() == gst::PadDirection::Sink {
+                    count_video_frames(pad, frames.clone());
+                }
+            });
+        }
+
+        let clock = gst::SystemClock::obtain();
+        let interval = gst::ClockTime::from_nseconds(interval.as_nanos() as u64);
+        let first_tick = clock.time().expect("system clock must have time") + interval;
+        let clock_id = clock.new_periodic_id(first_tick, interval);
+        let bus = transcode.pipeline.bus().expect("pipeline must have a bus");
+        let pipeline_weak = transcode.pipeline.downgrade();
+        clock_id.wait_async(move |_, _, _| {
+            let Some(pipeline) = pipeline_weak.upgrade() else {
+                return;
+            };
+            let structure = gst::Structure::new_empty(PROGRESS_TICK_MESSAGE);
+            let _ = bus.post(gst::message::Application::builder(structure).src(&pipeline).build());
+        }).map_err(|err| glib::bool_error!("Failed to schedule progress updates: {:?}", err))?;
+
+        Ok(Self {
+            pipeline: transcode.pipeline.clone(),
+            listener: Some(listener),
+            stage: Stage::Preparing,
+            pass: None,
+            start: config.is_trimmed().then(|| config.start.unwrap_or(gst::ClockTime::ZERO)),
+            duration,
+            frames,
+            last_frames: 0,
+            last_tick: Instant::now(),
+            started_at: None,
+            clock_id,
+        })
+    }
+
+    /// two-pass encode のときは pass ごとに reporter を作って、何 pass 目かを event につける
+    pub fn with_pass(mut self, pass: u32) -> Self {
+        self.pass = Some(pass);
+        self
+    }
+
+    /// two-pass encode で次の pass に同じ listener を使うために返す
+    pub fn into_listener(mut self) -> Box<dyn ProgressListener> {
+        self.listener.take().expect("listener must be taken only once")
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// stage が変わったときはすぐに event を出す
+    pub fn set_stage(&mut self, stage: Stage) {
+        if self.stage == stage {
+            return;
+        }
+        self.stage = stage;
+        if stage == Stage::Transcoding {
+            self.started_at = Some(Instant::now());
+        }
+        self.report();
+    }
+
+    /// bus から取り出した message を全部渡す。進捗に関係ない message は無視する
+    pub fn handle_message(&mut self, msg: &gst::Message) {
+        match msg.view() {
+            gst::MessageView::Application(application) if application.structure().map_or(false, |s| s.name() == PROGRESS_TICK_MESSAGE) => {
+                if self.stage != Stage::Preparing {
+                    self.report();
+                }
+            },
+            gst::MessageView::StateChanged(state_changed) if msg.src() == Some(self.pipeline.upcast_ref::<gst::Object>()) => {
+                if state_changed.current() == gst::State::Playing && self.stage == Stage::Preparing {
+                    self.set_stage(Stage::Transcoding);
+                }
+            },
+            gst::MessageView::Eos(..) => self.set_stage(Stage::Done),
+            _ => (),
+        }
+    }
+
+    fn report(&mut self) {
+        let position = self.pipeline.query_position::<gst::ClockTime>()
+            .map(|position| position.saturating_sub(self.start.unwrap_or(gst::ClockTime::ZERO)));
+        let percent = match (position, self.duration) {
+            (Some(position), Some(duration)) if duration > gst::ClockTime::ZERO => {
+                Some((position.nseconds() as f64 / duration.nseconds() as f64 * 100.0).min(100.0))
+            },
+            _ => None,
+        };
+
+        let now = Instant::now();
+        let frames = self.frames.load(Ordering::Relaxed);
+        let elapsed_since_tick = now.duration_since(self.last_tick).as_secs_f64();
+        let fps = (self.last_frames < frames && elapsed_since_tick > 0.0)
+            .then(|| (frames - self.last_frames) as f64 / elapsed_since_tick);
+        self.last_frames = frames;
+        self.last_tick = now;
+
+        // 始めてからの平均の速さで残りの時間を見積もる
+        let eta = match (self.started_at, percent) {
+            (Some(started_at), Some(percent)) if percent > 0.0 && self.stage == Stage::Transcoding => {
+                let elapsed = now.duration_since(started_at).as_secs_f64();
+                Some(Duration::from_secs_f64(elapsed * (100.0 - percent) / percent))
+            },
+            _ => None,
+        };
+
+        // filesink は書いた byte 数を position で返す
+        let bytes_written = self.pipeline.by_name("sink")
+            .and_then(|sink_el| sink_el.query_position::<gst::format::Bytes>())
+            .map(|bytes| *bytes);
+
+        let event = ProgressEvent { stage: self.stage, pass: self.pass, position, duration: self.duration, percent, fps, eta, bytes_written };
+        if let Some(listener) = &mut self.listener {
+            listener.on_progress(&event);
+        }
+    }
+}
+
+fn count_video_frames(pad: &gst::Pad, frames: Arc<AtomicU64>) {
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, _| {
+        if pad.current_caps().and_then(|caps| StreamKind::from_caps(&caps)) == Some(StreamKind::Video) {
+            frames.fetch_add(1, Ordering::Relaxed);
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        self.clock_id.unschedule();
+    }
+}
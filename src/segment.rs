@@ -0,0 +1,66 @@
+use std::{fs, path::Path};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+
+/// HLS/DASH の segment の長さのデフォルト
+pub const DEFAULT_SEGMENT_DURATION: gst::ClockTime = gst::ClockTime::from_seconds(6);
+
+pub const HLS_PLAYLIST_NAME: &str = "playlist.m3u8";
+pub const DASH_MANIFEST_NAME: &str = "manifest.mpd";
+
+/// segment の sink の target-duration は秒単位の整数なので、 1 秒以上の整数秒だけ受け付ける
+pub fn check_segment_duration(segment_duration: gst::ClockTime) -> Result<(), glib::BoolError> {
+    if segment_duration < gst::ClockTime::SECOND || segment_duration.nseconds() % gst::ClockTime::SECOND.nseconds() != 0 {
+        return Err(glib::bool_error!("Segment duration must be a whole number of seconds: {}", segment_duration));
+    }
+
+    Ok(())
+}
+
+/// segment を書く sink に出力先のディレクトリと segment の長さを設定する
+///
+/// どの sink も segment の長さごとに上流の encoder に force-key-unit を送るので、
+/// segment は必ず keyframe から始まる
+/// (stream copy だと keyframe を作れないので、 segment の出力では copy を使わない)
+pub(crate) fn configure_segment_sink(sink_el: &gst::Element, directory: &Path, segment_duration: gst::ClockTime) -> Result<(), glib::BoolError> {
+    fs::create_dir_all(directory)
+        .map_err(|err| glib::bool_error!("Failed to create output directory {}: {}", directory.display(), err))?;
+
+    let factory_name = sink_el.factory().map(|factory| factory.name().to_string()).unwrap_or_default();
+    let target_duration = segment_duration.seconds() as u32;
+    let location = |file_name: &str| directory.join(file_name).to_string_lossy().into_owned();
+
+    match factory_name.as_str() {
+        // mpegtsmux で TS の segment を書く
+        "hlssink2" => {
+            sink_el.set_property("location", location("segment%05d.ts"));
+            sink_el.set_property("playlist-location", location(HLS_PLAYLIST_NAME));
+            sink_el.set_property("target-duration", target_duration);
+            // 0 にすると全部の segment を残して、 playlist にも全部書く (VOD)
+            sink_el.set_property("max-files", 0u32);
+            sink_el.set_property("playlist-length", 0u32);
+        },
+        // cmafmux で init segment と fMP4 の segment を書く。 CMAF なので stream は一つだけ
+        "hlscmafsink" => {
+            sink_el.set_property("init-location", location("init%05d.mp4"));
+            sink_el.set_property("location", location("segment%05d.m4s"));
+            sink_el.set_property("playlist-location", location(HLS_PLAYLIST_NAME));
+            sink_el.set_property("target-duration", target_duration);
+            sink_el.set_property("max-files", 0u32);
+            sink_el.set_property("playlist-length", 0u32);
+            sink_el.set_property_from_str("playlist-type", "vod");
+        },
+        // stream ごとに fMP4 の segment を書いて、 MPD にまとめる
+        "dashsink" => {
+            sink_el.set_property("mpd-root-path", directory.to_string_lossy().into_owned());
+            sink_el.set_property("mpd-filename", DASH_MANIFEST_NAME);
+            sink_el.set_property("target-duration", target_duration);
+            sink_el.set_property_from_str("muxer", "mp4");
+            sink_el.set_property("dynamic", false);
+        },
+        _ => return Err(glib::bool_error!("{} is not a segment sink", factory_name)),
+    }
+
+    Ok(())
+}
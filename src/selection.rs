@@ -0,0 +1,173 @@
+use std::fmt;
+
+use gstreamer as gst;
+use gst::glib;
+use gstreamer_tag as gst_tag;
+
+use crate::probe::{StreamInfo, StreamKind};
+
+/// `--map 0`, `--map 0:a`, `--map 0:a:1` のような入力 stream の指定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamMap {
+    pub input_index: usize,
+    pub kind: Option<StreamKind>,
+    pub index: Option<usize>,
+}
+
+impl StreamMap {
+    pub fn parse(spec: &str) -> Result<Self, glib::BoolError> {
+        let invalid = || glib::bool_error!("Invalid stream map `{}`, expected <input>[:<v|a|s>[:<index>]]", spec);
+
+        let mut parts = spec.split(':');
+        let input_index = parts.next().and_then(|part| part.parse().ok()).ok_or_else(invalid)?;
+        let kind = match parts.next() {
+            None => None,
+            Some("v") => Some(StreamKind::Video),
+            Some("a") => Some(StreamKind::Audio),
+            Some("s") => Some(StreamKind::Subtitle),
+            Some(_) => return Err(invalid()),
+        };
+        let index = match parts.next() {
+            None => None,
+            Some(part) => Some(part.parse().map_err(|_| invalid())?),
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self { input_index, kind, index })
+    }
+
+    pub fn matches(&self, input_index: usize, kind: StreamKind, index: usize) -> bool {
+        self.input_index == input_index
+            && self.kind.map_or(true, |map_kind| map_kind == kind)
+            && self.index.map_or(true, |map_index| map_index == index)
+    }
+}
+
+/// `parse` で読める形で書く
+impl fmt::Display for StreamMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.input_index)?;
+        if let Some(kind) = self.kind {
+            write!(f, ":{}", kind.selector())?;
+            if let Some(index) = self.index {
+                write!(f, ":{}", index)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// どの stream を出力に含めるか
+///
+/// 何も指定しなければ demuxer が出してきた stream を全部使う
+#[derive(Debug, Clone, Default)]
+pub struct StreamSelection {
+    /// 指定があれば、いずれかにマッチした stream だけを使う
+    pub maps: Vec<StreamMap>,
+    /// 指定があれば、その言語の音声だけを使う (入力の language-code tag と同じ表記で指定する)
+    pub audio_language: Option<String>,
+    /// 指定があれば、その言語の字幕だけを使う
+    pub subtitle_language: Option<String>,
+}
+
+impl StreamSelection {
+    /// stream の情報が Discoverer で取れなかったときは、言語の条件は満たさないものとして扱う
+    pub fn is_selected(&self, input_index: usize, kind: StreamKind, index: usize, stream: Option<&StreamInfo>) -> bool {
+        if !self.maps.is_empty() && !self.maps.iter().any(|map| map.matches(input_index, kind, index)) {
+            return false;
+        }
+
+        let language = match kind {
+            StreamKind::Video => None,
+            StreamKind::Audio => self.audio_language.as_deref(),
+            StreamKind::Subtitle => self.subtitle_language.as_deref(),
+        };
+        match language {
+            Some(language) => stream
+                .and_then(|stream| stream.language.as_deref())
+                .map_or(false, |stream_language| same_language(stream_language, language)),
+            None => true,
+        }
+    }
+}
+
+/// qtdemux は "jpn" (ISO 639-2) 、 matroskademux は "ja" (ISO 639-1) のように demuxer で書き方が違うので、
+/// 両方を ISO 639-1 にしてから比べる。 639-1 にない言語はそのまま比べる
+fn same_language(a: &str, b: &str) -> bool {
+    let normalize = |code: &str| {
+        let code = code.to_ascii_lowercase();
+        gst_tag::language_codes::language_code_iso_639_1(&code).map_or(code, |code| code.to_string())
+    };
+    normalize(a) == normalize(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stream_map() {
+        let map = StreamMap::parse("0:v").unwrap();
+        assert_eq!(map, StreamMap { input_index: 0, kind: Some(StreamKind::Video), index: None });
+        assert_eq!(map.to_string(), "0:v");
+
+        let map = StreamMap::parse("1:a:2").unwrap();
+        assert_eq!(map, StreamMap { input_index: 1, kind: Some(StreamKind::Audio), index: Some(2) });
+        assert_eq!(map.to_string(), "1:a:2");
+
+        let map = StreamMap::parse("3").unwrap();
+        assert_eq!(map, StreamMap { input_index: 3, kind: None, index: None });
+        assert_eq!(map.to_string(), "3");
+    }
+
+    #[test]
+    fn rejects_invalid_stream_map() {
+        assert!(StreamMap::parse("0:x").is_err());
+        assert!(StreamMap::parse("0:a:x").is_err());
+        assert!(StreamMap::parse("0:a:1:2").is_err());
+        assert!(StreamMap::parse(":v").is_err());
+        assert!(StreamMap::parse("v").is_err());
+        assert!(StreamMap::parse("").is_err());
+    }
+
+    fn audio_stream(language: &str) -> StreamInfo {
+        StreamInfo {
+            kind: StreamKind::Audio,
+            index: 0,
+            stream_id: None,
+            caps: None,
+            language: Some(language.to_string()),
+            width: None,
+            height: None,
+            framerate: None,
+            sample_rate: None,
+            channels: None,
+        }
+    }
+
+    fn selects_audio(audio_language: &str, stream_language: &str) -> bool {
+        let selection = StreamSelection { audio_language: Some(audio_language.to_string()), ..Default::default() };
+        selection.is_selected(0, StreamKind::Audio, 0, Some(&audio_stream(stream_language)))
+    }
+
+    #[test]
+    fn matches_language_in_both_spellings() {
+        gst::init().unwrap();
+        // mp4 (qtdemux) は ISO 639-2 、 mkv (matroskademux) は ISO 639-1
+        assert!(selects_audio("jpn", "jpn"));
+        assert!(selects_audio("jpn", "ja"));
+        assert!(selects_audio("ja", "jpn"));
+        assert!(selects_audio("JPN", "ja"));
+        assert!(!selects_audio("jpn", "en"));
+        assert!(!selects_audio("jpn", "eng"));
+    }
+
+    #[test]
+    fn skips_stream_without_language() {
+        let selection = StreamSelection { audio_language: Some("jpn".to_string()), ..Default::default() };
+        assert!(!selection.is_selected(0, StreamKind::Audio, 0, None));
+        assert!(selection.is_selected(0, StreamKind::Video, 0, None));
+    }
+}
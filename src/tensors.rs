@@ -0,0 +1,405 @@
+use std::{fs::{self, File}, io::{self, BufWriter, Seek, SeekFrom, Write}, path::PathBuf};
+
+use gstreamer as gst;
+use gst::glib;
+use gstreamer_video as gst_video;
+
+use crate::{error::{Error, Result}, extract::{self, SampleWriter}, npy, probe::StreamKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TensorFormat {
+    /// batch ごとに `batch_000000.npy` (フレーム) と `batch_000000_pts.npy` (PTS) を書く
+    Npy,
+    /// batch ごとに `frames` と `pts` の二つの配列を `batch_000000.npz` に書く
+    /// zip の header に CRC と長さを先に書くので、 batch を一つメモリに溜めてから書く。 batch_size の指定が要る
+    Npz,
+}
+
+impl TensorFormat {
+    pub fn find(name: &str) -> std::result::Result<Self, glib::BoolError> {
+        match name.to_ascii_lowercase().as_str() {
+            "npy" => Ok(Self::Npy),
+            "npz" => Ok(Self::Npz),
+            _ => Err(glib::bool_error!("Unsupported tensor format: {} (supported: npy, npz)", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Rgb,
+    Bgr,
+    Gray,
+}
+
+impl ColorSpace {
+    pub fn find(name: &str) -> std::result::Result<Self, glib::BoolError> {
+        match name.to_ascii_lowercase().as_str() {
+            "rgb" => Ok(Self::Rgb),
+            "bgr" => Ok(Self::Bgr),
+            "gray" => Ok(Self::Gray),
+            _ => Err(glib::bool_error!("Unsupported color space: {} (supported: rgb, bgr, gray)", name)),
+        }
+    }
+
+    fn video_format(&self) -> gst_video::VideoFormat {
+        match self {
+            Self::Rgb => gst_video::VideoFormat::Rgb,
+            Self::Bgr => gst_video::VideoFormat::Bgr,
+            Self::Gray => gst_video::VideoFormat::Gray8,
+        }
+    }
+
+    /// 配列の最後の次元 (C) の大きさ
+    pub fn channels(&self) -> usize {
+        match self {
+            Self::Rgb | Self::Bgr => 3,
+            Self::Gray => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TensorExportConfig {
+    pub path: PathBuf,
+    /// 書き出す先のディレクトリ。なければ作る
+    pub output_dir: PathBuf,
+    pub format: TensorFormat,
+    pub color_space: ColorSpace,
+    /// 指定があれば videoscale で合わせる。片方だけなら縦横比を保つ
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// decode したフレームのうち、この数ごとに一つ使う (1 なら全部)
+    pub frame_stride: usize,
+    /// 一つのファイルに入れるフレームの数。 None なら全部を一つのファイルに入れる (npy のときだけ)
+    pub batch_size: Option<usize>,
+    /// 何番目の映像の stream を書き出すか
+    pub stream_index: usize,
+    pub diagnose_caps: bool,
+}
+
+impl TensorExportConfig {
+    pub fn new(path: impl Into<PathBuf>, output_dir: impl Into<PathBuf>, format: TensorFormat) -> Self {
+        Self {
+            path: path.into(),
+            output_dir: output_dir.into(),
+            format,
+            color_space: ColorSpace::Rgb,
+            width: None,
+            height: None,
+            frame_stride: 1,
+            batch_size: None,
+            stream_index: 0,
+            diagnose_caps: false,
+        }
+    }
+}
+
+/// 書き出した一つのファイル (npy なら PTS の配列のファイルも)
+#[derive(Debug, Clone)]
+pub struct TensorBatch {
+    pub path: PathBuf,
+    /// 配列の (H, W, C)
+    pub shape: (usize, usize, usize),
+    /// 配列の N 番目のフレームの PTS
+    pub pts: Vec<gst::ClockTime>,
+}
+
+/// 入力の映像を decode して、 (N, H, W, C) の uint8 の配列を書き出す
+///
+/// appsink の caps で色空間と解像度を指定するので、変換がいらなければ videoconvert と videoscale は passthrough になり、
+/// decoder が出した buffer をそのままファイルに書く
+pub fn export_tensors(config: &TensorExportConfig) -> Result<Vec<TensorBatch>> {
+    if config.frame_stride == 0 || config.batch_size == Some(0) {
+        return Err(Error::Gst(glib::bool_error!("Frame stride and batch size must be greater than zero")));
+    }
+    // npz は batch をメモリに溜めるので、動画全体を一つの batch にはできない
+    if config.format == TensorFormat::Npz && config.batch_size.is_none() {
+        return Err(Error::Gst(glib::bool_error!("Batch size is required for npz output")));
+    }
+    // 解像度が決まっていれば、 decode する前に npz の大きさを確かめる
+    if let (Some(width), Some(height), Some(batch_size)) = (config.width, config.height, config.batch_size) {
+        if config.format == TensorFormat::Npz {
+            check_npz_len((height as usize, width as usize, config.color_space.channels()), batch_size)?;
+        }
+    }
+    fs::create_dir_all(&config.output_dir)?;
+
+    let mut caps_builder = gst_video::VideoCapsBuilder::new().format(config.color_space.video_format());
+    if let Some(width) = config.width {
+        caps_builder = caps_builder.width(width as i32);
+    }
+    if let Some(height) = config.height {
+        caps_builder = caps_builder.height(height as i32);
+    }
+    // 配列の一つの要素が正方形の画素になるようにする
+    let raw_caps = caps_builder.pixel_aspect_ratio(gst::Fraction::new(1, 1)).build();
+
+    let writer = extract::decode_to_writer(&config.path, StreamKind::Video, config.stream_index, &raw_caps, config.diagnose_caps, TensorWriter::new(config))?;
+    writer.finish()
+}
+
+/// appsink から来たフレームを batch ごとのファイルに振り分けて書く
+struct TensorWriter {
+    output_dir: PathBuf,
+    format: TensorFormat,
+    channels: usize,
+    frame_stride: usize,
+    batch_size: Option<usize>,
+    /// 最初のフレームの caps から決める (H, W, C)
+    shape: Option<(usize, usize, usize)>,
+    /// frame_stride で間引く前の、書き出せるフレーム (PTS のあるもの) の数
+    counted: u64,
+    current: Option<Batch>,
+    batches: Vec<TensorBatch>,
+}
+
+/// 書いている途中の batch
+struct Batch {
+    path: PathBuf,
+    frames: BatchFrames,
+    pts: Vec<gst::ClockTime>,
+}
+
+enum BatchFrames {
+    /// header は閉じるときに N を入れて書き直す
+    Npy(BufWriter<File>),
+    Npz(Vec<u8>),
+}
+
+impl TensorWriter {
+    fn new(config: &TensorExportConfig) -> Self {
+        Self {
+            output_dir: config.output_dir.clone(),
+            format: config.format,
+            channels: config.color_space.channels(),
+            frame_stride: config.frame_stride,
+            batch_size: config.batch_size,
+            shape: None,
+            counted: 0,
+            current: None,
+            batches: Vec::new(),
+        }
+    }
+
+    fn open_batch(&self, shape: (usize, usize, usize)) -> Result<Batch> {
+        let index = self.batches.len();
+        let (path, frames) = match self.format {
+            TensorFormat::Npy => {
+                let path = self.output_dir.join(format!("batch_{:06}.npy", index));
+                let mut writer = BufWriter::new(File::create(&path)?);
+                npy::write_header(&mut writer, "|u1", &frames_shape(0, shape))?;
+                (path, BatchFrames::Npy(writer))
+            },
+            TensorFormat::Npz => {
+                let batch_size = self.batch_size.ok_or_else(|| glib::bool_error!("Batch size is required for npz output"))?;
+                // 最初のフレームで解像度がわかったところで、 batch を溜め始める前に確かめる
+                check_npz_len(shape, batch_size)?;
+                let data = Vec::with_capacity(shape.0 * shape.1 * shape.2 * batch_size);
+                (self.output_dir.join(format!("batch_{:06}.npz", index)), BatchFrames::Npz(data))
+            },
+        };
+
+        Ok(Batch { path, frames, pts: Vec::new() })
+    }
+
+    fn close_batch(&mut self) -> Result<()> {
+        let Some(batch) = self.current.take() else {
+            return Ok(());
+        };
+        let shape = self.shape.expect("shape must be known when a batch is open");
+        let frames_shape = frames_shape(batch.pts.len(), shape);
+        // numpy で扱いやすいように PTS は int64 のナノ秒にする
+        let pts_data = batch.pts.iter().flat_map(|pts| (pts.nseconds() as i64).to_le_bytes()).collect::<Vec<_>>();
+        let pts_shape = [batch.pts.len() as u64];
+
+        match batch.frames {
+            BatchFrames::Npy(mut writer) => {
+                writer.seek(SeekFrom::Start(0))?;
+                npy::write_header(&mut writer, "|u1", &frames_shape)?;
+                writer.flush()?;
+
+                let pts_path = self.output_dir.join(format!("batch_{:06}_pts.npy", self.batches.len()));
+                let mut pts_writer = BufWriter::new(File::create(pts_path)?);
+                npy::write_header(&mut pts_writer, "<i8", &pts_shape)?;
+                pts_writer.write_all(&pts_data)?;
+                pts_writer.flush()?;
+            },
+            BatchFrames::Npz(data) => {
+                let frames_header = npy::header("|u1", &frames_shape)?;
+                let pts_header = npy::header("<i8", &pts_shape)?;
+                let mut writer = BufWriter::new(File::create(&batch.path)?);
+                npy::write_npz(&mut writer, &[
+                    npy::NpzEntry { name: "frames.npy", header: &frames_header, data: &data },
+                    npy::NpzEntry { name: "pts.npy", header: &pts_header, data: &pts_data },
+                ])?;
+                writer.flush()?;
+            },
+        }
+
+        log::debug!("Wrote {} frames to {}", batch.pts.len(), batch.path.display());
+        self.batches.push(TensorBatch { path: batch.path, shape, pts: batch.pts });
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<TensorBatch>> {
+        self.close_batch()?;
+        if self.shape.is_none() {
+            return Err(Error::Gst(glib::bool_error!("No video frame was decoded")));
+        }
+        Ok(self.batches)
+    }
+}
+
+impl SampleWriter for TensorWriter {
+    fn push(&mut self, sample: &gst::Sample) -> Result<()> {
+        let Some(buffer) = sample.buffer() else {
+            return Ok(());
+        };
+        let Some(pts) = buffer.pts() else {
+            log::warn!("Skip decoded frame without PTS after {} frames", self.counted);
+            return Ok(());
+        };
+        // 書き出せないフレームで間隔がずれないように、 PTS のあるフレームだけを数える
+        let index = self.counted;
+        self.counted += 1;
+        if index % self.frame_stride as u64 != 0 {
+            return Ok(());
+        }
+
+        let caps = sample.caps().ok_or_else(|| glib::bool_error!("Decoded frame has no caps"))?;
+        let info = gst_video::VideoInfo::from_caps(caps).map_err(|err| glib::bool_error!("Invalid video caps {}: {}", caps, err))?;
+
+        let shape = (info.height() as usize, info.width() as usize, self.channels);
+        match self.shape {
+            None => self.shape = Some(shape),
+            // 配列の途中で形は変えられない
+            Some(current) if current != shape => {
+                return Err(Error::Gst(glib::bool_error!(
+                    "Frame size changed from {}x{} to {}x{} at {}, specify the width and height", current.1, current.0, shape.1, shape.0, pts,
+                )));
+            },
+            Some(_) => (),
+        }
+
+        if self.current.is_none() {
+            self.current = Some(self.open_batch(shape)?);
+        }
+        let batch = self.current.as_mut().expect("current batch must be opened");
+
+        let frame = gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &info)
+            .map_err(|err| glib::bool_error!("Failed to map frame at {}: {}", pts, err))?;
+        let plane = frame.plane_data(0).map_err(|err| glib::bool_error!("Failed to read frame at {}: {}", pts, err))?;
+        let stride = frame.plane_stride()[0] as usize;
+        let row_len = shape.1 * shape.2;
+        match &mut batch.frames {
+            BatchFrames::Npy(writer) => write_rows(writer, plane, stride, row_len, shape.0)?,
+            BatchFrames::Npz(data) => write_rows(data, plane, stride, row_len, shape.0)?,
+        }
+        batch.pts.push(pts);
+
+        if self.batch_size == Some(batch.pts.len()) {
+            self.close_batch()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// 行の終わりに padding があれば (RGB で幅が 4 の倍数でないときなど) 除いて、 H x (W x C) に詰めて書く
+fn write_rows<W: Write>(writer: &mut W, plane: &[u8], stride: usize, row_len: usize, rows: usize) -> io::Result<()> {
+    if stride == row_len {
+        return writer.write_all(&plane[..row_len * rows]);
+    }
+    for row in plane.chunks(stride).take(rows) {
+        writer.write_all(&row[..row_len])?;
+    }
+    Ok(())
+}
+
+/// 一つの batch の npz が zip64 なしで書ける大きさか
+fn check_npz_len((height, width, channels): (usize, usize, usize), batch_size: usize) -> Result<()> {
+    // フレームと PTS (int64) の配列と、 .npy の header 二つ。 zip の header と central directory の分も余裕を見ておく
+    let len = (height as u64 * width as u64 * channels as u64 + 8)
+        .checked_mul(batch_size as u64)
+        .and_then(|len| len.checked_add(2 * npy::HEADER_LEN as u64 + 1024));
+    if len.map_or(true, |len| len > npy::NPZ_MAX_LEN) {
+        return Err(Error::Gst(glib::bool_error!(
+            "A batch of {} frames of {}x{}x{} does not fit in a 4 GiB npz, use a smaller batch size or npy", batch_size, height, width, channels,
+        )));
+    }
+    Ok(())
+}
+
+fn frames_shape(frames: usize, (height, width, channels): (usize, usize, usize)) -> [u64; 4] {
+    [frames as u64, height as u64, width as u64, channels as u64]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn write_rows_drops_stride_padding() {
+        // 幅 2 の RGB の行は 6 byte で、 8 byte に揃えてある
+        let plane = [1, 2, 3, 4, 5, 6, 0, 0, 7, 8, 9, 10, 11, 12, 0, 0];
+        let mut data = Vec::new();
+        write_rows(&mut data, &plane, 8, 6, 2).unwrap();
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+
+        let mut data = Vec::new();
+        write_rows(&mut data, &plane[..12], 6, 6, 2).unwrap();
+        assert_eq!(data, &plane[..12]);
+    }
+
+    #[test]
+    fn checks_npz_len_at_4gib_boundary() {
+        // 1080p の RGB は 690 フレームまで
+        assert!(check_npz_len((1080, 1920, 3), 690).is_ok());
+        assert!(check_npz_len((1080, 1920, 3), 691).is_err());
+
+        // 1 byte のフレームと 8 byte の PTS で、 header の分を除いてちょうど収まる数
+        let max_batch_size = ((npy::NPZ_MAX_LEN - 2 * npy::HEADER_LEN as u64 - 1024) / 9) as usize;
+        assert!(check_npz_len((1, 1, 1), max_batch_size).is_ok());
+        assert!(check_npz_len((1, 1, 1), max_batch_size + 1).is_err());
+    }
+
+    #[test]
+    fn splits_strided_frames_into_batches() {
+        gst::init().unwrap();
+
+        let output_dir = env::temp_dir().join(format!("learning-gstreamer-tensors-test-{}", process::id()));
+        fs::create_dir_all(&output_dir).unwrap();
+
+        let mut config = TensorExportConfig::new("input.mp4", &output_dir, TensorFormat::Npy);
+        config.color_space = ColorSpace::Gray;
+        config.frame_stride = 2;
+        config.batch_size = Some(2);
+        let mut writer = TensorWriter::new(&config);
+
+        // 幅 3 の Gray8 の行は 4 byte に揃う。 PTS の無いフレームは間隔に数えない
+        let info = gst_video::VideoInfo::builder(gst_video::VideoFormat::Gray8, 3, 2).build().unwrap();
+        let caps = info.to_caps().unwrap();
+        for pts in [Some(0), None, Some(40), Some(80), Some(120), Some(160), Some(200)] {
+            let mut buffer = gst::Buffer::from_mut_slice(vec![0u8; 8]);
+            buffer.get_mut().unwrap().set_pts(pts.map(gst::ClockTime::from_mseconds));
+            let sample = gst::Sample::builder().buffer(&buffer).caps(&caps).build();
+            writer.push(&sample).unwrap();
+        }
+        let batches = writer.finish().unwrap();
+
+        let summary = batches.iter()
+            .map(|batch| (batch.pts.iter().map(|pts| pts.mseconds()).collect::<Vec<_>>(), fs::metadata(&batch.path).unwrap().len()))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, [
+            (vec![0, 80], npy::HEADER_LEN as u64 + 2 * 6),
+            (vec![160], npy::HEADER_LEN as u64 + 6),
+        ]);
+        assert_eq!(batches[0].shape, (2, 3, 1));
+        assert!(output_dir.join("batch_000001_pts.npy").exists());
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use gstreamer as gst;
+use gst::glib;
+
+/// `90`, `1:30`, `00:01:30`, `00:01:30.5` のような時刻の指定
+///
+/// 先頭以外の分と秒は 60 未満で、小数は最後の秒にだけ書ける
+pub fn parse_clock_time(s: &str) -> Result<gst::ClockTime, glib::BoolError> {
+    let invalid = || glib::bool_error!("Invalid time `{}`, expected [[hh:]mm:]ss[.fraction]", s);
+
+    let parts = s.split(':').collect::<Vec<_>>();
+    if parts.len() > 3 {
+        return Err(invalid());
+    }
+    let (last, fields) = parts.split_last().ok_or_else(invalid)?;
+    let (whole, fraction) = match last.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (*last, None),
+    };
+
+    let mut seconds = 0u64;
+    for (index, field) in fields.iter().chain([&whole]).enumerate() {
+        let value = parse_digits(field).ok_or_else(invalid)?;
+        if index > 0 && value >= 60 {
+            return Err(invalid());
+        }
+        seconds = seconds.checked_mul(60).and_then(|seconds| seconds.checked_add(value)).ok_or_else(invalid)?;
+    }
+
+    // ns より細かいところは切り捨てる
+    let nseconds = match fraction {
+        None => 0,
+        Some(fraction) => {
+            parse_digits(fraction).ok_or_else(invalid)?;
+            fraction.bytes().take(9).chain(std::iter::repeat(b'0')).take(9)
+                .fold(0, |nseconds, digit| nseconds * 10 + (digit - b'0') as u64)
+        },
+    };
+
+    seconds.checked_mul(1_000_000_000).and_then(|total| total.checked_add(nseconds))
+        .map(gst::ClockTime::from_nseconds)
+        .ok_or_else(invalid)
+}
+
+/// `+` や `1e3` も受け付ける `str::parse` の代わりに、数字だけを読む
+fn parse_digits(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> gst::ClockTime {
+        gst::ClockTime::from_mseconds(ms)
+    }
+
+    #[test]
+    fn parses_each_form() {
+        assert_eq!(parse_clock_time("90").unwrap(), ms(90_000));
+        assert_eq!(parse_clock_time("1:30").unwrap(), ms(90_000));
+        assert_eq!(parse_clock_time("00:01:30").unwrap(), ms(90_000));
+        assert_eq!(parse_clock_time("00:01:30.5").unwrap(), ms(90_500));
+        assert_eq!(parse_clock_time("1:00:00").unwrap(), ms(3_600_000));
+        assert_eq!(parse_clock_time("0.25").unwrap(), ms(250));
+        assert_eq!(parse_clock_time("1.000000001").unwrap(), gst::ClockTime::from_nseconds(1_000_000_001));
+    }
+
+    #[test]
+    fn leading_field_is_not_limited() {
+        assert_eq!(parse_clock_time("90:00").unwrap(), ms(5_400_000));
+        assert_eq!(parse_clock_time("100:00:00").unwrap(), ms(360_000_000));
+    }
+
+    #[test]
+    fn rejects_out_of_range_fields() {
+        assert!(parse_clock_time("1:90").is_err());
+        assert!(parse_clock_time("1:60:00").is_err());
+        assert!(parse_clock_time("0:00:60.5").is_err());
+    }
+
+    #[test]
+    fn rejects_fraction_except_in_last_field() {
+        assert!(parse_clock_time("1.5:30").is_err());
+        assert!(parse_clock_time("1:1.5:30").is_err());
+    }
+
+    #[test]
+    fn rejects_non_digits() {
+        for s in ["", ":", "1:", ":30", "1e3", "+5", "-5", "inf", "NaN", "1.", ".5", "1:30:00:00", "1.5.5"] {
+            assert!(parse_clock_time(s).is_err(), "{}", s);
+        }
+    }
+}
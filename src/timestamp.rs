@@ -0,0 +1,380 @@
+use std::{fmt, sync::{Arc, Mutex}};
+
+use gstreamer as gst;
+use gst::prelude::*;
+
+/// この数のフレームで続けて直すことになったら、元の timestamp は使えないものとして framerate から作り直す
+/// 作り直している間に、元の timestamp がこの数のフレームで続けて正しければ元に戻す
+const HOPELESS_STREAK: u32 = 8;
+
+/// framerate がわからないときに DTS を単調増加にするためにずらす幅
+const MIN_DTS_STEP: gst::ClockTime = gst::ClockTime::from_useconds(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorrectionKind {
+    /// DTS がなかったので PTS か前のフレームから埋めた
+    ///
+    /// DTS をつけない demuxer はよくあるので、壊れているとはみなさない
+    MissingDts,
+    /// DTS が前のフレーム以下だったので後ろにずらした
+    NonMonotonicDts,
+    /// PTS が DTS より前だったので DTS に合わせた
+    PtsBeforeDts,
+    /// 元の timestamp を使わずに framerate から作り直した
+    Regenerated,
+}
+
+/// timestamp を直した記録
+///
+/// DTS を埋めたり作り直したりしたフレームは続いていれば一つにまとめて、 PTS/DTS は最初のフレームのものを入れる
+#[derive(Debug, Clone)]
+pub struct Correction {
+    /// stream の先頭 (seek した後はそこ) からのフレームの番号
+    pub frame: u64,
+    /// frame から続けて同じように直したフレームの数
+    pub frames: u64,
+    pub kind: CorrectionKind,
+    pub original_pts: Option<gst::ClockTime>,
+    pub original_dts: Option<gst::ClockTime>,
+    pub pts: Option<gst::ClockTime>,
+    pub dts: Option<gst::ClockTime>,
+}
+
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.frames > 1 {
+            write!(f, "frames {}-{}", self.frame, self.frame + self.frames - 1)?;
+        } else {
+            write!(f, "frame {}", self.frame)?;
+        }
+        write!(
+            f,
+            ": {:?} PTS {} -> {}, DTS {} -> {}",
+            self.kind,
+            self.original_pts.display(),
+            self.pts.display(),
+            self.original_dts.display(),
+            self.dts.display(),
+        )
+    }
+}
+
+/// demuxer から出てくる encode された映像の PTS/DTS を直す
+///
+/// buffer は decode 順に来るので、 DTS は単調増加で、 PTS は DTS 以上でないといけない
+/// mux や decoder はこれが守られていないと落ちたり、フレームを捨てたりする
+#[derive(Debug)]
+pub struct TimestampRepair {
+    frame_duration: Option<gst::ClockTime>,
+    frame: u64,
+    last_dts: Option<gst::ClockTime>,
+    /// 直す前の前のフレームの DTS (作り直しをやめるかどうかの判断に使う)
+    last_original_dts: Option<gst::ClockTime>,
+    /// 続けて直したフレームの数
+    streak: u32,
+    /// 作り直している間に、元の timestamp が続けて正しかったフレームの数
+    sane_streak: u32,
+    /// framerate から作り直しているときの、作り直し始めたフレームとその DTS
+    regenerate_from: Option<(u64, gst::ClockTime)>,
+    corrections: Vec<Correction>,
+}
+
+impl TimestampRepair {
+    pub fn new(framerate: Option<gst::Fraction>) -> Self {
+        let frame_duration = framerate
+            .filter(|framerate| framerate.numer() > 0 && framerate.denom() > 0)
+            .and_then(|framerate| gst::ClockTime::SECOND.mul_div_floor(framerate.denom() as u64, framerate.numer() as u64));
+
+        Self {
+            frame_duration,
+            frame: 0,
+            last_dts: None,
+            last_original_dts: None,
+            streak: 0,
+            sane_streak: 0,
+            regenerate_from: None,
+            corrections: Vec::new(),
+        }
+    }
+
+    /// flush した後は timestamp が戻るので、前のフレームとは比べない
+    pub fn reset(&mut self) {
+        self.frame = 0;
+        self.last_dts = None;
+        self.last_original_dts = None;
+        self.streak = 0;
+        self.sane_streak = 0;
+        self.regenerate_from = None;
+    }
+
+    pub fn corrections(&self) -> &[Correction] {
+        &self.corrections
+    }
+
+    /// 直した PTS/DTS を返す
+    pub fn repair(&mut self, original_pts: Option<gst::ClockTime>, original_dts: Option<gst::ClockTime>) -> (Option<gst::ClockTime>, Option<gst::ClockTime>) {
+        let frame = self.frame;
+        self.frame += 1;
+        let last_original_dts = std::mem::replace(&mut self.last_original_dts, original_dts.or(self.last_original_dts));
+
+        if let (Some((start_frame, start_dts)), Some(frame_duration)) = (self.regenerate_from, self.frame_duration) {
+            // 元の timestamp が単調増加で PTS >= DTS に戻ったら、作り直すのをやめる
+            let sane = match (original_pts, original_dts) {
+                (Some(pts), Some(dts)) => pts >= dts && last_original_dts.map_or(true, |last| dts > last),
+                _ => false,
+            };
+            self.sane_streak = if sane { self.sane_streak + 1 } else { 0 };
+            if self.sane_streak >= HOPELESS_STREAK {
+                log::info!("Timestamps are valid again for {} frames, stop regenerating them", self.sane_streak);
+                self.regenerate_from = None;
+                self.sane_streak = 0;
+                self.streak = 0;
+            } else {
+                let dts = start_dts + frame_duration * (frame - start_frame);
+                // PTS は B フレームの並べ替えに必要なので、 DTS との順序が正しいものだけ残す
+                let pts = original_pts.filter(|pts| *pts >= dts).unwrap_or(dts);
+                self.record(frame, CorrectionKind::Regenerated, original_pts, original_dts, Some(pts), Some(dts));
+                self.last_dts = Some(dts);
+                return (Some(pts), Some(dts));
+            }
+        }
+
+        let step = self.frame_duration.unwrap_or(MIN_DTS_STEP);
+        let mut pts = original_pts;
+        let mut dts = original_dts;
+        let mut corrected = false;
+
+        if dts.is_none() {
+            // 前のフレームの続きにする。最初のフレームなら PTS に合わせる
+            // B フレームでは PTS より後ろになるが、作った DTS に合わせて PTS を動かすと並べ替えが壊れるのでそのままにする
+            dts = self.last_dts.map(|last_dts| last_dts + step).or(pts);
+            if dts.is_some() {
+                self.record(frame, CorrectionKind::MissingDts, original_pts, original_dts, pts, dts);
+            }
+        } else {
+            if let (Some(last_dts), Some(current_dts)) = (self.last_dts, dts) {
+                if current_dts <= last_dts {
+                    dts = Some(last_dts + step);
+                    self.record(frame, CorrectionKind::NonMonotonicDts, original_pts, original_dts, pts, dts);
+                    corrected = true;
+                }
+            }
+
+            if let (Some(current_pts), Some(current_dts)) = (pts, dts) {
+                if current_pts < current_dts {
+                    pts = dts;
+                    self.record(frame, CorrectionKind::PtsBeforeDts, original_pts, original_dts, pts, dts);
+                    corrected = true;
+                }
+            }
+        }
+
+        // DTS がないだけのフレームは数えも途切れさせもしない
+        if original_dts.is_some() {
+            self.streak = if corrected { self.streak + 1 } else { 0 };
+        }
+        if self.streak >= HOPELESS_STREAK && self.frame_duration.is_some() {
+            if let Some(dts) = dts {
+                log::warn!("Timestamps are broken for {} frames in a row, regenerate them from the framerate", self.streak);
+                // 次のフレームから作り直すので、このフレームの番号と DTS を起点にする
+                self.regenerate_from = Some((frame, dts));
+                self.streak = 0;
+            }
+        }
+
+        if dts.is_some() {
+            self.last_dts = dts;
+        }
+        (pts, dts)
+    }
+
+    fn record(&mut self, frame: u64, kind: CorrectionKind, original_pts: Option<gst::ClockTime>, original_dts: Option<gst::ClockTime>, pts: Option<gst::ClockTime>, dts: Option<gst::ClockTime>) {
+        // 埋めたり作り直したりしたフレームは長く続くので、前の記録の続きなら数を増やすだけにする
+        if matches!(kind, CorrectionKind::MissingDts | CorrectionKind::Regenerated) {
+            if let Some(last) = self.corrections.last_mut() {
+                if last.kind == kind && last.frame + last.frames == frame {
+                    last.frames += 1;
+                    return;
+                }
+            }
+        }
+
+        let correction = Correction { frame, frames: 1, kind, original_pts, original_dts, pts, dts };
+        log::debug!("Repair timestamp: {}", correction);
+        self.corrections.push(correction);
+    }
+}
+
+/// pad を流れる buffer の timestamp を直す probe をつける
+///
+/// 直した記録は返した `TimestampRepair` に溜まる
+pub fn install(pad: &gst::Pad, framerate: Option<gst::Fraction>) -> Arc<Mutex<TimestampRepair>> {
+    let repair = Arc::new(Mutex::new(TimestampRepair::new(framerate)));
+
+    let probe_repair = repair.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST | gst::PadProbeType::EVENT_FLUSH, move |_, info| {
+        let mut repair = probe_repair.lock().unwrap();
+        match &mut info.data {
+            Some(gst::PadProbeData::Buffer(buffer)) => repair_buffer(&mut repair, buffer.make_mut()),
+            Some(gst::PadProbeData::BufferList(buffers)) => {
+                let buffers = buffers.make_mut();
+                for i in 0..buffers.len() {
+                    if let Some(buffer) = buffers.get_mut(i) {
+                        repair_buffer(&mut repair, buffer);
+                    }
+                }
+            },
+            Some(gst::PadProbeData::Event(event)) if event.type_() == gst::EventType::FlushStop => repair.reset(),
+            _ => (),
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    repair
+}
+
+fn repair_buffer(repair: &mut TimestampRepair, buffer: &mut gst::BufferRef) {
+    let (pts, dts) = repair.repair(buffer.pts(), buffer.dts());
+    buffer.set_pts(pts);
+    buffer.set_dts(dts);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Option<gst::ClockTime> {
+        Some(gst::ClockTime::from_mseconds(ms))
+    }
+
+    fn kinds(repair: &TimestampRepair) -> Vec<CorrectionKind> {
+        repair.corrections().iter().map(|correction| correction.kind).collect()
+    }
+
+    #[test]
+    fn keeps_valid_timestamps() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        assert_eq!(repair.repair(ms(80), ms(0)), (ms(80), ms(0)));
+        assert_eq!(repair.repair(ms(40), ms(40)), (ms(40), ms(40)));
+        assert!(repair.corrections().is_empty());
+    }
+
+    #[test]
+    fn fills_missing_dts() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        // 最初のフレームは PTS に合わせ、その後は前のフレームの続きにする
+        assert_eq!(repair.repair(ms(0), None), (ms(0), ms(0)));
+        assert_eq!(repair.repair(ms(40), None), (ms(40), ms(40)));
+        // 続けて埋めたフレームは一つの記録にまとめる
+        assert_eq!(kinds(&repair), [CorrectionKind::MissingDts]);
+        assert_eq!(repair.corrections()[0].frames, 2);
+    }
+
+    #[test]
+    fn missing_dts_does_not_trigger_regeneration() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        for frame in 0..HOPELESS_STREAK as u64 * 4 {
+            repair.repair(ms(frame * 40), None);
+        }
+        assert_eq!(kinds(&repair), [CorrectionKind::MissingDts]);
+        assert_eq!(repair.corrections()[0].frames, HOPELESS_STREAK as u64 * 4);
+    }
+
+    #[test]
+    fn keeps_b_frame_pts_when_filling_dts() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        // I P B B の decode 順で DTS がない
+        let mut last_dts = None;
+        for pts in [0, 120, 40, 80] {
+            let (repaired_pts, dts) = repair.repair(ms(pts), None);
+            assert_eq!(repaired_pts, ms(pts));
+            assert!(dts > last_dts);
+            last_dts = dts;
+        }
+        assert_eq!(kinds(&repair), [CorrectionKind::MissingDts]);
+    }
+
+    #[test]
+    fn leaves_buffer_without_timestamps() {
+        let mut repair = TimestampRepair::new(None);
+        assert_eq!(repair.repair(None, None), (None, None));
+        assert!(repair.corrections().is_empty());
+    }
+
+    #[test]
+    fn shifts_non_monotonic_dts() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        repair.repair(ms(100), ms(100));
+        assert_eq!(repair.repair(ms(200), ms(60)), (ms(200), ms(140)));
+        assert_eq!(kinds(&repair), [CorrectionKind::NonMonotonicDts]);
+    }
+
+    #[test]
+    fn shifts_non_monotonic_dts_without_framerate() {
+        let mut repair = TimestampRepair::new(None);
+        repair.repair(ms(100), ms(100));
+        let (_, dts) = repair.repair(ms(200), ms(100));
+        assert_eq!(dts, Some(gst::ClockTime::from_mseconds(100) + MIN_DTS_STEP));
+    }
+
+    #[test]
+    fn moves_pts_before_dts() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        assert_eq!(repair.repair(ms(20), ms(40)), (ms(40), ms(40)));
+        assert_eq!(kinds(&repair), [CorrectionKind::PtsBeforeDts]);
+    }
+
+    #[test]
+    fn regenerates_after_hopeless_streak() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        let frames = HOPELESS_STREAK as u64 + 10;
+
+        // DTS が全部同じ壊れた stream
+        let mut outputs: Vec<(Option<gst::ClockTime>, Option<gst::ClockTime>)> = Vec::new();
+        for _ in 0..frames {
+            let (pts, dts) = repair.repair(ms(0), ms(0));
+            let last_dts = outputs.last().and_then(|(_, dts)| *dts);
+            assert!(dts > last_dts, "DTS must be strictly increasing: {:?} after {:?}", dts, last_dts);
+            assert!(pts >= dts);
+            outputs.push((pts, dts));
+        }
+
+        // 作り直したフレームは一つの記録にまとめる
+        let regenerated = repair.corrections().iter().filter(|correction| correction.kind == CorrectionKind::Regenerated).collect::<Vec<_>>();
+        assert_eq!(regenerated.len(), 1);
+        assert_eq!(regenerated[0].frames, frames - HOPELESS_STREAK as u64 - 1);
+        // 作り直した DTS は framerate の間隔で並ぶ
+        let (_, last) = outputs[outputs.len() - 1];
+        let (_, previous) = outputs[outputs.len() - 2];
+        assert_eq!(last.unwrap() - previous.unwrap(), gst::ClockTime::from_mseconds(40));
+    }
+
+    #[test]
+    fn stops_regenerating_when_timestamps_recover() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        for _ in 0..HOPELESS_STREAK + 2 {
+            repair.repair(ms(0), ms(0));
+        }
+        assert_eq!(repair.corrections().last().unwrap().kind, CorrectionKind::Regenerated);
+
+        // 正しい timestamp が HOPELESS_STREAK フレーム続いたら、そこからは元のまま使う
+        let recovered = (0..HOPELESS_STREAK as u64 + 5)
+            .map(|frame| {
+                let timestamp = ms(1000 + frame * 40);
+                (repair.repair(timestamp, timestamp), timestamp)
+            })
+            .collect::<Vec<_>>();
+        for ((pts, dts), timestamp) in &recovered[HOPELESS_STREAK as usize - 1..] {
+            assert_eq!((*pts, *dts), (*timestamp, *timestamp));
+        }
+    }
+
+    #[test]
+    fn reset_forgets_previous_frames() {
+        let mut repair = TimestampRepair::new(Some(gst::Fraction::new(25, 1)));
+        repair.repair(ms(1000), ms(1000));
+        repair.reset();
+        assert_eq!(repair.repair(ms(0), ms(0)), (ms(0), ms(0)));
+        assert!(repair.corrections().is_empty());
+    }
+}
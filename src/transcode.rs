@@ -0,0 +1,826 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+
+use crate::{analyze::Taps, concat, error::Error, container::{Mp4Layout, OutputFormat}, encoder::Encoding, probe::{self, MediaInfo, StreamKind}, segment, selection::{StreamMap, StreamSelection}, timestamp::{self, Correction, TimestampRepair}};
+
+#[derive(Debug, Clone)]
+pub struct Input {
+    pub path: PathBuf,
+    /// 入力の container に合わせて選んだ demuxer (`probe::find_demuxer`)
+    pub demuxer_name: String,
+    pub media_info: MediaInfo,
+}
+
+/// 同じ decode から分岐して、別の解像度や bitrate で書く出力 (bitrate ladder の一段)
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    pub output_path: PathBuf,
+    pub output_format: OutputFormat,
+    pub video_encoding: Encoding,
+    pub audio_encoding: Encoding,
+}
+
+#[derive(Debug, Clone)]
+pub struct TranscodeConfig {
+    /// 二つ以上あれば順番に繋げて一つの出力にする
+    pub inputs: Vec<Input>,
+    /// segment の出力 (HLS/DASH) のときはディレクトリ
+    pub output_path: PathBuf,
+    pub output_format: OutputFormat,
+    /// mp4/mov のときの moov の置き方
+    pub mp4_layout: Mp4Layout,
+    /// segment の出力 (HLS/DASH) の segment の長さ
+    pub segment_duration: gst::ClockTime,
+    pub video_encoding: Encoding,
+    pub audio_encoding: Encoding,
+    pub selection: StreamSelection,
+    /// マッチした stream は decode/encode せずに、 parser を通してそのまま mux する
+    pub copy_streams: Vec<StreamMap>,
+    /// 映像しかない入力に無音の音声トラックを足す (音声トラックがないと再生できない player 向け)
+    pub add_silent_audio: bool,
+    /// 入力のこの位置から変換する
+    pub start: Option<gst::ClockTime>,
+    /// 入力のこの位置まで変換する
+    pub end: Option<gst::ClockTime>,
+    /// output_path とは別に、同じ decode から書く出力
+    /// 字幕は output_path にだけ書く
+    pub ladder: Vec<Rendition>,
+    /// demuxer から出てくる映像の PTS/DTS を直す
+    pub repair_timestamps: bool,
+    /// two-pass encode の 1 pass 目。映像だけを encode して統計ファイルを書かせ、出力は fakesink に捨てる
+    pub stats_pass: bool,
+}
+
+impl Rendition {
+    pub fn encoding(&self, kind: StreamKind) -> &Encoding {
+        match kind {
+            StreamKind::Video => &self.video_encoding,
+            _ => &self.audio_encoding,
+        }
+    }
+}
+
+impl TranscodeConfig {
+    pub fn is_trimmed(&self) -> bool {
+        self.start.is_some() || self.end.is_some()
+    }
+
+    pub fn encoding(&self, kind: StreamKind) -> &Encoding {
+        match kind {
+            StreamKind::Video => &self.video_encoding,
+            _ => &self.audio_encoding,
+        }
+    }
+}
+
+/// bitrate ladder の branch ごとの queue に溜められる raw データの長さ
+///
+/// tee は全部の branch に順番に push するので、一番遅い encoder がこの分だけ遅れるまでは他の branch が先に進める
+/// それ以上遅れると queue が一杯になって tee が待つ (出力からフレームは落とさない)
+const LADDER_QUEUE_TIME: gst::ClockTime = gst::ClockTime::from_seconds(3);
+
+/// bitrate ladder の branch ごとの queue に溜められる raw データの byte 数
+///
+/// raw の 1080p は 3 秒で 280 MB ほどになるので、 LADDER_QUEUE_TIME より先にこちらで止まることもある
+const LADDER_QUEUE_BYTES: u32 = 128 * 1024 * 1024;
+
+/// 映像しかない入力に足す無音の音声の sample rate
+pub(crate) const SILENT_AUDIO_RATE: i32 = 48000;
+
+/// demuxer が全部の stream を出し終えたときに bus に流す application message の名前
+const STREAMS_EXPOSED_MESSAGE: &str = "streams-exposed";
+
+/// 中断されたことを bus の message loop に伝える application message の名前
+pub(crate) const INTERRUPTED_MESSAGE: &str = "interrupted";
+
+pub struct Transcode {
+    pub pipeline: gst::Pipeline,
+    pub(crate) config: Arc<TranscodeConfig>,
+    /// trim するときに seek するまで demuxer の src pad を止めておく probe
+    pub(crate) trim_gates: Arc<Mutex<Vec<(gst::Pad, gst::PadProbeId)>>>,
+    /// 途中で止めるときに EOS を送る pad 。空なら pipeline に送る
+    pub(crate) eos_pads: Vec<gst::Pad>,
+    /// demuxer の pad の名前と、その pad の timestamp を直した記録
+    pub(crate) timestamp_repairs: Arc<Mutex<Vec<(String, Arc<Mutex<TimestampRepair>>)>>>,
+}
+
+impl Transcode {
+    /// trim の指定があれば seek してから PLAYING にする
+    ///
+    /// seek する前に `INTERRUPTED_MESSAGE` が来たら、何も書かずに `Error::Interrupted` を返す
+    pub fn play(&self) -> crate::Result<()> {
+        if self.config.is_trimmed() {
+            self.seek_to_trim_range()?;
+        }
+
+        self.pipeline.set_state(gst::State::Playing)
+            .map_err(|_| glib::bool_error!("Failed to set pipeline playing"))?;
+
+        Ok(())
+    }
+
+    /// `repair_timestamps` で直した timestamp を pad ごとに返す
+    pub fn timestamp_corrections(&self) -> Vec<(String, Vec<Correction>)> {
+        self.timestamp_repairs.lock().unwrap().iter()
+            .map(|(pad_name, repair)| (pad_name.clone(), repair.lock().unwrap().corrections().to_vec()))
+            .collect()
+    }
+
+    /// 途中で止めるために EOS を流す
+    ///
+    /// mux が EOS を受け取ると moov などを書いて出力ファイルを閉じるので、途中までの長さの正しいファイルになる
+    /// EOS message が bus に来るまで待つのは呼び出し側
+    pub fn send_eos(&self) -> Result<(), glib::BoolError> {
+        if self.eos_pads.is_empty() {
+            // pipeline に送ると全部の source element に EOS が送られる
+            if !self.pipeline.send_event(gst::event::Eos::new()) {
+                return Err(glib::bool_error!("Failed to send EOS to pipeline"));
+            }
+            return Ok(());
+        }
+
+        for pad in &self.eos_pads {
+            if !pad.send_event(gst::event::Eos::new()) {
+                return Err(glib::bool_error!("Failed to send EOS to {}", pad.name()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// PAUSED にして demuxer の stream が出揃うのを待ってから、 trim の範囲に seek する
+    ///
+    /// demuxer の src pad は最初の buffer で block されているので、 seek する前のデータは mux まで届かない
+    /// (mux が書き始めてから flush すると出力ファイルが壊れる)
+    /// seek は start/stop を指定した flush seek で、 stop まで来ると普通に EOS になる
+    /// flush で running time が 0 に戻るので、 running time で timestamp をつける mux の出力は 0 始まりになる
+    fn seek_to_trim_range(&self) -> crate::Result<()> {
+        self.pipeline.set_state(gst::State::Paused)
+            .map_err(|_| glib::bool_error!("Failed to set pipeline paused"))?;
+
+        let bus = self.pipeline.bus().expect("pipeline must have a bus");
+        wait_for_streams_exposed(&bus)?;
+
+        let gates = std::mem::take(&mut *self.trim_gates.lock().unwrap());
+        let (pad, _) = gates.first().ok_or_else(|| glib::bool_error!("No stream to seek"))?;
+
+        let start = self.config.start.unwrap_or(gst::ClockTime::ZERO);
+        let stop_type = if self.config.end.is_some() { gst::SeekType::Set } else { gst::SeekType::None };
+        let seek = gst::event::Seek::new(1.0, gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE, gst::SeekType::Set, Some(start), stop_type, self.config.end);
+
+        // pipeline に送ると mux から上流に向かって探すことになるが、 decodebin の pad がまだないので demuxer に直接送る
+        log::info!("Seek to {} - {}", start, self.config.end.display());
+        if !pad.send_event(seek) {
+            return Err(glib::bool_error!("Failed to seek to {}", start));
+        }
+
+        for (pad, probe_id) in gates {
+            pad.remove_probe(probe_id);
+        }
+
+        Ok(())
+    }
+}
+
+/// demuxer が `STREAMS_EXPOSED_MESSAGE` を流すまで bus を見る
+///
+/// 途中で取った application message のうち自分で扱わないものは、後の message loop が受け取れるように post し直す
+fn wait_for_streams_exposed(bus: &gst::Bus) -> crate::Result<()> {
+    let mut unhandled = Vec::new();
+    let result = loop {
+        let msg = bus.timed_pop_filtered(gst::ClockTime::NONE, &[gst::MessageType::Application, gst::MessageType::Error])
+            .expect("bus must return a message when waiting without timeout");
+        let name = match msg.view() {
+            gst::MessageView::Application(application) => application.structure().map(|s| s.name().to_string()),
+            gst::MessageView::Error(err) => {
+                break Err(Error::Gst(glib::bool_error!("Error from {:?}: {} ({:?})", msg.src().map(|s| s.path_string()), err.error(), err.debug())));
+            },
+            _ => continue,
+        };
+        match name.as_deref() {
+            Some(STREAMS_EXPOSED_MESSAGE) => break Ok(()),
+            Some(INTERRUPTED_MESSAGE) => break Err(Error::Interrupted),
+            _ => unhandled.push(msg),
+        }
+    };
+
+    for msg in unhandled {
+        if let Err(err) = bus.post(msg) {
+            log::warn!("Failed to re-post application message: {}", err);
+        }
+    }
+
+    result
+}
+
+/// demuxer が実際に出してきた stream のうち、 branch を組み立てたもの
+#[derive(Debug, Default)]
+struct LinkedStreams {
+    /// 種類ごとに demuxer が出してきた pad の数 (選ばなかったものも含む)
+    exposed_counts: HashMap<StreamKind, usize>,
+    /// mux の直前の element (encoder か parser)
+    video_branches: Vec<gst::Element>,
+    audio_branches: Vec<gst::Element>,
+    subtitle_count: usize,
+}
+
+/// filesrc ! demux ! (queue ! decodebin ! convert ! encoder) ! mux ! filesink を組み立てる
+///
+/// demuxer の src pad は sometimes pad なので、 demuxer 以降の branch は pad-added で組み立てる
+/// 映像だけ、音声だけの入力もあるので、出てきた stream の分だけ branch を作る
+/// 字幕は decode せずにそのまま mux に渡す
+/// 入力が複数あるときは `concat::build_concat_pipeline` で繋げる
+pub fn build_pipeline(config: &TranscodeConfig) -> Result<Transcode, glib::BoolError> {
+    build_tapped_pipeline(config, Arc::default())
+}
+
+/// `build_pipeline` と同じ pipeline の decode の後ろに、 analyzer の appsink を繋ぐ
+pub(crate) fn build_tapped_pipeline(config: &TranscodeConfig, taps: Arc<Taps>) -> Result<Transcode, glib::BoolError> {
+    if config.output_format.segmented && !config.copy_streams.is_empty() {
+        return Err(glib::bool_error!("Stream copy cannot be used for segmented output, keyframes cannot be forced at segment boundaries"));
+    }
+    if !config.ladder.is_empty() && (!config.copy_streams.is_empty() || config.add_silent_audio) {
+        return Err(glib::bool_error!("Stream copy and silent audio cannot be used with multiple renditions"));
+    }
+    if config.stats_pass && (config.inputs.len() > 1 || !config.ladder.is_empty()) {
+        return Err(glib::bool_error!("Two-pass encoding is not supported with multiple inputs or renditions"));
+    }
+    // copy した映像は encoder を通らないので、 1 pass 目で統計ファイルが書かれない
+    if config.stats_pass && !config.copy_streams.is_empty() {
+        return Err(glib::bool_error!("Two-pass encoding cannot be used with stream copy"));
+    }
+
+    match config.inputs.as_slice() {
+        [] => Err(glib::bool_error!("No input")),
+        [input] => build_single_input_pipeline(config, input, taps),
+        _ => concat::build_concat_pipeline(config, &taps),
+    }
+}
+
+fn build_single_input_pipeline(config: &TranscodeConfig, input: &Input, taps: Arc<Taps>) -> Result<Transcode, glib::BoolError> {
+    let pipeline = gst::Pipeline::builder().name("transcode_pipeline").build();
+
+    let filesrc_el = gst::ElementFactory::make("filesrc").name("src").property("location", input.path.as_path()).build()?;
+    let demux_el = gst::ElementFactory::make(&input.demuxer_name).name("demux").build()?;
+    pipeline.add_many(&[&filesrc_el, &demux_el])?;
+    gst::Element::link_many(&[&filesrc_el, &demux_el])?;
+    let mux_el = add_output(&pipeline, config)?;
+    let ladder_mux_els = config.ladder.iter().enumerate()
+        .map(|(i, rendition)| add_muxer(&pipeline, config, rendition.output_format, &rendition.output_path, i + 1))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let config = Arc::new(config.clone());
+    let media_info = Arc::new(input.media_info.clone());
+    let linked_streams = Arc::new(Mutex::new(LinkedStreams::default()));
+    let trim_gates = Arc::new(Mutex::new(Vec::new()));
+    let timestamp_repairs = Arc::new(Mutex::new(Vec::new()));
+
+    {
+        let pipeline_weak = pipeline.downgrade();
+        let mux_el_weak = mux_el.downgrade();
+        let ladder_mux_els_weak = ladder_mux_els.iter().map(|el| el.downgrade()).collect::<Vec<_>>();
+        let config = config.clone();
+        let linked_streams = linked_streams.clone();
+        let trim_gates = trim_gates.clone();
+        let timestamp_repairs = timestamp_repairs.clone();
+        demux_el.connect_pad_added(move |demux_el, pad| {
+            let (Some(pipeline), Some(mux_el)) = (pipeline_weak.upgrade(), mux_el_weak.upgrade()) else {
+                return;
+            };
+            let Some(ladder_mux_els) = ladder_mux_els_weak.iter().map(|el| el.upgrade()).collect::<Option<Vec<_>>>() else {
+                return;
+            };
+
+            if config.is_trimmed() {
+                let probe_id = pad.add_probe(gst::PadProbeType::BLOCK | gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST, |_, _| gst::PadProbeReturn::Ok)
+                    .expect("block probe must be added to demuxer src pad");
+                trim_gates.lock().unwrap().push((pad.clone(), probe_id));
+            }
+
+            let caps = pad.current_caps().expect("demuxer src pad must have caps");
+            let Some(kind) = StreamKind::from_caps(&caps) else {
+                log::debug!("Ignore demuxer pad: {} ({:?})", pad.name(), caps);
+                return;
+            };
+
+            let mut linked_streams = linked_streams.lock().unwrap();
+            let index = {
+                let count = linked_streams.exposed_counts.entry(kind).or_default();
+                *count += 1;
+                *count - 1
+            };
+
+            let stream = media_info.find_stream(pad.stream_id().as_deref(), kind, index);
+            let language = stream.and_then(|stream| stream.language.clone());
+            let selected = config.selection.is_selected(0, kind, index, stream) && config.output_format.accepts_kind(kind);
+            if !selected || (config.stats_pass && kind != StreamKind::Video) {
+                log::info!("Skip {:?} stream {} ({}, language={:?})", kind, index, pad.name(), language);
+                if let Err(err) = link_discard_branch(&pipeline, pad) {
+                    gst::element_error!(demux_el, gst::StreamError::Failed, ("Failed to discard {}: {}", pad.name(), err));
+                }
+                return;
+            }
+
+            if config.repair_timestamps && kind == StreamKind::Video {
+                let repair = timestamp::install(pad, stream.and_then(|stream| stream.framerate));
+                timestamp_repairs.lock().unwrap().push((pad.name().to_string(), repair));
+            }
+
+            let result = match kind {
+                StreamKind::Video | StreamKind::Audio => {
+                    let copy = config.copy_streams.iter().any(|map| map.matches(0, kind, index));
+                    let branch = if copy && config.output_format.accepts_codec(&caps) {
+                        link_copy_branch(&pipeline, pad, &mux_el, kind)
+                    } else {
+                        if copy {
+                            log::warn!("{} does not accept {}, re-encode {:?} stream {}", config.output_format.muxer_name, caps, kind, index);
+                        }
+                        if ladder_mux_els.is_empty() {
+                            link_decode_branch(&pipeline, pad, &mux_el, kind, config.encoding(kind), &taps)
+                        } else {
+                            let outputs = [(mux_el.clone(), config.encoding(kind))].into_iter()
+                                .chain(config.ladder.iter().zip(&ladder_mux_els)
+                                    .filter(|(rendition, _)| rendition.output_format.accepts_kind(kind))
+                                    .map(|(rendition, mux_el)| (mux_el.clone(), rendition.encoding(kind))))
+                                .collect::<Vec<_>>();
+                            link_ladder_branch(&pipeline, pad, kind, &outputs, &taps)
+                        }
+                    };
+
+                    branch.map(|tail_el| {
+                        let src_pad = tail_el.static_pad("src").expect("branch tail must have a src pad");
+                        match kind {
+                            StreamKind::Video => linked_streams.video_branches.push(tail_el),
+                            _ => linked_streams.audio_branches.push(tail_el),
+                        }
+                        src_pad
+                    })
+                },
+                StreamKind::Subtitle => match link_passthrough_branch(&pipeline, pad, &mux_el) {
+                    Ok(src_pad) => {
+                        linked_streams.subtitle_count += 1;
+                        Ok(src_pad)
+                    },
+                    Err(err) => {
+                        // 字幕を受け付けない muxer もあるので、その時は捨てて続ける
+                        log::warn!("Drop subtitle stream {} ({}): {}", index, pad.name(), err);
+                        if let Err(err) = link_discard_branch(&pipeline, pad) {
+                            gst::element_error!(demux_el, gst::StreamError::Failed, ("Failed to discard {}: {}", pad.name(), err));
+                        }
+                        return;
+                    },
+                },
+            };
+
+            match result {
+                Ok(src_pad) => {
+                    log::info!("Map {:?} stream {} ({}, language={:?})", kind, index, pad.name(), language);
+                    if let Some(language) = language {
+                        keep_language_tag(&src_pad, language);
+                    }
+                },
+                Err(err) => {
+                    gst::element_error!(demux_el, gst::StreamError::Failed, ("Failed to build branch for {}: {}", pad.name(), err));
+                },
+            }
+        });
+    }
+
+    {
+        let pipeline_weak = pipeline.downgrade();
+        let mux_el_weak = mux_el.downgrade();
+        let config = config.clone();
+        demux_el.connect_no_more_pads(move |demux_el| {
+            let (Some(pipeline), Some(mux_el)) = (pipeline_weak.upgrade(), mux_el_weak.upgrade()) else {
+                return;
+            };
+
+            let linked_streams = linked_streams.lock().unwrap();
+            log::info!("Demuxer exposed all streams: video={}, audio={}, subtitle={}", linked_streams.video_branches.len(), linked_streams.audio_branches.len(), linked_streams.subtitle_count);
+
+            // branch が一つもないと mux に何も流れず EOS も来ないので、ここでエラーにする
+            if linked_streams.video_branches.is_empty() && linked_streams.audio_branches.is_empty() {
+                gst::element_error!(demux_el, gst::StreamError::Demux, ("No video or audio stream selected from input"));
+                return;
+            }
+
+            if config.add_silent_audio && !config.stats_pass && linked_streams.audio_branches.is_empty() {
+                let video_el = &linked_streams.video_branches[0];
+                if let Err(err) = link_silent_audio_branch(&pipeline, &mux_el, &config.audio_encoding, video_el) {
+                    gst::element_error!(demux_el, gst::StreamError::Failed, ("Failed to build silent audio branch: {}", err));
+                    return;
+                }
+            }
+
+            let msg = gst::message::Application::builder(gst::Structure::new_empty(STREAMS_EXPOSED_MESSAGE)).src(demux_el).build();
+            if let Err(err) = demux_el.post_message(msg) {
+                log::warn!("Failed to post {} message: {}", STREAMS_EXPOSED_MESSAGE, err);
+            }
+        });
+    }
+
+    Ok(Transcode { pipeline, config, trim_gates, eos_pads: Vec::new(), timestamp_repairs })
+}
+
+/// mux ! filesink を作って pipeline に追加し、 mux を返す
+///
+/// segment の出力 (HLS/DASH) では、 segment と playlist を書く sink を mux の代わりに使う
+pub(crate) fn add_output(pipeline: &gst::Pipeline, config: &TranscodeConfig) -> Result<gst::Element, glib::BoolError> {
+    add_muxer(pipeline, config, config.output_format, &config.output_path, 0)
+}
+
+/// 二つ目以降の出力 (bitrate ladder) の element の名前には番号をつける
+fn add_muxer(pipeline: &gst::Pipeline, config: &TranscodeConfig, output_format: OutputFormat, output_path: &Path, output_index: usize) -> Result<gst::Element, glib::BoolError> {
+    let (mux_name, sink_name) = match output_index {
+        0 => ("mux".to_string(), "sink".to_string()),
+        i => (format!("mux{}", i), format!("sink{}", i)),
+    };
+
+    if config.stats_pass {
+        // encoder に統計ファイルを書かせるだけなので、 encode したデータは捨てる
+        let fakesink_el = gst::ElementFactory::make("fakesink").name(mux_name).property("sync", false).build()?;
+        pipeline.add(&fakesink_el)?;
+        return Ok(fakesink_el);
+    }
+    let mux_el = gst::ElementFactory::make(output_format.muxer_name).name(mux_name).build()?;
+
+    if output_format.segmented {
+        segment::configure_segment_sink(&mux_el, output_path, config.segment_duration)?;
+        pipeline.add(&mux_el)?;
+        return Ok(mux_el);
+    }
+
+    let filesink_el = gst::ElementFactory::make("filesink").name(sink_name).property("location", output_path).build()?;
+    output_format.configure_muxer(&mux_el, config.mp4_layout);
+    pipeline.add_many(&[&mux_el, &filesink_el])?;
+    mux_el.link(&filesink_el)?;
+
+    Ok(mux_el)
+}
+
+/// kind に合った mux の sink pad に繋ぐ
+///
+/// hlssink2 のように sink pad template の caps が ANY だと、 `Element::link` では音声が video の pad に繋がることがあるので、
+/// kind の名前の request pad template があればそれを使う
+pub(crate) fn link_to_mux(src_el: &gst::Element, mux_el: &gst::Element, kind: StreamKind) -> Result<(), glib::BoolError> {
+    let prefix = match kind {
+        StreamKind::Video => "video",
+        StreamKind::Audio => "audio",
+        StreamKind::Subtitle => "subtitle",
+    };
+    let template = mux_el.pad_template_list().into_iter().find(|template| {
+        template.direction() == gst::PadDirection::Sink
+            && template.presence() == gst::PadPresence::Request
+            && template.name_template().starts_with(prefix)
+    });
+    let Some(template) = template else {
+        // mpegtsmux の sink_%d のように kind で分かれていなければ caps で選ばせる
+        return src_el.link(mux_el);
+    };
+
+    let sink_pad = mux_el.request_pad(&template, None, None)
+        .ok_or_else(|| glib::bool_error!("Failed to request {} pad from {}", template.name_template(), mux_el.name()))?;
+    let src_pad = src_el.static_pad("src").ok_or_else(|| glib::bool_error!("{} has no src pad", src_el.name()))?;
+    src_pad.link(&sink_pad)
+        .map_err(|err| glib::bool_error!("Failed to link {} to {}: {}", src_el.name(), sink_pad.name(), err))?;
+
+    Ok(())
+}
+
+/// converter ! (raw capsfilter) ! encoder ! (encoded capsfilter) を作って pipeline に追加する
+///
+/// 先頭と末尾の element は呼び出し側で繋ぐ
+pub(crate) fn add_encode_chain(pipeline: &gst::Pipeline, kind: StreamKind, encoding: &Encoding) -> Result<Vec<gst::Element>, glib::BoolError> {
+    let converter_names: &[&str] = match kind {
+        StreamKind::Video => &["videoconvert", "videoscale", "videorate"],
+        _ => &["audioconvert", "audioresample"],
+    };
+
+    let mut chain = Vec::new();
+    for converter_name in converter_names {
+        chain.push(gst::ElementFactory::make(converter_name).build()?);
+    }
+    if let Some(raw_caps) = &encoding.raw_caps {
+        chain.push(gst::ElementFactory::make("capsfilter").property("caps", raw_caps.clone()).build()?);
+    }
+    chain.push(encoding.encoder.make()?);
+    if let Some(encoded_caps) = &encoding.encoded_caps {
+        chain.push(gst::ElementFactory::make("capsfilter").property("caps", encoded_caps.clone()).build()?);
+    }
+
+    pipeline.add_many(&chain)?;
+    gst::Element::link_many(&chain)?;
+
+    Ok(chain)
+}
+
+/// src_pad ! queue ! decodebin ! tee ! (queue ! encode chain ! mux)...
+///
+/// 一回の decode を bitrate ladder の全部の出力で使う
+/// 最初の出力の branch の末尾を返す
+fn link_ladder_branch(pipeline: &gst::Pipeline, src_pad: &gst::Pad, kind: StreamKind, outputs: &[(gst::Element, &Encoding)], taps: &Taps) -> Result<gst::Element, glib::BoolError> {
+    let tee_el = gst::ElementFactory::make("tee").build()?;
+    pipeline.add(&tee_el)?;
+
+    let mut tail_els = Vec::new();
+    for (mux_el, encoding) in outputs {
+        // 遅い branch との差を時間で区切って、メモリは byte 数で抑える
+        // leaky にすると出力のフレームが落ちるので、溢れたら tee を待たせる
+        let queue_el = gst::ElementFactory::make("queue")
+            .property("max-size-buffers", 0u32)
+            .property("max-size-bytes", LADDER_QUEUE_BYTES)
+            .property("max-size-time", LADDER_QUEUE_TIME.nseconds())
+            .build()?;
+        pipeline.add(&queue_el)?;
+
+        let chain = add_encode_chain(pipeline, kind, encoding)?;
+        queue_el.link(chain.first().expect("encode chain must not be empty"))?;
+        let tail_el = chain.last().expect("encode chain must not be empty").clone();
+        link_to_mux(&tail_el, mux_el, kind)?;
+        tee_el.link(&queue_el)?;
+
+        for el in chain.iter().rev().chain([&queue_el]) {
+            el.sync_state_with_parent()?;
+        }
+        log::debug!("Linked {:?} rendition to {} ({})", kind, mux_el.name(), encoding.encoder.factory_name);
+        tail_els.push(tail_el);
+    }
+    taps.link_to_tee(pipeline, kind, &tee_el)?;
+    tee_el.sync_state_with_parent()?;
+
+    link_decoder(pipeline, src_pad, &tee_el)?;
+
+    tail_els.into_iter().next().ok_or_else(|| glib::bool_error!("No output for {:?} stream", kind))
+}
+
+/// src_pad ! queue ! decodebin ! [tee (analyzer) !] (encode chain) ! mux
+fn link_decode_branch(pipeline: &gst::Pipeline, src_pad: &gst::Pad, mux_el: &gst::Element, kind: StreamKind, encoding: &Encoding, taps: &Taps) -> Result<gst::Element, glib::BoolError> {
+    let chain = add_encode_chain(pipeline, kind, encoding)?;
+    let tail_el = chain.last().expect("encode chain must not be empty").clone();
+    link_to_mux(&tail_el, mux_el, kind)?;
+
+    // 下流から順に state を合わせる
+    for el in chain.iter().rev() {
+        el.sync_state_with_parent()?;
+    }
+    let head_el = taps.link(pipeline, kind, chain.first().expect("encode chain must not be empty"))?;
+    link_decoder(pipeline, src_pad, &head_el)?;
+
+    log::debug!("Linked demuxer pad to {} branch: {}", encoding.encoder.factory_name, src_pad.name());
+
+    Ok(tail_el)
+}
+
+/// src_pad ! queue ! decodebin ! head_el
+///
+/// head_el から下流は先に state を合わせておくこと
+pub(crate) fn link_decoder(pipeline: &gst::Pipeline, src_pad: &gst::Pad, head_el: &gst::Element) -> Result<(), glib::BoolError> {
+    let queue_el = gst::ElementFactory::make("queue").build()?;
+    let decodebin_el = gst::ElementFactory::make("decodebin").build()?;
+
+    pipeline.add_many(&[&queue_el, &decodebin_el])?;
+    queue_el.link(&decodebin_el)?;
+
+    // decodebin の src pad も sometimes pad
+    let head_el_weak = head_el.downgrade();
+    decodebin_el.connect_pad_added(move |decodebin_el, pad| {
+        let Some(head_el) = head_el_weak.upgrade() else {
+            return;
+        };
+
+        let sink_pad = head_el.static_pad("sink").expect("converter must have a sink pad");
+        if sink_pad.is_linked() {
+            log::debug!("Ignore decodebin pad: {}", pad.name());
+            return;
+        }
+
+        if let Err(err) = pad.link(&sink_pad) {
+            gst::element_error!(decodebin_el, gst::CoreError::Negotiation, ("Failed to link decoded pad {}: {}", pad.name(), err));
+        }
+    });
+
+    let queue_sink_pad = queue_el.static_pad("sink").expect("queue must have a sink pad");
+    src_pad.link(&queue_sink_pad)
+        .map_err(|err| glib::bool_error!("Failed to link {} to queue: {}", src_pad.name(), err))?;
+
+    decodebin_el.sync_state_with_parent()?;
+    queue_el.sync_state_with_parent()?;
+
+    Ok(())
+}
+
+/// src_pad ! queue ! parser ! mux
+///
+/// decode/encode せずに container だけ変える。 parser が見つからなければ queue から直接 mux に繋ぐ
+fn link_copy_branch(pipeline: &gst::Pipeline, src_pad: &gst::Pad, mux_el: &gst::Element, kind: StreamKind) -> Result<gst::Element, glib::BoolError> {
+    let caps = src_pad.current_caps().expect("demuxer src pad must have caps");
+
+    let queue_el = gst::ElementFactory::make("queue").build()?;
+    let parser_el = match probe::find_parser(&caps) {
+        Some(parser_name) => Some(gst::ElementFactory::make(&parser_name).build()?),
+        None => {
+            log::debug!("No parser found for {}, link to muxer directly", caps);
+            None
+        },
+    };
+    let tail_el = parser_el.as_ref().unwrap_or(&queue_el).clone();
+
+    pipeline.add(&queue_el)?;
+    if let Some(parser_el) = &parser_el {
+        pipeline.add(parser_el)?;
+        queue_el.link(parser_el)?;
+    }
+    link_to_mux(&tail_el, mux_el, kind)?;
+
+    let queue_sink_pad = queue_el.static_pad("sink").expect("queue must have a sink pad");
+    src_pad.link(&queue_sink_pad)
+        .map_err(|err| glib::bool_error!("Failed to link {} to queue: {}", src_pad.name(), err))?;
+
+    if let Some(parser_el) = &parser_el {
+        parser_el.sync_state_with_parent()?;
+    }
+    queue_el.sync_state_with_parent()?;
+
+    log::debug!("Linked demuxer pad to muxer without re-encoding: {} ({})", src_pad.name(), caps);
+
+    Ok(tail_el)
+}
+
+/// src_pad ! queue ! mux
+///
+/// 字幕のように decode/encode しない stream 用。 mux が受け付けなければエラー
+fn link_passthrough_branch(pipeline: &gst::Pipeline, src_pad: &gst::Pad, mux_el: &gst::Element) -> Result<gst::Pad, glib::BoolError> {
+    let queue_el = gst::ElementFactory::make("queue").build()?;
+    pipeline.add(&queue_el)?;
+
+    let queue_src_pad = queue_el.static_pad("src").expect("queue must have a src pad");
+    let queue_sink_pad = queue_el.static_pad("sink").expect("queue must have a sink pad");
+    src_pad.link(&queue_sink_pad)
+        .map_err(|err| glib::bool_error!("Failed to link {} to queue: {}", src_pad.name(), err))?;
+
+    // 先に demuxer と繋いでおくと、 kind で pad が分かれていない muxer でも caps で選べる。
+    // 繋げなかった queue は呼び出し側で捨てる branch の邪魔になるので外す
+    if let Err(err) = link_to_mux(&queue_el, mux_el, StreamKind::Subtitle) {
+        src_pad.unlink(&queue_sink_pad)?;
+        pipeline.remove(&queue_el)?;
+        return Err(err);
+    }
+    queue_el.sync_state_with_parent()?;
+
+    log::debug!("Linked demuxer pad to {}: {}", mux_el.name(), src_pad.name());
+
+    Ok(queue_src_pad)
+}
+
+/// src_pad ! fakesink
+///
+/// 使わない stream も繋いでおかないと、 demuxer が not-linked で止まることがある
+pub(crate) fn link_discard_branch(pipeline: &gst::Pipeline, src_pad: &gst::Pad) -> Result<(), glib::BoolError> {
+    let fakesink_el = gst::ElementFactory::make("fakesink").property("sync", false).property("async", false).build()?;
+    pipeline.add(&fakesink_el)?;
+
+    let sink_pad = fakesink_el.static_pad("sink").expect("fakesink must have a sink pad");
+    src_pad.link(&sink_pad)
+        .map_err(|err| glib::bool_error!("Failed to link {} to fakesink: {}", src_pad.name(), err))?;
+    fakesink_el.sync_state_with_parent()?;
+
+    Ok(())
+}
+
+/// stream の tag に language-code が入っていなければ足す
+///
+/// decoder や encoder を通すと上流の tag が落ちることがあるので、 mux の直前で入れ直す
+fn keep_language_tag(src_pad: &gst::Pad, language: String) {
+    src_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        let Some(gst::PadProbeData::Event(ref mut event)) = info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let gst::EventView::Tag(tag) = event.view() else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        let tags = tag.tag();
+        if tags.scope() != gst::TagScope::Stream || tags.get::<gst::tags::LanguageCode>().is_some() {
+            return gst::PadProbeReturn::Ok;
+        }
+
+        let mut tags = tags.to_owned();
+        tags.make_mut().add::<gst::tags::LanguageCode>(&language.as_str(), gst::TagMergeMode::Replace);
+        *event = gst::event::Tag::new(tags);
+
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// audiotestsrc wave=silence ! capsfilter ! (encode chain) ! mux
+///
+/// audiotestsrc は終わりがないので、映像の branch から EOS が出たら audiotestsrc にも EOS を送って止める
+fn link_silent_audio_branch(pipeline: &gst::Pipeline, mux_el: &gst::Element, encoding: &Encoding, video_el: &gst::Element) -> Result<(), glib::BoolError> {
+    let src_el = gst::ElementFactory::make("audiotestsrc").property_from_str("wave", "silence").build()?;
+    let capsfilter_el = gst::ElementFactory::make("capsfilter")
+        .property("caps", gst::Caps::builder("audio/x-raw").field("rate", SILENT_AUDIO_RATE).field("channels", 2i32).build())
+        .build()?;
+
+    pipeline.add_many(&[&src_el, &capsfilter_el])?;
+    src_el.link(&capsfilter_el)?;
+
+    let chain = add_encode_chain(pipeline, StreamKind::Audio, encoding)?;
+    capsfilter_el.link(chain.first().expect("encode chain must not be empty"))?;
+    link_to_mux(chain.last().expect("encode chain must not be empty"), mux_el, StreamKind::Audio)?;
+
+    let src_el_weak = src_el.downgrade();
+    let video_src_pad = video_el.static_pad("src").expect("branch tail must have a src pad");
+    video_src_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        let Some(gst::PadProbeData::Event(ref event)) = info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        if event.type_() != gst::EventType::Eos {
+            return gst::PadProbeReturn::Ok;
+        }
+
+        if let Some(src_el) = src_el_weak.upgrade() {
+            log::debug!("Video reached EOS, stop silent audio");
+            src_el.send_event(gst::event::Eos::new());
+        }
+        gst::PadProbeReturn::Remove
+    });
+
+    for el in chain.iter().rev().chain([&capsfilter_el, &src_el]) {
+        el.sync_state_with_parent()?;
+    }
+
+    log::debug!("Linked silent audio branch to {}", encoding.encoder.factory_name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn application(name: &str) -> gst::Message {
+        gst::message::Application::new(gst::Structure::new_empty(name))
+    }
+
+    fn application_name(msg: &gst::Message) -> Option<String> {
+        match msg.view() {
+            gst::MessageView::Application(application) => application.structure().map(|s| s.name().to_string()),
+            _ => None,
+        }
+    }
+
+    fn config() -> TranscodeConfig {
+        let encoding = |spec: &str| Encoding::from(crate::encoder::EncoderSpec::parse(spec).unwrap());
+        TranscodeConfig {
+            inputs: Vec::new(),
+            output_path: "out.mp4".into(),
+            output_format: OutputFormat::find("mp4").unwrap(),
+            mp4_layout: Mp4Layout::Default,
+            segment_duration: segment::DEFAULT_SEGMENT_DURATION,
+            video_encoding: encoding("x264enc"),
+            audio_encoding: encoding("avenc_aac"),
+            selection: StreamSelection::default(),
+            copy_streams: Vec::new(),
+            add_silent_audio: false,
+            start: None,
+            end: None,
+            ladder: Vec::new(),
+            repair_timestamps: false,
+            stats_pass: false,
+        }
+    }
+
+    #[test]
+    fn rejects_stream_copy_in_stats_pass() {
+        gst::init().unwrap();
+        let config = TranscodeConfig { copy_streams: vec![StreamMap::parse("0:v").unwrap()], stats_pass: true, ..config() };
+        let err = build_pipeline(&config).err().expect("stats pass with stream copy must be rejected");
+        assert!(err.to_string().contains("stream copy"), "{}", err);
+    }
+
+    #[test]
+    fn interrupt_before_streams_exposed() {
+        gst::init().unwrap();
+        let bus = gst::Bus::new();
+        bus.post(application("progress")).unwrap();
+        bus.post(application(INTERRUPTED_MESSAGE)).unwrap();
+
+        assert!(matches!(wait_for_streams_exposed(&bus), Err(Error::Interrupted)));
+        // 扱わなかった message は後の message loop のために残す
+        assert_eq!(bus.pop().as_ref().and_then(application_name).as_deref(), Some("progress"));
+        assert!(bus.pop().is_none());
+    }
+
+    #[test]
+    fn keeps_other_messages_while_waiting_for_streams() {
+        gst::init().unwrap();
+        let bus = gst::Bus::new();
+        bus.post(application("progress")).unwrap();
+        bus.post(application(STREAMS_EXPOSED_MESSAGE)).unwrap();
+
+        assert!(wait_for_streams_exposed(&bus).is_ok());
+        assert_eq!(bus.pop().as_ref().and_then(application_name).as_deref(), Some("progress"));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{env, fs, path::{Path, PathBuf}, process, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::{Duration, Instant}};
+
+use gstreamer as gst;
+use gst::prelude::*;
+
+use crate::{
+    analyze::{Analysis, AnalysisRun, AnalyzerReport},
+    error::{Error, Result},
+    job::ResolvedJob,
+    negotiation::NegotiationTracer,
+    progress::{self, ProgressListener, ProgressReporter},
+    timestamp::Correction,
+    transcode::{self, Transcode, TranscodeConfig, INTERRUPTED_MESSAGE},
+    verify::{self, Tolerances, VerifyReport},
+};
+
+/// 中断して EOS を送ってから、 mux が出力を書き終えるのを待つ時間のデフォルト
+pub const DEFAULT_EOS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 同じプロセスで two-pass の transcode を並べて動かしても、統計ファイルのディレクトリが被らないようにする
+static NEXT_STATS_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// job の中身以外の、動かし方の設定
+pub struct TranscodeOptions {
+    /// None なら進捗を出さない
+    pub progress: Option<Box<dyn ProgressListener>>,
+    pub progress_interval: Duration,
+    pub diagnose_caps: bool,
+    pub eos_timeout: Duration,
+    /// two-pass の統計ファイルを書くディレクトリ。 None なら temp dir の下に作って、終わったら消す
+    pub stats_dir: Option<PathBuf>,
+    /// 終わった後に出力を demux し直して入力と比べる。 None なら比べない
+    pub verify: Option<Tolerances>,
+    /// decode したフレームや音声を transcode しながら analyzer に渡す。 None なら何もしない
+    pub analysis: Option<Analysis>,
+}
+
+impl Default for TranscodeOptions {
+    fn default() -> Self {
+        Self {
+            progress: None,
+            progress_interval: progress::DEFAULT_INTERVAL,
+            diagnose_caps: false,
+            eos_timeout: DEFAULT_EOS_TIMEOUT,
+            stats_dir: None,
+            verify: None,
+            analysis: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TranscodeReport {
+    /// `repair_timestamps` で直した timestamp (demuxer の pad ごと)
+    pub timestamp_corrections: Vec<(String, Vec<Correction>)>,
+    /// 出力ごとの比べた結果。 `TranscodeOptions::verify` が None か、中断したときは空
+    pub verification: Vec<VerifyReport>,
+    /// sidecar に書いた analyzer ごとの結果。 `TranscodeOptions::analysis` が None なら空
+    pub analysis: Vec<AnalyzerReport>,
+}
+
+impl TranscodeReport {
+    pub fn verified(&self) -> bool {
+        self.verification.iter().all(VerifyReport::passed)
+    }
+}
+
+/// job を最後まで (two-pass なら 2 pass とも) 動かす
+///
+/// `interrupt` は別のスレッド (signal handler など) から呼べる
+pub struct Transcoder {
+    job: ResolvedJob,
+    listener: Mutex<Option<Box<dyn ProgressListener>>>,
+    progress_interval: Duration,
+    diagnose_caps: bool,
+    eos_timeout: Duration,
+    stats_dir: Option<PathBuf>,
+    verify: Option<Tolerances>,
+    /// two-pass のときは 2 pass 目で使う
+    analysis: Mutex<Option<Analysis>>,
+    /// 今動いている pipeline 。 two-pass のときは pass ごとに変わる
+    running: Mutex<Option<Arc<Transcode>>>,
+    interrupted: AtomicBool,
+}
+
+impl Transcoder {
+    pub fn new(job: ResolvedJob, options: TranscodeOptions) -> Self {
+        Self {
+            job,
+            listener: Mutex::new(options.progress),
+            progress_interval: options.progress_interval,
+            diagnose_caps: options.diagnose_caps,
+            eos_timeout: options.eos_timeout,
+            stats_dir: options.stats_dir,
+            verify: options.verify,
+            analysis: Mutex::new(options.analysis),
+            running: Mutex::new(None),
+            interrupted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn config(&self) -> &TranscodeConfig {
+        &self.job.config
+    }
+
+    /// EOS を送って、そこまでの出力を書き終えさせる
+    ///
+    /// two-pass の 1 pass 目で中断したときは統計が途中までしかないので、 2 pass 目には進まずに `Error::Interrupted` を返す
+    pub fn interrupt(&self) -> Result<()> {
+        self.interrupted.store(true, Ordering::SeqCst);
+
+        // EOS は PLAYING にした後で message loop から送る
+        if let Some(transcode) = self.running.lock().unwrap().as_ref() {
+            post_interrupted(transcode);
+        }
+
+        Ok(())
+    }
+
+    pub fn run(&self) -> Result<TranscodeReport> {
+        let mut report = self.transcode()?;
+
+        // 中断したときは出力が途中までなので比べない
+        if let Some(tolerances) = &self.verify {
+            if !self.interrupted.load(Ordering::SeqCst) {
+                log::info!("Verify outputs");
+                report.verification = verify::verify(&self.job.config, tolerances)?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn transcode(&self) -> Result<TranscodeReport> {
+        let config = &self.job.config;
+        if !self.job.two_pass {
+            return self.run_pass(config, None);
+        }
+
+        // 1 pass 目で encoder に統計ファイルを書かせて、 2 pass 目でそれを読んで encode する
+        let (stats_dir, remove_stats_dir) = match &self.stats_dir {
+            Some(stats_dir) => (stats_dir.clone(), false),
+            None => {
+                let index = NEXT_STATS_DIR.fetch_add(1, Ordering::Relaxed);
+                (env::temp_dir().join(format!("learning-gstreamer-{}-{}", process::id(), index)), true)
+            },
+        };
+        fs::create_dir_all(&stats_dir)?;
+
+        let result = self.run_two_pass(config, &stats_dir.join("stats.log"));
+
+        if remove_stats_dir {
+            if let Err(err) = fs::remove_dir_all(&stats_dir) {
+                log::warn!("Failed to remove pass stats {}: {}", stats_dir.display(), err);
+            }
+        }
+
+        result
+    }
+
+    fn run_two_pass(&self, config: &TranscodeConfig, stats_path: &Path) -> Result<TranscodeReport> {
+        let (first_encoding, second_encoding) = config.video_encoding.two_pass(stats_path)?;
+
+        let first_config = TranscodeConfig { video_encoding: first_encoding, stats_pass: true, ..config.clone() };
+        self.run_pass(&first_config, Some(1))?;
+        if self.interrupted.load(Ordering::SeqCst) {
+            return Err(Error::Interrupted);
+        }
+
+        let second_config = TranscodeConfig { video_encoding: second_encoding, ..config.clone() };
+        self.run_pass(&second_config, Some(2))
+    }
+
+    /// pipeline を組み立てて、 EOS が来るまで動かす
+    fn run_pass(&self, config: &TranscodeConfig, pass: Option<u32>) -> Result<TranscodeReport> {
+        if self.interrupted.load(Ordering::SeqCst) {
+            return Err(Error::Interrupted);
+        }
+
+        // 1 pass 目は映像しか decode せず出力も捨てるので、 analyzer は最後の pass で動かす
+        let analysis = if config.stats_pass { None } else { self.analysis.lock().unwrap().take().map(Analysis::start).transpose()? };
+        let taps = analysis.as_ref().map(|analysis| analysis.taps.clone()).unwrap_or_default();
+
+        log::info!("Start build pipeline: {:?}", config);
+        let transcode = Arc::new(transcode::build_tapped_pipeline(config, taps)?);
+
+        // decodebin の中の element や demuxer の pad もあるので、 PLAYING にする前に probe をつけておく
+        let negotiation_tracer = self.diagnose_caps.then(|| NegotiationTracer::attach(transcode.pipeline.upcast_ref()));
+
+        let mut progress = match self.listener.lock().unwrap().take() {
+            Some(listener) => {
+                let progress = ProgressReporter::new(&transcode, listener, self.progress_interval)?;
+                Some(match pass {
+                    Some(pass) => progress.with_pass(pass),
+                    None => progress,
+                })
+            },
+            None => None,
+        };
+
+        *self.running.lock().unwrap() = Some(transcode.clone());
+        // running を入れる前に interrupt されると誰も EOS を送らないので、入れた後にもう一度確認する
+        if self.interrupted.load(Ordering::SeqCst) {
+            post_interrupted(&transcode);
+        }
+        let result = self.wait_for_eos(&transcode, progress.as_mut(), negotiation_tracer.as_ref());
+        *self.running.lock().unwrap() = None;
+
+        if let Some(progress) = progress {
+            *self.listener.lock().unwrap() = Some(progress.into_listener());
+        }
+        let mut report = TranscodeReport { timestamp_corrections: transcode.timestamp_corrections(), ..Default::default() };
+        // pipeline の error の方を返す
+        let state_result = transcode.pipeline.set_state(gst::State::Null);
+        let analysis_result = analysis.map(AnalysisRun::finish).transpose();
+        result?;
+        state_result?;
+        report.analysis = analysis_result?.unwrap_or_default();
+
+        Ok(report)
+    }
+
+    /// 中断されたら EOS を送ってあるので、 eos_timeout まで出力を書き終えるのを待つ
+    fn wait_for_eos(&self, transcode: &Transcode, mut progress: Option<&mut ProgressReporter>, negotiation_tracer: Option<&NegotiationTracer>) -> Result<()> {
+        // 中断が PLAYING にする前に来ていたら、 trim の seek も待たずにやめる
+        if self.interrupted.load(Ordering::SeqCst) {
+            return Err(Error::Interrupted);
+        }
+
+        log::info!("Set state to playing");
+        transcode.play()?;
+        let bus = transcode.pipeline.bus().expect("pipeline must have a bus");
+
+        log::info!("Before message loop");
+        let mut eos_deadline = None;
+        loop {
+            let timeout = eos_deadline.map(|deadline: Instant| {
+                gst::ClockTime::from_nseconds(deadline.saturating_duration_since(Instant::now()).as_nanos() as u64)
+            });
+            let Some(msg) = bus.timed_pop(timeout) else {
+                return Err(Error::EosTimeout);
+            };
+
+            if let Some(progress) = progress.as_deref_mut() {
+                progress.handle_message(&msg);
+            }
+            match msg.view() {
+                gst::MessageView::Eos(..) => break,
+                gst::MessageView::Application(application) if application.structure().map_or(false, |s| s.name() == INTERRUPTED_MESSAGE) => {
+                    // interrupt と run_pass の両方から post されることがあるので、 EOS は一度だけ送る
+                    if eos_deadline.is_some() {
+                        continue;
+                    }
+                    transcode.send_eos()?;
+                    eos_deadline = Some(Instant::now() + self.eos_timeout);
+                    if let Some(progress) = progress.as_deref_mut() {
+                        progress.set_stage(progress::Stage::Finalizing);
+                    }
+                },
+                gst::MessageView::Error(err) => {
+                    let negotiation_report = negotiation_tracer
+                        .filter(|_| NegotiationTracer::is_negotiation_error(err))
+                        .map(NegotiationTracer::report);
+                    return Err(Error::from_message(&msg, err, negotiation_report));
+                },
+                _ => (),
+            }
+        }
+        log::info!("After message loop");
+
+        Ok(())
+    }
+}
+
+/// message loop に中断を知らせる
+fn post_interrupted(transcode: &Transcode) {
+    let structure = gst::Structure::new_empty(INTERRUPTED_MESSAGE);
+    let _ = transcode.pipeline.post_message(gst::message::Application::new(structure));
+}
@@ -0,0 +1,347 @@
+use std::{fmt, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use gstreamer as gst;
+use gst::{glib, prelude::*};
+use gstreamer_audio as gst_audio;
+
+use crate::{
+    container::OutputFormat,
+    encoder::Encoding,
+    error::Result,
+    probe::{self, StreamInfo, StreamKind},
+    segment,
+    transcode::{self, TranscodeConfig},
+};
+
+/// 入力と設定から予想した出力と、実際の出力のずれをどこまで許すか
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerances {
+    /// 長さのずれ。 frame 数と sample 数もこの長さの分だけずれてよい
+    pub duration: gst::ClockTime,
+    /// 長い出力では、予想した長さに対するこの割合までずれてよい
+    pub duration_ratio: f64,
+}
+
+impl Default for Tolerances {
+    /// encoder の delay (AAC の priming など) や、 trim の位置が frame の境界に丸められる分
+    fn default() -> Self {
+        Self { duration: gst::ClockTime::from_mseconds(500), duration_ratio: 0.01 }
+    }
+}
+
+impl Tolerances {
+    fn allowed(&self, expected: gst::ClockTime) -> gst::ClockTime {
+        let by_ratio = gst::ClockTime::from_nseconds((expected.nseconds() as f64 * self.duration_ratio) as u64);
+        self.duration.max(by_ratio)
+    }
+}
+
+/// 一つの項目を比べた結果
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub expected: String,
+    pub actual: String,
+    pub passed: bool,
+}
+
+impl Check {
+    fn new(name: &'static str, expected: impl ToString, actual: impl ToString, passed: bool) -> Self {
+        Self { name, expected: expected.to_string(), actual: actual.to_string(), passed }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: expected {}, got {}", if self.passed { "ok" } else { "FAILED" }, self.name, self.expected, self.actual)
+    }
+}
+
+/// 一つの出力を確かめた結果
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    /// segment の出力 (HLS/DASH) では playlist か manifest
+    pub path: PathBuf,
+    pub checks: Vec<Check>,
+}
+
+impl VerifyReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Verify {}: {}", self.path.display(), if self.passed() { "ok" } else { "FAILED" })?;
+        for check in &self.checks {
+            writeln!(f, "  {}", check)?;
+        }
+        Ok(())
+    }
+}
+
+/// 入力の情報と設定から予想した出力
+///
+/// 分からないものは None にして、比べない
+#[derive(Debug, Default)]
+struct Expected {
+    duration: Option<gst::ClockTime>,
+    video_streams: usize,
+    audio_streams: usize,
+    width: Option<u32>,
+    height: Option<u32>,
+    framerate: Option<gst::Fraction>,
+    sample_rate: Option<u32>,
+}
+
+/// 出力を decode して数えたもの
+#[derive(Debug)]
+struct DecodedStream {
+    kind: StreamKind,
+    frames: u64,
+    samples: u64,
+}
+
+/// transcode が終わった出力 (bitrate ladder なら全部) を demux し直して、入力と比べる
+///
+/// 字幕は mux が受け付けなければ捨てるので比べない
+pub fn verify(config: &TranscodeConfig, tolerances: &Tolerances) -> Result<Vec<VerifyReport>> {
+    let mut reports = vec![verify_output(config, &config.output_path, config.output_format, &config.video_encoding, &config.audio_encoding, tolerances)?];
+    for rendition in &config.ladder {
+        reports.push(verify_output(config, &rendition.output_path, rendition.output_format, &rendition.video_encoding, &rendition.audio_encoding, tolerances)?);
+    }
+
+    Ok(reports)
+}
+
+fn verify_output(config: &TranscodeConfig, output_path: &Path, output_format: OutputFormat, video_encoding: &Encoding, audio_encoding: &Encoding, tolerances: &Tolerances) -> Result<VerifyReport> {
+    let path = playable_path(output_path, output_format);
+    let expected = expected_output(config, output_format, video_encoding, audio_encoding);
+    let mut report = VerifyReport { path: path.clone(), checks: Vec::new() };
+
+    // demux できなければ他の項目は比べられない
+    let media_info = match probe::discover(&path) {
+        Ok(media_info) => media_info,
+        Err(err) => {
+            report.checks.push(Check::new("demux", "readable output", err, false));
+            return Ok(report);
+        },
+    };
+    log::debug!("Verify {:?} against {:?}", media_info, expected);
+
+    if let Some(expected_duration) = expected.duration {
+        let (actual, passed) = match media_info.duration {
+            Some(duration) => (duration.to_string(), abs_diff(duration, expected_duration) <= tolerances.allowed(expected_duration)),
+            None => ("unknown".to_string(), false),
+        };
+        report.checks.push(Check::new("duration", expected_duration, actual, passed));
+    }
+
+    let video_streams = media_info.streams.iter().filter(|stream| stream.kind == StreamKind::Video).collect::<Vec<_>>();
+    let audio_streams = media_info.streams.iter().filter(|stream| stream.kind == StreamKind::Audio).collect::<Vec<_>>();
+    report.checks.push(Check::new("video streams", expected.video_streams, video_streams.len(), video_streams.len() == expected.video_streams));
+    report.checks.push(Check::new("audio streams", expected.audio_streams, audio_streams.len(), audio_streams.len() == expected.audio_streams));
+
+    if let (Some(stream), true) = (video_streams.first(), expected.width.is_some() || expected.height.is_some()) {
+        let matches = |expected: Option<u32>, actual: Option<u32>| expected.map_or(true, |expected| actual == Some(expected));
+        let passed = matches(expected.width, stream.width) && matches(expected.height, stream.height);
+        report.checks.push(Check::new("resolution", resolution(expected.width, expected.height), resolution(stream.width, stream.height), passed));
+    }
+
+    let (decoded_streams, decode_error) = decode(&path)?;
+    report.checks.push(Check::new("decode", "no errors", decode_error.as_deref().unwrap_or("no errors"), decode_error.is_none()));
+
+    let decoded_video = decoded_streams.iter().find(|stream| stream.kind == StreamKind::Video);
+    if let (Some(duration), Some(framerate), Some(decoded)) = (expected.duration, expected.framerate, decoded_video) {
+        let rate = (framerate.numer() as u64, framerate.denom() as u64);
+        let expected_frames = count_in(duration, rate);
+        let allowed = count_in(tolerances.allowed(duration), rate).max(1);
+        report.checks.push(Check::new("video frames", expected_frames, decoded.frames, decoded.frames.abs_diff(expected_frames) <= allowed));
+    }
+
+    let decoded_audio = decoded_streams.iter().find(|stream| stream.kind == StreamKind::Audio);
+    if let (Some(duration), Some(sample_rate), Some(decoded)) = (expected.duration, expected.sample_rate, decoded_audio) {
+        let rate = (sample_rate as u64, 1);
+        let expected_samples = count_in(duration, rate);
+        let allowed = count_in(tolerances.allowed(duration), rate);
+        report.checks.push(Check::new("audio samples", expected_samples, decoded.samples, decoded.samples.abs_diff(expected_samples) <= allowed));
+    }
+
+    Ok(report)
+}
+
+/// segment の出力 (HLS/DASH) はディレクトリなので、 playlist か manifest を読む
+fn playable_path(output_path: &Path, output_format: OutputFormat) -> PathBuf {
+    if !output_format.segmented {
+        return output_path.to_path_buf();
+    }
+
+    match output_format.muxer_name {
+        "dashsink" => output_path.join(segment::DASH_MANIFEST_NAME),
+        _ => output_path.join(segment::HLS_PLAYLIST_NAME),
+    }
+}
+
+/// `transcode::build_pipeline` と同じ規則で、どの stream がどう出力されるかを予想する
+fn expected_output(config: &TranscodeConfig, output_format: OutputFormat, video_encoding: &Encoding, audio_encoding: &Encoding) -> Expected {
+    let Some(first_input) = config.inputs.first() else {
+        return Expected::default();
+    };
+    let selected_streams = |kind: StreamKind| {
+        first_input.media_info.streams.iter()
+            .filter(move |stream| stream.kind == kind)
+            .filter(move |stream| config.selection.is_selected(0, kind, stream.index, Some(*stream)) && output_format.accepts_kind(kind))
+            .collect::<Vec<_>>()
+    };
+    let video_streams = selected_streams(StreamKind::Video);
+    let audio_streams = selected_streams(StreamKind::Audio);
+
+    // 複数の入力を繋げるときは、映像と音声を一つずつしか使わない
+    let count = |streams: &[&StreamInfo]| if config.inputs.len() > 1 { streams.len().min(1) } else { streams.len() };
+    let video_count = count(&video_streams);
+    let silent_audio = config.add_silent_audio && audio_streams.is_empty() && video_count > 0;
+    let audio_count = if silent_audio { 1 } else { count(&audio_streams) };
+
+    let copied = |kind: StreamKind, stream: &StreamInfo| {
+        config.copy_streams.iter().any(|map| map.matches(0, kind, stream.index))
+            && stream.caps.as_ref().map_or(false, |caps| output_format.accepts_codec(caps))
+    };
+
+    let mut expected = Expected { duration: expected_duration(config), video_streams: video_count, audio_streams: audio_count, ..Default::default() };
+
+    if let Some(&stream) = video_streams.first() {
+        let raw_video = video_encoding.raw_caps.as_ref().and_then(|caps| caps.structure(0)).filter(|_| !copied(StreamKind::Video, stream));
+        let raw_width = raw_video.and_then(|s| s.get::<i32>("width").ok()).map(|width| width as u32);
+        let raw_height = raw_video.and_then(|s| s.get::<i32>("height").ok()).map(|height| height as u32);
+        // 片方だけ指定したときは videoscale が縦横比を保つように決めるので、指定した方だけ比べる
+        (expected.width, expected.height) = if raw_width.is_some() || raw_height.is_some() {
+            (raw_width, raw_height)
+        } else {
+            (stream.width, stream.height)
+        };
+        expected.framerate = raw_video.and_then(|s| s.get::<gst::Fraction>("framerate").ok())
+            .or(stream.framerate)
+            .filter(|framerate| framerate.numer() > 0 && framerate.denom() > 0);
+    }
+
+    let stream = audio_streams.first().copied();
+    let raw_audio = audio_encoding.raw_caps.as_ref().and_then(|caps| caps.structure(0))
+        .filter(|_| !stream.map_or(false, |stream| copied(StreamKind::Audio, stream)));
+    expected.sample_rate = raw_audio.and_then(|s| s.get::<i32>("rate").ok()).map(|rate| rate as u32)
+        .or_else(|| if silent_audio { Some(transcode::SILENT_AUDIO_RATE as u32) } else { stream.and_then(|stream| stream.sample_rate) });
+
+    expected
+}
+
+/// trim していれば切り出した範囲、繋げていれば入力の長さの合計
+fn expected_duration(config: &TranscodeConfig) -> Option<gst::ClockTime> {
+    if config.is_trimmed() {
+        let input_duration = config.inputs.first()?.media_info.duration;
+        let end = match (config.end, input_duration) {
+            (Some(end), Some(input_duration)) => end.min(input_duration),
+            (end, input_duration) => end.or(input_duration)?,
+        };
+        return Some(end.saturating_sub(config.start.unwrap_or(gst::ClockTime::ZERO)));
+    }
+
+    config.inputs.iter().try_fold(gst::ClockTime::ZERO, |total, input| input.media_info.duration.map(|duration| total + duration))
+}
+
+/// uridecodebin ! fakesink で出力を最後まで decode して、 stream ごとに frame と sample を数える
+///
+/// playlist の uri を渡せば hlsdemux/dashdemux が segment を順番に読むので、 segment の出力も同じように decode できる
+/// decode の途中のエラーは返り値の二つ目に入れる
+fn decode(path: &Path) -> Result<(Vec<DecodedStream>, Option<String>)> {
+    let path = path.canonicalize()?;
+    let uri = gst::filename_to_uri(&path).map_err(|err| glib::bool_error!("Failed to make uri from {}: {}", path.display(), err))?;
+
+    let pipeline = gst::Pipeline::builder().name("verify_pipeline").build();
+    let decode_el = gst::ElementFactory::make("uridecodebin").property("uri", uri.as_str()).build()?;
+    pipeline.add(&decode_el)?;
+
+    let decoded_streams = Arc::new(Mutex::new(Vec::new()));
+    {
+        let pipeline_weak = pipeline.downgrade();
+        let decoded_streams = decoded_streams.clone();
+        decode_el.connect_pad_added(move |decode_el, pad| {
+            let Some(pipeline) = pipeline_weak.upgrade() else {
+                return;
+            };
+            if let Err(err) = link_counter(&pipeline, pad, &decoded_streams) {
+                gst::element_error!(decode_el, gst::StreamError::Failed, ("Failed to link {}: {}", pad.name(), err));
+            }
+        });
+    }
+
+    let bus = pipeline.bus().expect("pipeline must have a bus");
+    let decode_error = match pipeline.set_state(gst::State::Playing) {
+        Ok(_) => {
+            let msg = bus.timed_pop_filtered(gst::ClockTime::NONE, &[gst::MessageType::Eos, gst::MessageType::Error])
+                .expect("bus must return a message when waiting without timeout");
+            error_text(&msg)
+        },
+        Err(_) => Some(bus.pop_filtered(&[gst::MessageType::Error]).and_then(|msg| error_text(&msg)).unwrap_or_else(|| "Failed to start decoding".to_string())),
+    };
+    pipeline.set_state(gst::State::Null)?;
+
+    let decoded_streams = std::mem::take(&mut *decoded_streams.lock().unwrap());
+    Ok((decoded_streams, decode_error))
+}
+
+fn error_text(msg: &gst::Message) -> Option<String> {
+    match msg.view() {
+        gst::MessageView::Error(err) => Some(format!("{} ({:?})", err.error(), err.debug())),
+        _ => None,
+    }
+}
+
+/// decodebin の src pad を fakesink に繋いで、流れた buffer を数える
+fn link_counter(pipeline: &gst::Pipeline, pad: &gst::Pad, decoded_streams: &Arc<Mutex<Vec<DecodedStream>>>) -> Result<(), glib::BoolError> {
+    let caps = pad.current_caps().unwrap_or_else(|| pad.query_caps(None));
+
+    if let Some(kind) = StreamKind::from_caps(&caps).filter(|kind| *kind != StreamKind::Subtitle) {
+        // raw audio の buffer の大きさを frame (全 channel の 1 sample) の大きさで割ると sample 数になる
+        let bpf = gst_audio::AudioInfo::from_caps(&caps).ok().map(|info| info.bpf() as u64).filter(|bpf| *bpf > 0);
+        let index = {
+            let mut decoded_streams = decoded_streams.lock().unwrap();
+            decoded_streams.push(DecodedStream { kind, frames: 0, samples: 0 });
+            decoded_streams.len() - 1
+        };
+
+        let decoded_streams = decoded_streams.clone();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                let mut decoded_streams = decoded_streams.lock().unwrap();
+                let stream = &mut decoded_streams[index];
+                stream.frames += 1;
+                if let Some(bpf) = bpf {
+                    stream.samples += buffer.size() as u64 / bpf;
+                }
+            }
+            gst::PadProbeReturn::Ok
+        }).expect("buffer probe must be added to decodebin src pad");
+    }
+
+    let sink_el = gst::ElementFactory::make("fakesink").property("sync", false).build()?;
+    pipeline.add(&sink_el)?;
+    sink_el.sync_state_with_parent()?;
+    let sink_pad = sink_el.static_pad("sink").expect("fakesink must have a sink pad");
+    pad.link(&sink_pad).map_err(|err| glib::bool_error!("Failed to link to fakesink: {}", err))?;
+
+    Ok(())
+}
+
+fn abs_diff(a: gst::ClockTime, b: gst::ClockTime) -> gst::ClockTime {
+    if a > b { a - b } else { b - a }
+}
+
+/// duration の間に rate (numer/denom 毎秒) で数えた数
+fn count_in(duration: gst::ClockTime, (numer, denom): (u64, u64)) -> u64 {
+    (duration.nseconds() as u128 * numer as u128 / (denom as u128 * gst::ClockTime::SECOND.nseconds() as u128)) as u64
+}
+
+fn resolution(width: Option<u32>, height: Option<u32>) -> String {
+    let side = |value: Option<u32>| value.map_or("?".to_string(), |value| value.to_string());
+    format!("{}x{}", side(width), side(height))
+}